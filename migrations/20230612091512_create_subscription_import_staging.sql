@@ -0,0 +1,13 @@
+-- Bulk imports COPY validated rows in here first and then move them
+-- into `subscriptions` with a single set-based INSERT.
+-- Rows are only ever visible to the transaction of the import that
+-- wrote them, so the table can be UNLOGGED.
+CREATE UNLOGGED TABLE subscription_import_staging(
+  import_id UUID NOT NULL,
+  line BIGINT NOT NULL,
+  id UUID NOT NULL,
+  email TEXT NOT NULL,
+  name TEXT NOT NULL,
+  subscription_token TEXT NULL,
+  PRIMARY KEY (import_id, line)
+);
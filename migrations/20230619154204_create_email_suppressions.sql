@@ -0,0 +1,14 @@
+-- Addresses we must never email again, e.g. because they unsubscribed
+-- or hard-bounced on the platform they were imported from.
+CREATE TABLE email_suppressions(
+  email TEXT NOT NULL PRIMARY KEY,
+  reason TEXT NOT NULL,
+  created_at timestamptz NOT NULL
+);
+
+-- Imported rows now carry their own status instead of sharing the one
+-- requested for the whole import. The staging table is always empty
+-- outside of an import transaction, so NOT NULL is safe to add.
+ALTER TABLE subscription_import_staging
+  ADD COLUMN status TEXT NOT NULL,
+  ADD COLUMN suppression_reason TEXT NULL;
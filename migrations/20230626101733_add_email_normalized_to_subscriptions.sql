@@ -0,0 +1,12 @@
+-- `Foo@Example.com` and `foo@example.com` reach the same mailbox, so
+-- uniqueness is enforced on a normalized form of the address computed
+-- by the application, which also folds `+tag` aliases for some providers.
+-- Existing rows get the lowercased address; if that surfaces duplicates,
+-- the index creation fails and they have to be merged by hand first.
+ALTER TABLE subscriptions ADD COLUMN email_normalized TEXT NULL;
+UPDATE subscriptions SET email_normalized = lower(email);
+ALTER TABLE subscriptions ALTER COLUMN email_normalized SET NOT NULL;
+CREATE UNIQUE INDEX subscriptions_email_normalized_key ON subscriptions (email_normalized);
+
+-- The staging table is always empty outside of an import transaction.
+ALTER TABLE subscription_import_staging ADD COLUMN email_normalized TEXT NOT NULL;
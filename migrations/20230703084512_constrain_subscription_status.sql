@@ -0,0 +1,5 @@
+-- Statuses are spelled out by `SubscriptionStatus`, which also decides
+-- which changes between them are allowed.
+ALTER TABLE subscriptions
+  ADD CONSTRAINT subscriptions_status_check
+  CHECK (status IN ('pending_confirmation', 'confirmed', 'unsubscribed', 'bounced'));
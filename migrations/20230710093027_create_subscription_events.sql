@@ -0,0 +1,28 @@
+-- Append-only history of every subscription.
+-- There is no foreign key on `subscriber_id`: the history of a subscriber
+-- has to outlive the erasure of their row in `subscriptions`.
+CREATE TABLE subscription_events(
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  subscriber_id UUID NOT NULL,
+  kind TEXT NOT NULL CHECK (kind IN (
+    'subscribed', 'confirmation_sent', 'confirmed', 'unsubscribed', 'bounced', 'erased'
+  )),
+  actor TEXT NOT NULL CHECK (actor IN ('subscriber', 'admin', 'import', 'system')),
+  occurred_at timestamptz NOT NULL
+);
+CREATE INDEX subscription_events_subscriber_id_idx
+  ON subscription_events (subscriber_id, occurred_at, id);
+
+CREATE FUNCTION reject_subscription_event_changes() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'subscription_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER subscription_events_append_only
+  BEFORE UPDATE OR DELETE ON subscription_events
+  FOR EACH ROW EXECUTE FUNCTION reject_subscription_event_changes();
+
+-- All we know about existing subscribers is when they signed up.
+INSERT INTO subscription_events (subscriber_id, kind, actor, occurred_at)
+SELECT id, 'subscribed', 'system', subscribed_at FROM subscriptions;
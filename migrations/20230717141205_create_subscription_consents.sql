@@ -0,0 +1,14 @@
+-- How and when each subscriber opted in, as proof of their consent.
+-- Imported subscribers only get a row once they confirm through us.
+CREATE TABLE subscription_consents(
+  subscriber_id UUID PRIMARY KEY REFERENCES subscriptions (id) ON DELETE CASCADE,
+  -- The form, or any other place, people subscribed from.
+  source TEXT NULL,
+  consent_text_version TEXT NOT NULL,
+  signup_ip TEXT NULL,
+  signup_user_agent TEXT NULL,
+  signed_up_at timestamptz NULL,
+  confirmation_ip TEXT NULL,
+  confirmation_user_agent TEXT NULL,
+  confirmed_at timestamptz NULL
+);
@@ -0,0 +1,4 @@
+-- Confirmation links expire. Tokens issued before we kept track
+-- of their age get a fresh start from the day of the migration.
+ALTER TABLE subscription_tokens
+  ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
@@ -0,0 +1,4 @@
+-- Subscribers who never confirmed get reminded a few times, see `confirmation_reminders`.
+ALTER TABLE subscriptions
+  ADD COLUMN confirmation_reminders_sent INT NOT NULL DEFAULT 0,
+  ADD COLUMN last_reminder_sent_at timestamptz NULL;
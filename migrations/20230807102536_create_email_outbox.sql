@@ -0,0 +1,18 @@
+-- Emails written in the same transaction as the change they announce,
+-- then delivered, and retried, by the dispatcher in `email_outbox`.
+CREATE TABLE email_outbox(
+  id uuid PRIMARY KEY,
+  -- Deleting a subscriber drops the emails they still had coming.
+  subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+  recipient TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  html_body TEXT NOT NULL,
+  text_body TEXT NOT NULL,
+  created_at timestamptz NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz NOT NULL,
+  last_error TEXT NULL,
+  sent_at timestamptz NULL
+);
+CREATE INDEX email_outbox_undelivered_idx
+  ON email_outbox (next_attempt_at) WHERE sent_at IS NULL;
@@ -0,0 +1,16 @@
+-- Address changes waiting for the new address to be confirmed.
+-- Confirming one moves the subscription over and deletes the requests of the subscriber.
+CREATE TABLE email_change_requests(
+  token TEXT PRIMARY KEY,
+  subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+  new_email TEXT NOT NULL,
+  new_email_normalized TEXT NOT NULL,
+  created_at timestamptz NOT NULL
+);
+CREATE INDEX email_change_requests_subscriber_id_idx ON email_change_requests (subscriber_id);
+
+ALTER TABLE subscription_events DROP CONSTRAINT subscription_events_kind_check;
+ALTER TABLE subscription_events ADD CONSTRAINT subscription_events_kind_check CHECK (kind IN (
+  'subscribed', 'confirmation_sent', 'confirmed', 'unsubscribed', 'bounced', 'erased',
+  'email_changed'
+));
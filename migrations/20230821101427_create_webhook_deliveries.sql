@@ -0,0 +1,44 @@
+-- Subscription events waiting to be handed to the webhook endpoints, written in the
+-- same transaction as the event. The dispatcher turns each of them into one row of
+-- `webhook_deliveries` per endpoint that wants it, then deletes it.
+CREATE TABLE webhook_events(
+  event_id BIGINT PRIMARY KEY REFERENCES subscription_events (id),
+  -- Deleting a subscriber drops the webhooks they still had coming,
+  -- along with the personal data in their payloads.
+  subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+  event_kind TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  created_at timestamptz NOT NULL
+);
+
+CREATE TABLE webhook_deliveries(
+  id uuid PRIMARY KEY,
+  event_id BIGINT NOT NULL REFERENCES subscription_events (id),
+  subscriber_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+  -- Name of the endpoint in the configuration.
+  endpoint TEXT NOT NULL,
+  event_kind TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  created_at timestamptz NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz NOT NULL,
+  last_status_code INT NULL,
+  last_error TEXT NULL,
+  delivered_at timestamptz NULL,
+  UNIQUE (event_id, endpoint)
+);
+CREATE INDEX webhook_deliveries_undelivered_idx
+  ON webhook_deliveries (next_attempt_at) WHERE delivered_at IS NULL;
+CREATE INDEX webhook_deliveries_created_at_idx ON webhook_deliveries (created_at, id);
+
+-- Every attempt at a delivery, for the admin API.
+CREATE TABLE webhook_delivery_attempts(
+  id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  delivery_id uuid NOT NULL REFERENCES webhook_deliveries (id) ON DELETE CASCADE,
+  attempted_at timestamptz NOT NULL,
+  -- Missing when the endpoint could not be reached at all.
+  status_code INT NULL,
+  error TEXT NULL
+);
+CREATE INDEX webhook_delivery_attempts_delivery_id_idx
+  ON webhook_delivery_attempts (delivery_id, attempted_at);
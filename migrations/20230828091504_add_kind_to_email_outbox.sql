@@ -0,0 +1,8 @@
+-- What each outbox email is, so delivering it records the right event.
+-- Every email queued so far was a confirmation email.
+ALTER TABLE email_outbox ADD COLUMN kind TEXT NOT NULL DEFAULT 'confirmation';
+ALTER TABLE email_outbox ALTER COLUMN kind DROP DEFAULT;
+ALTER TABLE email_outbox ADD CONSTRAINT email_outbox_kind_check CHECK (kind IN (
+  'confirmation', 'confirmation_reminder', 'welcome', 'email_change_confirmation',
+  'email_change_notice'
+));
@@ -0,0 +1,2 @@
+-- Suppressions are looked up before every email, whatever the case of the address.
+CREATE INDEX email_suppressions_lower_email_idx ON email_suppressions (lower(email));
@@ -0,0 +1,7 @@
+-- Signup form tokens that were used already, so they can't be replayed.
+-- Rows are deleted once the token would have expired anyway.
+CREATE TABLE spent_form_tokens(
+  nonce TEXT PRIMARY KEY,
+  expires_at timestamptz NOT NULL
+);
+CREATE INDEX spent_form_tokens_expires_at_idx ON spent_form_tokens (expires_at);
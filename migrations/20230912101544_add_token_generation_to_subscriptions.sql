@@ -0,0 +1,4 @@
+-- Signed confirmation links name the generation they were issued for.
+-- Issuing a new link bumps it, which turns every earlier signed link away,
+-- the way deleting `subscription_tokens` does for stored ones.
+ALTER TABLE subscriptions ADD COLUMN token_generation INT NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+-- An address change now starts with a link to the current address, so only
+-- whoever reads its inbox can move the subscription. The new address gets
+-- its link once the change is approved. Pending requests had no approval
+-- and are dropped, people can ask again.
+DELETE FROM email_change_requests;
+ALTER TABLE email_change_requests ADD COLUMN approval_token TEXT NOT NULL UNIQUE;
+ALTER TABLE email_change_requests ADD COLUMN approved_at timestamptz NULL;
+
+ALTER TABLE email_outbox DROP CONSTRAINT email_outbox_kind_check;
+ALTER TABLE email_outbox ADD CONSTRAINT email_outbox_kind_check CHECK (kind IN (
+  'confirmation', 'confirmation_reminder', 'welcome', 'email_change_approval',
+  'email_change_confirmation', 'email_change_notice'
+));
@@ -0,0 +1,29 @@
+-- Queue webhooks for every subscription event, whoever writes it: signups,
+-- imports and status changes alike. The kinds are those in `WEBHOOK_EVENTS`.
+-- The payload is a snapshot of the subscriber as they are when the event is
+-- recorded; events of subscribers who are gone already queue nothing.
+CREATE FUNCTION queue_webhook_event() RETURNS trigger AS $$
+BEGIN
+  INSERT INTO webhook_events (event_id, subscriber_id, event_kind, payload, created_at)
+  SELECT
+    NEW.id, NEW.subscriber_id, NEW.kind,
+    json_build_object(
+      'id', NEW.id,
+      'type', NEW.kind,
+      'actor', NEW.actor,
+      'occurred_at', NEW.occurred_at,
+      'subscriber', json_build_object(
+        'id', s.id, 'email', s.email, 'name', s.name, 'status', s.status
+      )
+    )::text,
+    NEW.occurred_at
+  FROM subscriptions s
+  WHERE s.id = NEW.subscriber_id
+    AND NEW.kind IN ('subscribed', 'confirmed', 'unsubscribed', 'bounced', 'email_changed');
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER subscription_events_queue_webhooks
+  AFTER INSERT ON subscription_events
+  FOR EACH ROW EXECUTE FUNCTION queue_webhook_event();
@@ -0,0 +1,5 @@
+-- When the subscriber last became pending: at signup, or when someone who
+-- left or bounced signs up again. Stale pending subscriptions are purged on
+-- it rather than on `subscribed_at`, which keeps the first signup.
+ALTER TABLE subscriptions ADD COLUMN pending_since timestamptz NULL;
+UPDATE subscriptions SET pending_since = subscribed_at WHERE status = 'pending_confirmation';
@@ -0,0 +1,8 @@
+-- Reminders now count every confirmation email, the first one and resends included,
+-- and are timed from the latest of them, see `confirmation_reminders`.
+ALTER TABLE subscriptions RENAME COLUMN confirmation_reminders_sent TO confirmation_emails_sent;
+ALTER TABLE subscriptions RENAME COLUMN last_reminder_sent_at TO last_confirmation_sent_at;
+UPDATE subscriptions
+SET confirmation_emails_sent = confirmation_emails_sent + 1,
+    last_confirmation_sent_at = COALESCE(last_confirmation_sent_at, pending_since)
+WHERE status = 'pending_confirmation';
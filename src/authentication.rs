@@ -0,0 +1,82 @@
+use actix_web::{
+    dev::Payload, error::ErrorInternalServerError, http::header, web, FromRequest, HttpRequest,
+    HttpResponse, ResponseError,
+};
+use secrecy::{ExposeSecret, Secret};
+use std::future::{ready, Ready};
+
+use crate::startup::AppState;
+
+/// The token admins must present as `Authorization: Bearer <token>`.
+pub struct AdminApiToken(pub Secret<String>);
+
+/// Extractor guarding the admin endpoints.
+/// Adding it as a handler argument is enough to reject any request
+/// that does not carry the configured admin token.
+pub struct AdminAuth;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Missing or invalid admin credentials")]
+pub struct AdminAuthError;
+
+impl ResponseError for AdminAuthError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, r#"Bearer realm="admin""#))
+            .finish()
+    }
+}
+
+impl FromRequest for AdminAuth {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let expected = match req.app_data::<web::Data<AppState>>() {
+            Some(state) => &state.admin_api_token,
+            None => {
+                return ready(Err(ErrorInternalServerError(
+                    "The admin API token has not been configured",
+                )))
+            }
+        };
+
+        let provided = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match provided {
+            Some(provided)
+                if constant_time_eq(provided.as_bytes(), expected.0.expose_secret().as_bytes()) =>
+            {
+                ready(Ok(AdminAuth))
+            }
+            _ => ready(Err(AdminAuthError.into())),
+        }
+    }
+}
+
+/// Compare both tokens without short-circuiting on the first mismatching byte,
+/// so response times don't leak how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn equal_tokens_match() {
+        assert!(constant_time_eq(b"secret-token", b"secret-token"));
+    }
+
+    #[test]
+    fn different_tokens_do_not_match() {
+        assert!(!constant_time_eq(b"secret-token", b"secret-tokem"));
+        assert!(!constant_time_eq(b"secret-token", b"secret"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::Notify;
+
+/// Work request handlers start without waiting for it, e.g. emails whose
+/// sending time would tell callers something. Keeps count of what is still
+/// running, so it can be waited for.
+#[derive(Clone, Default)]
+pub struct BackgroundTasks {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: AtomicUsize,
+    idle: Notify,
+}
+
+/// Counts a task as done when dropped, even if it panicked.
+struct Running(Arc<Inner>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl BackgroundTasks {
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.inner.running.fetch_add(1, Ordering::SeqCst);
+        let running = Running(self.inner.clone());
+        tokio::spawn(async move {
+            let _running = running;
+            task.await;
+        });
+    }
+
+    /// Wait until no task is running anymore, including those spawned meanwhile.
+    pub async fn wait_until_idle(&self) {
+        loop {
+            // Created before the check, so a notification in between isn't missed.
+            let idle = self.inner.idle.notified();
+            if self.inner.running.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::BackgroundTasks;
+
+    #[tokio::test]
+    async fn waiting_until_idle_returns_once_every_task_is_done() {
+        let tasks = BackgroundTasks::default();
+        tasks.wait_until_idle().await;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let task_done = done.clone();
+        tasks.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            task_done.store(true, Ordering::SeqCst);
+        });
+        tasks.spawn(async { panic!("A task failed") });
+
+        tasks.wait_until_idle().await;
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
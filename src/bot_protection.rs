@@ -0,0 +1,257 @@
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::ExposeSecret;
+use sha2::Sha256;
+use sqlx::PgExecutor;
+
+use crate::configuration::BotProtectionSettings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Spam defence for the signup form that humans never notice:
+/// - a honeypot field, hidden from people by the form, that only bots fill in;
+/// - a signed form token recording when the form was served, betraying
+///   submissions that came too fast to have been typed by a person.
+///   Each token is good for a single signup, see `spend_form_token`.
+///
+/// Submissions caught by either are answered as if they had succeeded,
+/// so bots get no signal to adapt to.
+///
+/// Unless `require_form_token` is on, a submission without any token skips the
+/// time trap altogether: API clients that don't fetch a token first keep working,
+/// and so do bots that leave it out.
+pub struct BotProtection {
+    settings: BotProtectionSettings,
+}
+
+/// Why a submission was deemed automated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BotSignal {
+    HoneypotFilled,
+    MissingFormToken,
+    InvalidFormToken,
+    ExpiredFormToken,
+    SubmittedTooFast,
+    /// The token was used for another signup already.
+    ReplayedFormToken,
+}
+
+/// A form token that passed inspection, yet to be spent.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FormToken {
+    nonce: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl BotProtection {
+    pub fn new(settings: BotProtectionSettings) -> Self {
+        Self { settings }
+    }
+
+    /// A token to embed in the form, made of the time it was issued at,
+    /// a random nonce telling it apart from the others issued that second,
+    /// and a signature of both, so it can't be backdated.
+    pub fn issue_form_token(&self) -> String {
+        let nonce: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .map(char::from)
+            .take(16)
+            .collect();
+        self.issue_form_token_at(Utc::now().timestamp(), &nonce)
+    }
+
+    fn issue_form_token_at(&self, issued_at: i64, nonce: &str) -> String {
+        let signature = self.mac(issued_at, nonce).finalize().into_bytes();
+        format!("{}.{}.{}", issued_at, nonce, hex::encode(signature))
+    }
+
+    /// Look for the signs of a bot in a submission. Returns the form token
+    /// it came with, if any, for the signup to spend.
+    pub fn inspect(
+        &self,
+        honeypot: Option<&str>,
+        form_token: Option<&str>,
+    ) -> Result<Option<FormToken>, BotSignal> {
+        self.inspect_at(honeypot, form_token, Utc::now().timestamp())
+    }
+
+    fn inspect_at(
+        &self,
+        honeypot: Option<&str>,
+        form_token: Option<&str>,
+        now: i64,
+    ) -> Result<Option<FormToken>, BotSignal> {
+        if honeypot.is_some_and(|value| !value.trim().is_empty()) {
+            return Err(BotSignal::HoneypotFilled);
+        }
+
+        match form_token {
+            Some(token) => self.verify_form_token(token, now).map(Some),
+            None if self.settings.require_form_token => Err(BotSignal::MissingFormToken),
+            None => Ok(None),
+        }
+    }
+
+    fn verify_form_token(&self, token: &str, now: i64) -> Result<FormToken, BotSignal> {
+        let mut parts = token.splitn(3, '.');
+        let (Some(issued_at), Some(nonce), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(BotSignal::InvalidFormToken);
+        };
+        let issued_at: i64 = issued_at.parse().map_err(|_| BotSignal::InvalidFormToken)?;
+        let signature = hex::decode(signature).map_err(|_| BotSignal::InvalidFormToken)?;
+        self.mac(issued_at, nonce)
+            .verify_slice(&signature)
+            .map_err(|_| BotSignal::InvalidFormToken)?;
+
+        let age = now - issued_at;
+        let max_age = self.settings.form_token_max_age_secs as i64;
+        if age < self.settings.min_submit_secs as i64 {
+            Err(BotSignal::SubmittedTooFast)
+        } else if age > max_age {
+            Err(BotSignal::ExpiredFormToken)
+        } else {
+            let expires_at = Utc
+                .timestamp_opt(issued_at.saturating_add(max_age), 0)
+                .single()
+                .ok_or(BotSignal::InvalidFormToken)?;
+            Ok(FormToken {
+                nonce: nonce.to_owned(),
+                expires_at,
+            })
+        }
+    }
+
+    fn mac(&self, issued_at: i64, nonce: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(self.settings.form_token_secret.expose_secret().as_bytes())
+                .expect("HMAC accepts keys of any size");
+        mac.update(format!("{}.{}", issued_at, nonce).as_bytes());
+        mac
+    }
+}
+
+/// Use up a form token. Returns `false` when it was spent already,
+/// i.e. the form is being replayed.
+///
+/// Spend it only once the submission is otherwise valid: someone fixing a typo
+/// in the form sends it again with the same token.
+pub async fn spend_form_token(
+    executor: impl PgExecutor<'_>,
+    form_token: &FormToken,
+) -> Result<bool, sqlx::Error> {
+    // Expired tokens are turned down before they get here, their rows can go.
+    let spent = sqlx::query!(
+        r#"
+    WITH expired AS (DELETE FROM spent_form_tokens WHERE expires_at < $3)
+    INSERT INTO spent_form_tokens (nonce, expires_at)
+    VALUES ($1, $2)
+    ON CONFLICT DO NOTHING
+    "#,
+        form_token.nonce,
+        form_token.expires_at,
+        Utc::now()
+    )
+    .execute(executor)
+    .await?
+    .rows_affected();
+    Ok(spent == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use super::{BotProtection, BotSignal};
+    use crate::configuration::BotProtectionSettings;
+
+    const ISSUED_AT: i64 = 1_700_000_000;
+
+    fn protection(require_form_token: bool) -> BotProtection {
+        BotProtection::new(BotProtectionSettings {
+            form_token_secret: Secret::new("secret".into()),
+            min_submit_secs: 3,
+            form_token_max_age_secs: 3600,
+            require_form_token,
+        })
+    }
+
+    #[test]
+    fn a_filled_honeypot_gives_a_bot_away() {
+        let protection = protection(false);
+
+        assert_eq!(
+            protection.inspect_at(Some("https://spam.example.com"), None, ISSUED_AT),
+            Err(BotSignal::HoneypotFilled)
+        );
+        assert_eq!(protection.inspect_at(Some(""), None, ISSUED_AT), Ok(None));
+    }
+
+    #[test]
+    fn form_tokens_must_be_used_between_the_minimum_and_maximum_age() {
+        let protection = protection(false);
+        let token = protection.issue_form_token_at(ISSUED_AT, "nonce");
+        let submitted_after = |secs| {
+            protection
+                .inspect_at(None, Some(&token), ISSUED_AT + secs)
+                .err()
+        };
+
+        assert_eq!(submitted_after(1), Some(BotSignal::SubmittedTooFast));
+        assert_eq!(submitted_after(3), None);
+        assert_eq!(submitted_after(3600), None);
+        assert_eq!(submitted_after(3601), Some(BotSignal::ExpiredFormToken));
+    }
+
+    #[test]
+    fn valid_form_tokens_are_spent_by_their_nonce_until_they_expire() {
+        let protection = protection(false);
+        let token = protection.issue_form_token_at(ISSUED_AT, "nonce");
+
+        let form_token = protection
+            .inspect_at(None, Some(&token), ISSUED_AT + 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(form_token.nonce, "nonce");
+        assert_eq!(form_token.expires_at.timestamp(), ISSUED_AT + 3600);
+    }
+
+    #[test]
+    fn backdated_or_malformed_form_tokens_are_rejected() {
+        let protection = protection(false);
+        let token = protection.issue_form_token_at(ISSUED_AT, "nonce");
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let backdated = format!("{}.nonce.{}", ISSUED_AT - 60, signature);
+        let other_nonce = format!("{}.other.{}", ISSUED_AT, signature);
+
+        for token in [
+            backdated.as_str(),
+            other_nonce.as_str(),
+            "not-a-token",
+            "1700000000.nonce.zz",
+            "1700000000.abcd",
+            "",
+        ] {
+            assert_eq!(
+                protection.inspect_at(None, Some(token), ISSUED_AT + 10),
+                Err(BotSignal::InvalidFormToken),
+                "{:?} was accepted",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn form_tokens_are_only_mandatory_when_required() {
+        assert_eq!(
+            protection(false).inspect_at(None, None, ISSUED_AT),
+            Ok(None)
+        );
+        assert_eq!(
+            protection(true).inspect_at(None, None, ISSUED_AT),
+            Err(BotSignal::MissingFormToken)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+
+/// Checks the response a client got back from solving a CAPTCHA challenge.
+pub trait CaptchaVerifier: Send + Sync {
+    /// Whether `response` proves the challenge was solved.
+    /// An error means we could not find out either way.
+    fn verify<'a>(&'a self, response: &'a str) -> BoxFuture<'a, Result<bool, anyhow::Error>>;
+}
+
+/// The verifier signups must get through, if the CAPTCHA step is enabled.
+pub struct SignupCaptcha(pub Option<Arc<dyn CaptchaVerifier>>);
+
+impl SignupCaptcha {
+    /// Whether a signup may go on, given the CAPTCHA response it came with.
+    pub async fn check(&self, response: Option<&str>) -> Result<bool, anyhow::Error> {
+        let verifier = match &self.0 {
+            Some(verifier) => verifier,
+            None => return Ok(true),
+        };
+        match response.map(str::trim).filter(|r| !r.is_empty()) {
+            Some(response) => verifier.verify(response).await,
+            None => Ok(false),
+        }
+    }
+}
+
+/// Verifier for the `siteverify` endpoints of hCaptcha, Cloudflare Turnstile
+/// and reCAPTCHA, which all take the same form and answer with `success`.
+pub struct HttpCaptchaVerifier {
+    http_client: Client,
+    verify_url: String,
+    secret_key: Secret<String>,
+}
+
+impl HttpCaptchaVerifier {
+    pub fn new(verify_url: String, secret_key: Secret<String>, timeout: Duration) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            verify_url,
+            secret_key,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct VerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyResponse {
+    success: bool,
+}
+
+impl CaptchaVerifier for HttpCaptchaVerifier {
+    fn verify<'a>(&'a self, response: &'a str) -> BoxFuture<'a, Result<bool, anyhow::Error>> {
+        Box::pin(async move {
+            let outcome: VerifyResponse = self
+                .http_client
+                .post(&self.verify_url)
+                .form(&VerifyRequest {
+                    secret: self.secret_key.expose_secret(),
+                    response,
+                })
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .context("Failed to call the CAPTCHA verify endpoint")?
+                .json()
+                .await
+                .context("Failed to read the answer of the CAPTCHA verify endpoint")?;
+            Ok(outcome.success)
+        })
+    }
+}
+
+/// Verifier that never leaves the process, for tests and local development.
+pub enum LocalCaptchaVerifier {
+    AlwaysPass,
+    AlwaysFail,
+}
+
+impl CaptchaVerifier for LocalCaptchaVerifier {
+    fn verify<'a>(&'a self, _response: &'a str) -> BoxFuture<'a, Result<bool, anyhow::Error>> {
+        let solved = matches!(self, LocalCaptchaVerifier::AlwaysPass);
+        Box::pin(async move { Ok(solved) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::{HttpCaptchaVerifier, LocalCaptchaVerifier, SignupCaptcha};
+
+    fn http_captcha(mock_server: &MockServer) -> SignupCaptcha {
+        SignupCaptcha(Some(Arc::new(HttpCaptchaVerifier::new(
+            format!("{}/siteverify", mock_server.uri()),
+            Secret::new("secret-key".into()),
+            Duration::from_millis(200),
+        ))))
+    }
+
+    #[tokio::test]
+    async fn the_http_verifier_sends_the_secret_and_response_to_the_verify_endpoint() {
+        let mock_server = MockServer::start().await;
+        let captcha = http_captcha(&mock_server);
+
+        Mock::given(path("/siteverify"))
+            .and(method("POST"))
+            .and(body_string_contains("secret=secret-key"))
+            .and(body_string_contains("response=solved"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": true,
+                "hostname": "localhost",
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        assert_ok_eq!(captcha.check(Some("solved")).await, true);
+    }
+
+    #[tokio::test]
+    async fn the_http_verifier_reports_failed_challenges() {
+        let mock_server = MockServer::start().await;
+        let captcha = http_captcha(&mock_server);
+
+        Mock::given(path("/siteverify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "success": false,
+                "error-codes": ["invalid-input-response"],
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        assert_ok_eq!(captcha.check(Some("guessed")).await, false);
+    }
+
+    #[tokio::test]
+    async fn the_http_verifier_fails_if_the_endpoint_is_unavailable() {
+        let mock_server = MockServer::start().await;
+        let captcha = http_captcha(&mock_server);
+
+        Mock::given(path("/siteverify"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        assert_err!(captcha.check(Some("solved")).await);
+    }
+
+    #[tokio::test]
+    async fn a_missing_response_never_reaches_the_verifier() {
+        let captcha = SignupCaptcha(Some(Arc::new(LocalCaptchaVerifier::AlwaysPass)));
+
+        assert_ok_eq!(captcha.check(Some("anything")).await, true);
+        assert_ok_eq!(captcha.check(Some("  ")).await, false);
+        assert_ok_eq!(captcha.check(None).await, false);
+    }
+
+    #[tokio::test]
+    async fn every_signup_passes_when_the_captcha_is_disabled() {
+        assert_ok_eq!(SignupCaptcha(None).check(None).await, true);
+    }
+}
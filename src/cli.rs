@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+
+use crate::{
+    configuration::Settings,
+    email_normalization::normalize_stored_emails,
+    retention::purge_stale_pending_subscriptions,
+    startup::get_db_conn_pool,
+    subscriber_import::{
+        import_subscribers, send_import_confirmations, ImportFormat, ImportStatus,
+    },
+};
+
+/// Mailbolt, an email newsletter service.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the HTTP server. This is what runs when no command is given.
+    Serve,
+    /// Import subscribers from a CSV file.
+    /// Prints a JSON report of the rows that could not be imported.
+    Import {
+        /// Path to the CSV file.
+        path: PathBuf,
+        /// Layout of the CSV file.
+        #[arg(long, value_enum, default_value_t)]
+        format: ImportFormat,
+        /// Import everyone who is subscribed as already confirmed
+        /// instead of sending them a confirmation email.
+        #[arg(long)]
+        confirmed: bool,
+    },
+    /// Delete subscribers who never confirmed within `retention.pending_max_age_days`.
+    /// Prints how many were deleted.
+    PurgePending,
+    /// Recompute the normalized address signups are matched on for every
+    /// subscriber, e.g. after changing `email_policy.plus_alias_domains`.
+    /// Prints a JSON report of the addresses that could not be updated.
+    NormalizeEmails,
+}
+
+pub async fn import(
+    config: Settings,
+    path: PathBuf,
+    format: ImportFormat,
+    confirmed: bool,
+) -> anyhow::Result<()> {
+    let status = if confirmed {
+        ImportStatus::Confirmed
+    } else {
+        ImportStatus::PendingConfirmation
+    };
+
+    let file = tokio::fs::File::open(&path)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    let conn_pool = get_db_conn_pool(&config.database);
+    let email_policy = config.email_policy.policy()?;
+
+    let report = import_subscribers(
+        &conn_pool,
+        file,
+        format,
+        status,
+        &email_policy,
+        &config.application.base_url,
+    )
+    .await?;
+
+    if report.pending_confirmations > 0 {
+        let email_client = config.email_client.client();
+        send_import_confirmations(
+            &conn_pool,
+            &email_client,
+            &config.email_outbox.policy(),
+            report.pending_confirmations,
+        )
+        .await;
+    }
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+pub async fn purge_pending(config: Settings) -> anyhow::Result<()> {
+    let conn_pool = get_db_conn_pool(&config.database);
+    let purged = purge_stale_pending_subscriptions(
+        &conn_pool,
+        config.retention.pending_max_age(),
+        config.retention.batch_size,
+    )
+    .await?;
+
+    println!("{}", serde_json::json!({ "purged": purged }));
+    Ok(())
+}
+
+pub async fn normalize_emails(config: Settings) -> anyhow::Result<()> {
+    let conn_pool = get_db_conn_pool(&config.database);
+    let email_policy = config.email_policy.policy()?;
+    let report = normalize_stored_emails(&conn_pool, &email_policy).await?;
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::{
+    future::{ready, Ready},
+    net::IpAddr,
+};
+
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+
+use crate::startup::AppState;
+
+/// The proxies trusted to tell us the real client IP
+/// through the `X-Forwarded-For` header, e.g. our load balancer.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+/// Where a request came from, as far as we can tell.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequest for ClientInfo {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let trusted_proxies = req
+            .app_data::<web::Data<AppState>>()
+            .map(|state| state.trusted_proxies.0.as_slice())
+            .unwrap_or_default();
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        ready(Ok(ClientInfo {
+            ip: client_ip(req, trusted_proxies),
+            user_agent,
+        }))
+    }
+}
+
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let forwarded_for: Vec<_> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+
+    Some(resolve_client_ip(
+        peer,
+        &forwarded_for.join(","),
+        trusted_proxies,
+    ))
+}
+
+/// Walk the `X-Forwarded-For` chain back from the closest hop, for as long as
+/// the hops are our own proxies. The first address we don't trust is the client:
+/// anything before it in the header could have been made up by the client itself.
+fn resolve_client_ip(peer: IpAddr, forwarded_for: &str, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    if forwarded_for.is_empty() {
+        return client;
+    }
+
+    for hop in forwarded_for.rsplit(',') {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::resolve_client_ip;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_when_the_peer_is_not_a_trusted_proxy() {
+        let client = resolve_client_ip(ip("203.0.113.7"), "198.51.100.1", &[]);
+        assert_eq!(client, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn forwarded_for_is_followed_through_trusted_proxies_only() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        let client = resolve_client_ip(ip("10.0.0.1"), "198.51.100.1", &trusted);
+        assert_eq!(client, ip("198.51.100.1"));
+
+        // The client can prepend anything it wants to the header,
+        // only the hop added by our closest untrusted peer counts.
+        let client = resolve_client_ip(ip("10.0.0.1"), "1.1.1.1, 198.51.100.1, 10.0.0.2", &trusted);
+        assert_eq!(client, ip("198.51.100.1"));
+
+        let client = resolve_client_ip(ip("10.0.0.1"), "not-an-ip", &trusted);
+        assert_eq!(client, ip("10.0.0.1"));
+    }
+}
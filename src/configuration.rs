@@ -1,3 +1,5 @@
+use std::{net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::{
@@ -5,13 +7,39 @@ use sqlx::{
     ConnectOptions,
 };
 
-use crate::domain::SubscriberEmail;
+use crate::captcha::{HttpCaptchaVerifier, LocalCaptchaVerifier, SignupCaptcha};
+use crate::confirmation_reminders::ReminderPolicy;
+use crate::domain::{EmailPolicy, NamePolicy, SubscriberEmail};
+use crate::email_client::EmailClient;
+use crate::email_outbox::OutboxPolicy;
+use crate::routes::{ConfirmationPolicy, ConfirmationTokenMode};
+use crate::signed_tokens::{SigningKey, TokenSigner};
+use crate::webhooks::{WebhookClient, WebhookEndpoint, WebhookPolicy};
 
 #[derive(Clone, serde::Deserialize)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub email_outbox: EmailOutboxSettings,
+    pub webhooks: WebhookSettings,
+    pub admin: AdminSettings,
+    pub rate_limit: RateLimitSettings,
+    pub bot_protection: BotProtectionSettings,
+    pub captcha: CaptchaSettings,
+    pub email_policy: EmailPolicySettings,
+    pub name_policy: NamePolicySettings,
+    pub consent: ConsentSettings,
+    pub confirmation: ConfirmationSettings,
+    pub reminders: ReminderSettings,
+    pub retention: RetentionSettings,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct AdminSettings {
+    // Bearer token expected on every request to the `/admin` endpoints.
+    // In production, inject it via the `APP_ADMIN__API_TOKEN` env variable.
+    pub api_token: Secret<String>,
 }
 
 #[derive(Clone, serde::Deserialize)]
@@ -19,12 +47,89 @@ pub struct EmailClientSettings {
     pub base_url: String,
     pub sender_email: String,
     pub auth_token: Secret<String>,
+    // Whether the email API accepts internationalized (SMTPUTF8) addresses as they are.
+    // Without it, addresses with a non-ASCII local part can't be emailed at all.
+    pub smtputf8: bool,
 }
 
 impl EmailClientSettings {
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
+
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email");
+        EmailClient::new(self.base_url, sender_email, self.auth_token, self.smtputf8)
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct EmailOutboxSettings {
+    // Attempts at delivering an email before it is given up on, counting the first one.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i32,
+    // Wait after the first failed attempt, doubled after every other one up to the maximum.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_base_delay_secs: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_max_delay_secs: i64,
+    // How often the dispatcher looks for emails due.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_secs: u64,
+}
+
+impl EmailOutboxSettings {
+    pub fn policy(&self) -> OutboxPolicy {
+        OutboxPolicy {
+            max_attempts: self.max_attempts,
+            retry_base_delay: chrono::Duration::seconds(self.retry_base_delay_secs),
+            retry_max_delay: chrono::Duration::seconds(self.retry_max_delay_secs),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct WebhookSettings {
+    // Attempts at a delivery before it is given up on, counting the first one.
+    // Deliveries given up on can still be redelivered through the admin API.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: i32,
+    // Wait after the first failed attempt, doubled after every other one up to the maximum.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_base_delay_secs: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_max_delay_secs: i64,
+    // How often the dispatcher looks for deliveries due.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_secs: u64,
+    // How long an endpoint has to answer before the attempt counts as failed.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_secs: u64,
+    pub endpoints: Vec<WebhookEndpoint>,
+}
+
+impl WebhookSettings {
+    pub fn policy(&self) -> Result<WebhookPolicy, std::io::Error> {
+        let retries = OutboxPolicy {
+            max_attempts: self.max_attempts,
+            retry_base_delay: chrono::Duration::seconds(self.retry_base_delay_secs),
+            retry_max_delay: chrono::Duration::seconds(self.retry_max_delay_secs),
+        };
+        WebhookPolicy::new(self.endpoints.clone(), retries)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    }
+
+    pub fn client(&self) -> WebhookClient {
+        WebhookClient::new(Duration::from_secs(self.timeout_secs))
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
 }
 
 #[derive(Clone, serde::Deserialize)]
@@ -40,6 +145,269 @@ pub struct ApplicationSettings {
     pub base_url: String,
 }
 
+#[derive(Clone, serde::Deserialize)]
+pub struct RateLimitSettings {
+    // Only these peers are trusted to tell us the real client IP
+    // through the `X-Forwarded-For` header, e.g. our load balancer.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub signup: EndpointRateLimits,
+    pub resend_confirmation: EndpointRateLimits,
+    pub change_email: EndpointRateLimits,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct EndpointRateLimits {
+    pub per_ip: TokenBucketSettings,
+    pub per_email: TokenBucketSettings,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub struct TokenBucketSettings {
+    // How many requests can be made in a burst
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub capacity: u32,
+    // One more request is allowed every `refill_interval_secs`
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refill_interval_secs: u64,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct BotProtectionSettings {
+    // Key signing the form tokens handed out to the signup form.
+    // In production, inject it via the `APP_BOT_PROTECTION__FORM_TOKEN_SECRET` env variable.
+    pub form_token_secret: Secret<String>,
+    // Forms submitted sooner than this after their token was issued
+    // were filled by a bot, no human types that fast.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_submit_secs: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub form_token_max_age_secs: u64,
+    // Whether signups without any form token are dropped too.
+    // Leave it off as long as API clients don't fetch a token first.
+    pub require_form_token: bool,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum CaptchaSettings {
+    Disabled,
+    // Any hCaptcha or Turnstile style `siteverify` endpoint.
+    // In production, inject the secret via the `APP_CAPTCHA__SECRET_KEY` env variable.
+    Http {
+        verify_url: String,
+        secret_key: Secret<String>,
+        #[serde(deserialize_with = "deserialize_number_from_string")]
+        timeout_milliseconds: u64,
+    },
+    AlwaysPass,
+    AlwaysFail,
+}
+
+impl CaptchaSettings {
+    pub fn verifier(self) -> SignupCaptcha {
+        match self {
+            CaptchaSettings::Disabled => SignupCaptcha(None),
+            CaptchaSettings::Http {
+                verify_url,
+                secret_key,
+                timeout_milliseconds,
+            } => SignupCaptcha(Some(Arc::new(HttpCaptchaVerifier::new(
+                verify_url,
+                secret_key,
+                Duration::from_millis(timeout_milliseconds),
+            )))),
+            CaptchaSettings::AlwaysPass => {
+                SignupCaptcha(Some(Arc::new(LocalCaptchaVerifier::AlwaysPass)))
+            }
+            CaptchaSettings::AlwaysFail => {
+                SignupCaptcha(Some(Arc::new(LocalCaptchaVerifier::AlwaysFail)))
+            }
+        }
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct EmailPolicySettings {
+    // File listing the disposable domains refused at signup, one per line.
+    pub blocked_domains_file: Option<PathBuf>,
+    // Refuse shared mailboxes like `noreply@`, `postmaster@` or `abuse@`.
+    pub reject_role_accounts: bool,
+    // Providers delivering `user+tag@` to `user@`, whose aliases
+    // count as the same subscriber, e.g. `gmail.com`.
+    pub plus_alias_domains: Vec<String>,
+    // Addresses at a domain a typo away from one of these get a
+    // "did you mean" suggestion instead of being accepted as they are.
+    pub suggestion_domains: Vec<String>,
+}
+
+impl EmailPolicySettings {
+    pub fn policy(&self) -> Result<EmailPolicy, std::io::Error> {
+        let blocked_domains = match &self.blocked_domains_file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path).map_err(|e| {
+                    std::io::Error::new(
+                        e.kind(),
+                        format!(
+                            "Failed to read blocked domains from {}: {}",
+                            path.display(),
+                            e
+                        ),
+                    )
+                })?;
+                EmailPolicy::parse_blocklist(&contents)
+            }
+            None => Default::default(),
+        };
+        Ok(EmailPolicy::new(
+            blocked_domains,
+            self.reject_role_accounts,
+            self.plus_alias_domains.iter().cloned().collect(),
+        )
+        .with_suggestion_domains(self.suggestion_domains.clone()))
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct ConfirmationSettings {
+    // How long confirmation links stay valid.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub token_ttl_hours: i64,
+    // Page of our own site people land on after following a confirmation
+    // link, with the outcome in its `status` query parameter, e.g.
+    // `https://example.com/welcome?status=confirmed`. Without one, we render
+    // the page ourselves. There are no lists yet, so one page serves them all.
+    pub redirect_url: Option<String>,
+    // Whether new subscribers have to confirm their address before they get
+    // any issue. When off, signups are confirmed right away and get a welcome
+    // email instead. Imports have their own `--confirmed` switch.
+    // Like `redirect_url`, it applies to every signup until there are lists.
+    pub double_opt_in: bool,
+    // `stored` confirmation links carry a random token we look up in the database,
+    // `signed` ones carry the subscriber id and expiry, signed with `signing_keys`.
+    pub token_mode: ConfirmationTokenMode,
+    // The first key signs new tokens, all of them verify tokens, which lets keys
+    // be rotated. Links signed with a key that is removed stop working.
+    // Secrets need at least 32 bytes.
+    pub signing_keys: Vec<SigningKey>,
+}
+
+impl ConfirmationSettings {
+    pub fn policy(&self) -> Result<ConfirmationPolicy, std::io::Error> {
+        let redirect_url = self
+            .redirect_url
+            .as_deref()
+            .map(reqwest::Url::parse)
+            .transpose()
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid confirmation redirect URL: {}", e),
+                )
+            })?;
+        let token_signer = if self.signing_keys.is_empty() {
+            None
+        } else {
+            let signer = TokenSigner::new(self.signing_keys.clone())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            Some(signer)
+        };
+        if self.token_mode == ConfirmationTokenMode::Signed && token_signer.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Signed confirmation tokens need at least one signing key",
+            ));
+        }
+        Ok(ConfirmationPolicy {
+            token_ttl: chrono::Duration::hours(self.token_ttl_hours),
+            redirect_url,
+            double_opt_in: self.double_opt_in,
+            token_mode: self.token_mode,
+            token_signer,
+        })
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct ReminderSettings {
+    // Whether `serve` also runs the job reminding pending subscribers to confirm.
+    pub enabled: bool,
+    // How long after signing up, or after the previous reminder, the next one goes out.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub delay_hours: i64,
+    // Reminders stop once a subscriber got this many confirmation emails on top
+    // of the first one, resends included.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_reminders: i32,
+    // How often the job looks for reminders that are due.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_secs: u64,
+}
+
+impl ReminderSettings {
+    pub fn policy(&self) -> ReminderPolicy {
+        ReminderPolicy {
+            delay: chrono::Duration::hours(self.delay_hours),
+            max_reminders: self.max_reminders,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct RetentionSettings {
+    // Whether `serve` also runs the job purging stale pending subscriptions.
+    // The `purge-pending` command runs it once, e.g. from cron.
+    pub enabled: bool,
+    // Subscribers still pending this long after signing up are deleted.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub pending_max_age_days: i64,
+    // How often the job runs.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_secs: u64,
+    // Subscribers deleted per transaction, so a large backlog doesn't hold
+    // locks on the whole table.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: i64,
+}
+
+impl RetentionSettings {
+    pub fn pending_max_age(&self) -> chrono::Duration {
+        chrono::Duration::days(self.pending_max_age_days)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct ConsentSettings {
+    // Version of the consent text our signup forms show, stored with every
+    // signup as proof of what people agreed to. Bump it whenever the text changes.
+    pub text_version: String,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct NamePolicySettings {
+    // Characters refused anywhere in a subscriber name, written as one string.
+    pub forbidden_chars: String,
+    // Longest name accepted, counted in user-perceived characters.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_graphemes: usize,
+}
+
+impl NamePolicySettings {
+    pub fn policy(&self) -> NamePolicy {
+        NamePolicy {
+            forbidden_chars: self.forbidden_chars.chars().collect(),
+            max_graphemes: self.max_graphemes,
+        }
+    }
+}
+
 #[derive(Clone, serde::Deserialize)]
 pub struct DatabaseSettings {
     pub username: String,
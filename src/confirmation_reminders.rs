@@ -0,0 +1,206 @@
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    configuration::Settings,
+    domain::{SubscriberEmail, SubscriptionStatus},
+    email_client::EmailClient,
+    email_outbox::{
+        count_confirmation_emails, deliver_outbox_email, enqueue_email, EmailKind, OutboxEmail,
+        OutboxPolicy,
+    },
+    routes::{confirmation_email_bodies, ConfirmationPolicy},
+    startup::get_db_conn_pool,
+};
+
+const REMINDER_EMAIL_SUBJECT: &str = "Please confirm your subscription";
+const REMINDER_EMAIL_INTRO: &str =
+    "You signed up to Mailbolt, but haven't confirmed your email address yet.";
+
+/// When pending subscribers get reminded to confirm their address.
+#[derive(Clone, Debug)]
+pub struct ReminderPolicy {
+    /// How long after the latest confirmation email, resends included, the next reminder goes out.
+    pub delay: chrono::Duration,
+    /// Reminders stop once a subscriber got this many confirmation emails on top
+    /// of the first one, resends included.
+    pub max_reminders: i32,
+}
+
+struct DueReminder {
+    subscriber_id: Uuid,
+    /// `None` when the stored address can't be emailed.
+    outbox_email_id: Option<Uuid>,
+}
+
+/// Check for due reminders every `interval`, until the process stops.
+pub async fn run_reminders_until_stopped(config: Settings) {
+    let pool = get_db_conn_pool(&config.database);
+    let email_client = config.email_client.client();
+    let policy = config.reminders.policy();
+    let outbox_policy = config.email_outbox.policy();
+    let interval = config.reminders.interval();
+    let confirmation_policy = match config.confirmation.policy() {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!(
+                "Invalid confirmation settings, not sending reminders: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    loop {
+        match send_confirmation_reminders(
+            &pool,
+            &email_client,
+            &config.application.base_url,
+            &policy,
+            &confirmation_policy,
+            &outbox_policy,
+        )
+        .await
+        {
+            Ok(0) => {}
+            Ok(sent) => tracing::info!(sent, "Sent confirmation reminders"),
+            Err(e) => tracing::error!("Failed to send confirmation reminders: {:?}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Send one reminder, with another confirmation link, to every pending
+/// subscriber it is due to. Returns how many reminders went out; the others
+/// are left in the outbox for the dispatcher to retry.
+#[tracing::instrument(name = "Send confirmation reminders", skip_all)]
+pub async fn send_confirmation_reminders(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    policy: &ReminderPolicy,
+    confirmation_policy: &ConfirmationPolicy,
+    outbox_policy: &OutboxPolicy,
+) -> Result<u64, anyhow::Error> {
+    let cutoff = Utc::now() - policy.delay;
+    let mut sent = 0;
+
+    while let Some(due) = claim_due_reminder(
+        pool,
+        email_client,
+        base_url,
+        policy,
+        confirmation_policy,
+        cutoff,
+    )
+    .await?
+    {
+        let Some(outbox_email_id) = due.outbox_email_id else {
+            continue;
+        };
+        match deliver_outbox_email(pool, email_client, outbox_policy, outbox_email_id).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(e) => tracing::error!(
+                subscriber_id = %due.subscriber_id,
+                "Failed to send a confirmation reminder: {:?}",
+                e
+            ),
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Pick the next subscriber a reminder is due to, issue them another confirmation
+/// link and queue the reminder in the outbox. Links sent before keep working until
+/// they expire. Other instances of the job skip the rows this one holds, so nobody
+/// gets the same reminder twice.
+///
+/// Every reminder is counted when queued rather than when sent, so a failing
+/// email API costs subscribers a reminder instead of flooding them with them.
+async fn claim_due_reminder(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    base_url: &str,
+    policy: &ReminderPolicy,
+    confirmation_policy: &ConfirmationPolicy,
+    cutoff: chrono::DateTime<Utc>,
+) -> Result<Option<DueReminder>, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let row = sqlx::query!(
+        r#"
+    SELECT id, email FROM subscriptions
+    WHERE status = $1
+      AND confirmation_emails_sent <= $2
+      AND COALESCE(last_confirmation_sent_at, pending_since) <= $3
+    ORDER BY COALESCE(last_confirmation_sent_at, pending_since)
+    LIMIT 1
+    FOR UPDATE SKIP LOCKED
+    "#,
+        SubscriptionStatus::PendingConfirmation.as_str(),
+        policy.max_reminders,
+        cutoff
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look for a subscriber due a reminder")?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let outbox_email_id = match SubscriberEmail::parse(row.email) {
+        Ok(recipient) if email_client.can_deliver_to(&recipient) => {
+            let subscription_token = confirmation_policy
+                .issue_token(&mut transaction, row.id)
+                .await
+                .context("Failed to store the reminder's subscription token")?;
+            let (html_body, text_body) =
+                confirmation_email_bodies(REMINDER_EMAIL_INTRO, base_url, &subscription_token);
+            let id = enqueue_email(
+                &mut transaction,
+                OutboxEmail {
+                    subscriber_id: row.id,
+                    kind: EmailKind::ConfirmationReminder,
+                    recipient: &recipient,
+                    subject: REMINDER_EMAIL_SUBJECT,
+                    html_body: &html_body,
+                    text_body: &text_body,
+                },
+            )
+            .await
+            .context("Failed to queue a confirmation reminder")?;
+            Some(id)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            tracing::warn!(
+                subscriber_id = %row.id,
+                "Skipping a reminder to an invalid stored address: {}",
+                e
+            );
+            None
+        }
+    };
+    if outbox_email_id.is_none() {
+        // Counted as if sent, so a bad row can't stall the batch.
+        count_confirmation_emails(&mut transaction, &[row.id])
+            .await
+            .context("Failed to count a skipped confirmation reminder")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit a confirmation reminder")?;
+
+    Ok(Some(DueReminder {
+        subscriber_id: row.id,
+        outbox_email_id,
+    }))
+}
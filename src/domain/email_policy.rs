@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+
+use super::{email_suggestion::suggest_domain, SubscriberEmail};
+
+/// Local parts of shared mailboxes rather than people, as listed by RFC 2142,
+/// along with the usual "don't reply" addresses.
+const ROLE_ACCOUNTS: [&str; 16] = [
+    "abuse",
+    "do-not-reply",
+    "donotreply",
+    "ftp",
+    "hostmaster",
+    "mailer-daemon",
+    "news",
+    "no-reply",
+    "noc",
+    "noreply",
+    "postmaster",
+    "security",
+    "usenet",
+    "uucp",
+    "webmaster",
+    "www",
+];
+
+/// Which syntactically valid addresses we still refuse to subscribe,
+/// and which ones we consider to be the same mailbox.
+/// The default policy accepts every address.
+#[derive(Debug, Default)]
+pub struct EmailPolicy {
+    blocked_domains: HashSet<String>,
+    reject_role_accounts: bool,
+    plus_alias_domains: HashSet<String>,
+    suggestion_domains: Vec<String>,
+}
+
+impl EmailPolicy {
+    pub fn new(
+        blocked_domains: HashSet<String>,
+        reject_role_accounts: bool,
+        plus_alias_domains: HashSet<String>,
+    ) -> Self {
+        // Addresses are matched on their ASCII domain
+        let to_ascii = |domains: HashSet<String>| {
+            domains
+                .into_iter()
+                .map(|domain| idna::domain_to_ascii(&domain).unwrap_or(domain.to_lowercase()))
+                .collect()
+        };
+        Self {
+            blocked_domains: to_ascii(blocked_domains),
+            reject_role_accounts,
+            plus_alias_domains: to_ascii(plus_alias_domains),
+            suggestion_domains: Vec::new(),
+        }
+    }
+
+    /// Popular domains whose typos we point out, in order of preference.
+    pub fn with_suggestion_domains(mut self, domains: Vec<String>) -> Self {
+        self.suggestion_domains = domains
+            .into_iter()
+            .map(|domain| idna::domain_to_ascii(&domain).unwrap_or(domain.to_lowercase()))
+            .collect();
+        self
+    }
+
+    /// The address `email` was probably meant to be,
+    /// when its domain looks like a typo of a popular one.
+    pub fn suggest(&self, email: &SubscriberEmail) -> Option<String> {
+        let domain = suggest_domain(email.domain(), &self.suggestion_domains)?;
+        let (domain, _) = idna::domain_to_unicode(domain);
+        Some(format!("{}@{}", email.local_part(), domain))
+    }
+
+    /// The form of `email` we enforce uniqueness on. Mail providers treat
+    /// local parts case-insensitively, and the ones in `plus_alias_domains`
+    /// deliver `user+anything@` to `user@`, so all of those are the same subscriber.
+    pub fn canonical(&self, email: &SubscriberEmail) -> String {
+        let local_part = email.local_part().to_lowercase();
+        let domain = email.domain();
+        let mailbox = match local_part.split_once('+') {
+            Some((mailbox, _)) if self.plus_alias_domains.contains(domain) => mailbox,
+            _ => &local_part,
+        };
+        format!("{}@{}", mailbox, domain)
+    }
+
+    /// Read a blocklist with one domain per line.
+    /// Blank lines and everything after a `#` are ignored.
+    pub fn parse_blocklist(contents: &str) -> HashSet<String> {
+        contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|domain| !domain.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+
+    pub fn check(&self, email: &SubscriberEmail) -> Result<(), String> {
+        let domain = email.domain().to_lowercase();
+        // Throwaway services hand out subdomains as well
+        let is_blocked = std::iter::successors(Some(domain.as_str()), |d| {
+            d.split_once('.').map(|(_, parent)| parent)
+        })
+        .any(|d| self.blocked_domains.contains(d));
+        if is_blocked {
+            return Err(format!(
+                "Addresses at '{}' are disposable and can't subscribe",
+                domain
+            ));
+        }
+
+        let local_part = email.local_part().to_lowercase();
+        let mailbox = local_part.split('+').next().unwrap_or_default();
+        if self.reject_role_accounts && ROLE_ACCOUNTS.contains(&mailbox) {
+            return Err(format!(
+                "'{}' is a role address, please subscribe with a personal one",
+                email.as_ref()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok};
+
+    use super::EmailPolicy;
+    use crate::domain::SubscriberEmail;
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.into()).unwrap()
+    }
+
+    fn strict_policy() -> EmailPolicy {
+        let blocklist =
+            "# Disposable domains\nmailinator.com\n\nTrashMail.com  # and its aliases\n";
+        EmailPolicy::new(
+            EmailPolicy::parse_blocklist(blocklist),
+            true,
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn the_default_policy_accepts_every_address() {
+        let policy = EmailPolicy::default();
+
+        assert_ok!(policy.check(&email("noreply@mailinator.com")));
+    }
+
+    #[test]
+    fn blocked_domains_and_their_subdomains_are_rejected() {
+        let policy = strict_policy();
+
+        assert_err!(policy.check(&email("ursula@mailinator.com")));
+        assert_err!(policy.check(&email("ursula@eu.MAILINATOR.com")));
+        assert_err!(policy.check(&email("ursula@trashmail.com")));
+        assert_ok!(policy.check(&email("ursula@notmailinator.com")));
+    }
+
+    #[test]
+    fn role_addresses_are_rejected_when_configured() {
+        let policy = strict_policy();
+
+        for role in ["noreply", "Postmaster", "abuse", "no-reply+news"] {
+            let result = policy.check(&email(&format!("{}@example.com", role)));
+            assert_err!(&result, "{} was accepted", role);
+            assert!(result.unwrap_err().contains("role address"));
+        }
+        assert_ok!(policy.check(&email("ursula@example.com")));
+
+        let lenient = EmailPolicy::new(Default::default(), false, Default::default());
+        assert_ok!(lenient.check(&email("postmaster@example.com")));
+    }
+
+    #[test]
+    fn canonical_addresses_ignore_case_and_configured_plus_aliases() {
+        let policy = EmailPolicy::new(
+            Default::default(),
+            false,
+            ["GMail.com".to_string()].into_iter().collect(),
+        );
+
+        assert_eq!(
+            policy.canonical(&email("Ursula.LeGuin@Example.com")),
+            "ursula.leguin@example.com"
+        );
+        assert_eq!(
+            policy.canonical(&email("Ursula+news@gmail.com")),
+            "ursula@gmail.com"
+        );
+        assert_eq!(
+            policy.canonical(&email("ursula+news@example.com")),
+            "ursula+news@example.com"
+        );
+    }
+
+    #[test]
+    fn suggestions_keep_the_local_part() {
+        let policy = EmailPolicy::default()
+            .with_suggestion_domains(vec!["gmail.com".into(), "hotmail.com".into()]);
+
+        assert_eq!(
+            policy.suggest(&email("Jane.Doe@gmial.com")).as_deref(),
+            Some("Jane.Doe@gmail.com")
+        );
+        assert_eq!(policy.suggest(&email("jane@gmail.com")), None);
+        assert_eq!(
+            EmailPolicy::default().suggest(&email("jane@gmial.com")),
+            None
+        );
+    }
+}
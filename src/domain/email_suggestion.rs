@@ -0,0 +1,102 @@
+/// The popular domain `domain` is most likely a typo of, if any.
+///
+/// The longer a domain, the more typos it takes before we stop recognizing it:
+/// short domains are too close to one another for a single typo to tell.
+pub fn suggest_domain<'a>(domain: &str, popular_domains: &'a [String]) -> Option<&'a str> {
+    if popular_domains.iter().any(|d| d == domain) {
+        return None;
+    }
+
+    popular_domains
+        .iter()
+        .filter_map(|candidate| {
+            let max_distance = match candidate.chars().count() {
+                0..=6 => return None,
+                7..=9 => 1,
+                _ => 2,
+            };
+            let distance = edit_distance(domain, candidate);
+            (distance <= max_distance).then_some((distance, candidate.as_str()))
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Damerau-Levenshtein distance (optimal string alignment variant):
+/// insertions, deletions, substitutions and swaps of adjacent characters
+/// all count as one edit. Swaps are the most common typo, as in `gmial.com`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // distances[i][j] is the distance between the first i chars of a and the first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, suggest_domain};
+
+    fn popular_domains() -> Vec<String> {
+        [
+            "gmail.com",
+            "hotmail.com",
+            "yahoo.com",
+            "email.com",
+            "me.com",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+
+    #[test]
+    fn swapped_characters_count_as_a_single_edit() {
+        assert_eq!(edit_distance("gmial.com", "gmail.com"), 1);
+        assert_eq!(edit_distance("gmail.con", "gmail.com"), 1);
+        assert_eq!(edit_distance("gmai.com", "gmail.com"), 1);
+        assert_eq!(edit_distance("example.com", "gmail.com"), 5);
+    }
+
+    #[test]
+    fn typos_of_popular_domains_get_a_suggestion() {
+        let domains = popular_domains();
+
+        assert_eq!(suggest_domain("gmial.com", &domains), Some("gmail.com"));
+        assert_eq!(suggest_domain("hotmial.con", &domains), Some("hotmail.com"));
+        assert_eq!(suggest_domain("yaho.com", &domains), Some("yahoo.com"));
+    }
+
+    #[test]
+    fn popular_and_unrelated_domains_are_left_alone() {
+        let domains = popular_domains();
+
+        // `email.com` is one edit away from `gmail.com`, but exists in its own right
+        assert_eq!(suggest_domain("email.com", &domains), None);
+        assert_eq!(suggest_domain("gmail.com", &domains), None);
+        assert_eq!(suggest_domain("example.com", &domains), None);
+        // Too short to tell a typo from another domain
+        assert_eq!(suggest_domain("mx.com", &domains), None);
+    }
+}
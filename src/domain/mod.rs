@@ -1,7 +1,16 @@
+mod email_policy;
+mod email_suggestion;
 mod new_subscriber;
 mod subscriber_email;
 mod subscriber_name;
+mod subscription_event;
+mod subscription_status;
+mod validation_errors;
 
+pub use email_policy::EmailPolicy;
 pub use new_subscriber::NewSubscriber;
 pub use subscriber_email::SubscriberEmail;
-pub use subscriber_name::SubscriberName;
+pub use subscriber_name::{NamePolicy, SubscriberName};
+pub use subscription_event::{EventActor, SubscriptionEventKind};
+pub use subscription_status::{InvalidTransition, SubscriptionStatus};
+pub use validation_errors::{FieldError, ValidationErrors};
@@ -1,21 +1,81 @@
 use validator::validate_email;
 
+/// Characters allowed in an unquoted local part besides letters and digits.
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~.";
+
+/// A valid email address, internationalized ones (RFC 6531) included.
 #[derive(Debug)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    /// The address as people read it, with a lowercase Unicode domain.
+    address: String,
+    local_part: String,
+    ascii_domain: String,
+}
 
 impl SubscriberEmail {
+    /// Parse an address, normalizing its domain to lowercase, as domains are
+    /// case-insensitive anyway. The local part is kept as typed: only the
+    /// receiving server knows what it means.
     pub fn parse(s: String) -> Result<Self, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
+        let invalid = || format!("'{}' is not a valid email", s);
+
+        let (local_part, domain) = s.trim().rsplit_once('@').ok_or_else(invalid)?;
+        let ascii_domain = idna::domain_to_ascii(domain).map_err(|_| invalid())?;
+        let (unicode_domain, errors) = idna::domain_to_unicode(&ascii_domain);
+        errors.map_err(|_| invalid())?;
+
+        // `validate_email` predates non-ASCII local parts, so we check
+        // those ourselves and let it check the rest with a stand-in.
+        let checked_local_part = if local_part.is_ascii() {
+            local_part
+        } else if is_valid_utf8_local_part(local_part) {
+            "utf8"
         } else {
-            Err(format!("'{}' is not a valid email", s))
+            return Err(invalid());
+        };
+        if !validate_email(format!("{}@{}", checked_local_part, ascii_domain)) {
+            return Err(invalid());
         }
+
+        Ok(Self {
+            address: format!("{}@{}", local_part, unicode_domain),
+            local_part: local_part.to_string(),
+            ascii_domain,
+        })
+    }
+
+    /// Everything before the last `@`.
+    pub fn local_part(&self) -> &str {
+        &self.local_part
+    }
+
+    /// The domain in its ASCII form, which is punycode for internationalized domains.
+    pub fn domain(&self) -> &str {
+        &self.ascii_domain
     }
+
+    /// The address as servers without SMTPUTF8 support know it, if there is one:
+    /// unlike domains, local parts with non-ASCII characters have no ASCII spelling.
+    pub fn ascii(&self) -> Option<String> {
+        self.local_part
+            .is_ascii()
+            .then(|| format!("{}@{}", self.local_part, self.ascii_domain))
+    }
+}
+
+fn is_valid_utf8_local_part(local_part: &str) -> bool {
+    !local_part.is_empty()
+        && local_part.len() <= 64
+        && local_part.chars().all(|c| {
+            c.is_alphanumeric()
+                || ATEXT_SPECIALS.contains(c)
+                || (!c.is_ascii() && !c.is_control() && !c.is_whitespace())
+        })
 }
 
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.address
     }
 }
 
@@ -60,6 +120,42 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn domains_are_normalized() {
+        let email = SubscriberEmail::parse(" Ursula.Le.Guin@Example.COM ".into()).unwrap();
+        assert_eq!(email.as_ref(), "Ursula.Le.Guin@example.com");
+    }
+
+    #[test]
+    fn internationalized_domains_keep_both_forms() {
+        let email = SubscriberEmail::parse("ursula@Bücher.example".into()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@bücher.example");
+        assert_eq!(email.domain(), "xn--bcher-kva.example");
+        assert_eq!(
+            email.ascii().as_deref(),
+            Some("ursula@xn--bcher-kva.example")
+        );
+
+        let email = SubscriberEmail::parse("ursula@xn--bcher-kva.example".into()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@bücher.example");
+    }
+
+    #[test]
+    fn non_ascii_local_parts_are_allowed_without_an_ascii_form() {
+        let email = SubscriberEmail::parse("用户@例子.广告".into()).unwrap();
+        assert_eq!(email.as_ref(), "用户@例子.广告");
+        assert!(email.domain().is_ascii());
+        assert_eq!(email.ascii(), None);
+
+        assert_ok!(SubscriberEmail::parse("josé.garcía@example.com".into()));
+        assert_err!(SubscriberEmail::parse("jo sé@example.com".into()));
+        assert_err!(SubscriberEmail::parse("josé\u{7}@example.com".into()));
+        assert_err!(SubscriberEmail::parse(format!(
+            "{}@example.com",
+            "é".repeat(33)
+        )));
+    }
+
     #[test]
     fn valid_emails_should_be_allowed() {
         let email = SafeEmail().fake();
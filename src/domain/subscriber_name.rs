@@ -1,26 +1,109 @@
+use std::collections::HashSet;
+
+use unicode_general_category::{get_general_category, GeneralCategory};
+use unicode_normalization::UnicodeNormalization;
+use unicode_script::{Script, UnicodeScript};
 use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
-pub static FORBIDDEN_CHARS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+pub static DEFAULT_FORBIDDEN_CHARS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+pub const DEFAULT_MAX_GRAPHEMES: usize = 256;
+
+/// Scripts a single word may legitimately mix, as Japanese and Korean writing do.
+const MIXABLE_SCRIPTS: [&[Script]; 3] = [
+    &[Script::Han, Script::Hiragana, Script::Katakana],
+    &[Script::Han, Script::Hangul],
+    &[Script::Han, Script::Bopomofo],
+];
+
+/// What we accept as a subscriber name, on top of the checks
+/// keeping names from breaking or spoofing the emails they end up in.
+#[derive(Debug, Clone)]
+pub struct NamePolicy {
+    pub forbidden_chars: Vec<char>,
+    pub max_graphemes: usize,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self {
+            forbidden_chars: DEFAULT_FORBIDDEN_CHARS.to_vec(),
+            max_graphemes: DEFAULT_MAX_GRAPHEMES,
+        }
+    }
+}
 
 impl SubscriberName {
     pub fn parse(s: String) -> Result<Self, String> {
+        Self::parse_with(s, &NamePolicy::default())
+    }
+
+    /// Parse a name into its NFC form, so that the same name
+    /// is always stored with the same characters.
+    pub fn parse_with(s: String, policy: &NamePolicy) -> Result<Self, String> {
+        let s: String = s.nfc().collect();
+
         let is_empty_or_whitespace = s.trim().is_empty();
 
-        let is_too_long = s.graphemes(true).count() > 256;
+        let is_too_long = s.graphemes(true).count() > policy.max_graphemes;
+
+        let contains_forbidden_chars = s.chars().any(|c| policy.forbidden_chars.contains(&c));
 
-        let contains_forbidden_chars = s.chars().any(|c| FORBIDDEN_CHARS.contains(&c));
+        // Control characters, and format ones like zero-width joiners or bidi
+        // overrides, are invisible but can reorder or hide the text around them.
+        let contains_invisible_chars = s.chars().any(|c| {
+            matches!(
+                get_general_category(c),
+                GeneralCategory::Control | GeneralCategory::Format
+            )
+        });
 
-        if is_empty_or_whitespace || is_too_long || contains_forbidden_chars {
-            Err(format!("{} is not a valid subscriber name", s))
+        if is_empty_or_whitespace {
+            Err("The subscriber name cannot be empty".into())
+        } else if is_too_long {
+            Err(format!(
+                "The subscriber name cannot be longer than {} characters",
+                policy.max_graphemes
+            ))
+        } else if contains_forbidden_chars {
+            let forbidden: Vec<_> = policy.forbidden_chars.iter().map(char::to_string).collect();
+            Err(format!(
+                "The subscriber name cannot contain any of {}",
+                forbidden.join(" ")
+            ))
+        } else if contains_invisible_chars {
+            Err(
+                "The subscriber name cannot contain control or invisible formatting characters"
+                    .into(),
+            )
+        } else if let Some(word) = s.split_whitespace().find(|word| mixes_scripts(word)) {
+            Err(format!(
+                "'{}' mixes letters from different alphabets that look alike",
+                word
+            ))
         } else {
             Ok(Self(s))
         }
     }
 }
 
+/// Whether a word mixes scripts, as homoglyph spoofing does, e.g. a Cyrillic
+/// `а` among Latin letters. Punctuation, digits and the like belong to no script.
+fn mixes_scripts(word: &str) -> bool {
+    let scripts: HashSet<Script> = word
+        .chars()
+        .map(|c| c.script())
+        .filter(|script| !matches!(script, Script::Common | Script::Inherited | Script::Unknown))
+        .collect();
+
+    scripts.len() > 1
+        && !MIXABLE_SCRIPTS
+            .iter()
+            .any(|mixable| scripts.iter().all(|script| mixable.contains(script)))
+}
+
 impl AsRef<str> for SubscriberName {
     fn as_ref(&self) -> &str {
         &self.0
@@ -33,7 +116,7 @@ mod tests {
 
     use crate::domain::SubscriberName;
 
-    use super::FORBIDDEN_CHARS;
+    use super::{NamePolicy, DEFAULT_FORBIDDEN_CHARS};
 
     #[test]
     fn a_256_grapheme_long_name_is_valid() {
@@ -49,9 +132,58 @@ mod tests {
 
     #[test]
     fn names_with_invalid_chars_are_rejected() {
-        for name in &FORBIDDEN_CHARS {
+        for name in &DEFAULT_FORBIDDEN_CHARS {
             let name = name.to_string();
             assert_err!(SubscriberName::parse(name));
         }
     }
+
+    #[test]
+    fn names_are_nfc_normalized() {
+        // `e` followed by a combining acute accent
+        let name = SubscriberName::parse("Rene\u{301}e".into()).unwrap();
+        assert_eq!(name.as_ref(), "Ren\u{e9}e");
+    }
+
+    #[test]
+    fn names_with_control_or_format_chars_are_rejected() {
+        for name in [
+            "Ursula\u{0}",
+            "Ursula\u{1b}[31m",
+            "Ur\u{200d}sula",
+            "Ur\u{200b}sula",
+            "Ursula \u{202e}niugeL",
+            "\u{2066}Ursula\u{2069}",
+        ] {
+            assert_err!(
+                SubscriberName::parse(name.into()),
+                "{:?} was accepted",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn words_mixing_lookalike_scripts_are_rejected() {
+        // The first `а` is Cyrillic
+        assert_err!(SubscriberName::parse("P\u{430}ypal Support".into()));
+
+        assert_ok!(SubscriberName::parse("Ursula K. Le Guin".into()));
+        assert_ok!(SubscriberName::parse("Фёдор Dostoevsky".into()));
+        assert_ok!(SubscriberName::parse("Ἀριστοτέλης".into()));
+        assert_ok!(SubscriberName::parse("村上 はるき".into()));
+        assert_ok!(SubscriberName::parse("O'Brien-Smith 2nd".into()));
+    }
+
+    #[test]
+    fn the_policy_sets_the_length_and_forbidden_chars() {
+        let policy = NamePolicy {
+            forbidden_chars: vec!['@'],
+            max_graphemes: 5,
+        };
+
+        assert_err!(SubscriberName::parse_with("Ursula".into(), &policy));
+        assert_err!(SubscriberName::parse_with("U@L".into(), &policy));
+        assert_ok!(SubscriberName::parse_with("U<L>".into(), &policy));
+    }
 }
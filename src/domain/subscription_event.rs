@@ -0,0 +1,171 @@
+use sqlx::{
+    error::BoxDynError,
+    postgres::{PgTypeInfo, PgValueRef},
+    Postgres,
+};
+
+use super::SubscriptionStatus;
+
+/// Something that happened to a subscription, as kept in its history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionEventKind {
+    Subscribed,
+    ConfirmationSent,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    /// The subscriber's personal data was deleted. Their history outlives them.
+    Erased,
+    /// The subscriber moved their subscription to another address.
+    EmailChanged,
+}
+
+/// Who caused a subscription event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventActor {
+    /// The subscriber, e.g. by filling in the form or following a link.
+    Subscriber,
+    /// An admin, through the admin API.
+    Admin,
+    /// A bulk import, from the admin API or the CLI.
+    Import,
+    /// Mailbolt itself, e.g. when sending emails.
+    System,
+}
+
+impl SubscriptionEventKind {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "subscribed" => Ok(Self::Subscribed),
+            "confirmation_sent" => Ok(Self::ConfirmationSent),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "bounced" => Ok(Self::Bounced),
+            "erased" => Ok(Self::Erased),
+            "email_changed" => Ok(Self::EmailChanged),
+            unknown => Err(format!("'{}' is not a subscription event", unknown)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Subscribed => "subscribed",
+            Self::ConfirmationSent => "confirmation_sent",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Bounced => "bounced",
+            Self::Erased => "erased",
+            Self::EmailChanged => "email_changed",
+        }
+    }
+}
+
+/// The event recorded when a subscription enters a status.
+impl From<SubscriptionStatus> for SubscriptionEventKind {
+    fn from(status: SubscriptionStatus) -> Self {
+        match status {
+            SubscriptionStatus::PendingConfirmation => Self::Subscribed,
+            SubscriptionStatus::Confirmed => Self::Confirmed,
+            SubscriptionStatus::Unsubscribed => Self::Unsubscribed,
+            SubscriptionStatus::Bounced => Self::Bounced,
+        }
+    }
+}
+
+impl EventActor {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "subscriber" => Ok(Self::Subscriber),
+            "admin" => Ok(Self::Admin),
+            "import" => Ok(Self::Import),
+            "system" => Ok(Self::System),
+            unknown => Err(format!("'{}' is not an event actor", unknown)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Subscriber => "subscriber",
+            Self::Admin => "admin",
+            Self::Import => "import",
+            Self::System => "system",
+        }
+    }
+}
+
+impl sqlx::Type<Postgres> for SubscriptionEventKind {
+    fn type_info() -> PgTypeInfo {
+        <&str as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl<'r> sqlx::Decode<'r, Postgres> for SubscriptionEventKind {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let kind = <&str as sqlx::Decode<Postgres>>::decode(value)?;
+        Ok(Self::parse(kind)?)
+    }
+}
+
+impl sqlx::Type<Postgres> for EventActor {
+    fn type_info() -> PgTypeInfo {
+        <&str as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl<'r> sqlx::Decode<'r, Postgres> for EventActor {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let actor = <&str as sqlx::Decode<Postgres>>::decode(value)?;
+        Ok(Self::parse(actor)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventActor, SubscriptionEventKind};
+    use crate::domain::SubscriptionStatus;
+
+    #[test]
+    fn kinds_and_actors_round_trip_through_their_text_form() {
+        use SubscriptionEventKind::*;
+
+        for kind in [
+            Subscribed,
+            ConfirmationSent,
+            Confirmed,
+            Unsubscribed,
+            Bounced,
+            Erased,
+            EmailChanged,
+        ] {
+            assert_eq!(SubscriptionEventKind::parse(kind.as_str()), Ok(kind));
+        }
+        for actor in [
+            EventActor::Subscriber,
+            EventActor::Admin,
+            EventActor::Import,
+            EventActor::System,
+        ] {
+            assert_eq!(EventActor::parse(actor.as_str()), Ok(actor));
+        }
+    }
+
+    #[test]
+    fn statuses_are_entered_through_the_event_of_the_same_name() {
+        for status in [
+            SubscriptionStatus::Confirmed,
+            SubscriptionStatus::Unsubscribed,
+            SubscriptionStatus::Bounced,
+        ] {
+            assert_eq!(
+                SubscriptionEventKind::from(status).as_str(),
+                status.as_str()
+            );
+        }
+        assert_eq!(
+            SubscriptionEventKind::from(SubscriptionStatus::PendingConfirmation),
+            SubscriptionEventKind::Subscribed
+        );
+    }
+}
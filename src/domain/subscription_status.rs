@@ -0,0 +1,126 @@
+use sqlx::{
+    error::BoxDynError,
+    postgres::{PgTypeInfo, PgValueRef},
+    Postgres,
+};
+
+/// Where a subscriber stands with the newsletter.
+/// Stored as text, which a CHECK constraint keeps to these values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    /// Signed up, but has yet to click the link in the confirmation email.
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    /// Emails to the address bounced, so we stopped sending any.
+    Bounced,
+}
+
+/// A status change `SubscriptionStatus::transition_to` refused.
+#[derive(Debug, thiserror::Error)]
+#[error("A subscription cannot go from {from} to {to}")]
+pub struct InvalidTransition {
+    pub from: SubscriptionStatus,
+    pub to: SubscriptionStatus,
+}
+
+impl SubscriptionStatus {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "pending_confirmation" => Ok(Self::PendingConfirmation),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "bounced" => Ok(Self::Bounced),
+            unknown => Err(format!("'{}' is not a subscription status", unknown)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PendingConfirmation => "pending_confirmation",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Bounced => "bounced",
+        }
+    }
+
+    /// Every status change a subscription can go through.
+    /// People who left, or whose address bounced, can come back,
+    /// but only by confirming their address again.
+    pub fn can_transition_to(self, to: Self) -> bool {
+        use SubscriptionStatus::*;
+
+        matches!(
+            (self, to),
+            (PendingConfirmation, Confirmed | Unsubscribed | Bounced)
+                | (Confirmed, Unsubscribed | Bounced)
+                | (Unsubscribed | Bounced, PendingConfirmation)
+        )
+    }
+
+    pub fn transition_to(self, to: Self) -> Result<Self, InvalidTransition> {
+        if self.can_transition_to(to) {
+            Ok(to)
+        } else {
+            Err(InvalidTransition { from: self, to })
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl sqlx::Type<Postgres> for SubscriptionStatus {
+    fn type_info() -> PgTypeInfo {
+        <&str as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl<'r> sqlx::Decode<'r, Postgres> for SubscriptionStatus {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let status = <&str as sqlx::Decode<Postgres>>::decode(value)?;
+        Ok(Self::parse(status)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok};
+
+    use super::SubscriptionStatus::{self, *};
+
+    const ALL: [SubscriptionStatus; 4] = [PendingConfirmation, Confirmed, Unsubscribed, Bounced];
+
+    #[test]
+    fn statuses_round_trip_through_their_text_form() {
+        for status in ALL {
+            assert_eq!(SubscriptionStatus::parse(status.as_str()), Ok(status));
+        }
+        assert_err!(SubscriptionStatus::parse("Confirmed"));
+    }
+
+    #[test]
+    fn subscribers_confirm_then_leave_and_come_back_pending() {
+        let status = assert_ok!(PendingConfirmation.transition_to(Confirmed));
+        let status = assert_ok!(status.transition_to(Unsubscribed));
+        let status = assert_ok!(status.transition_to(PendingConfirmation));
+        assert_ok!(status.transition_to(Bounced));
+        assert_ok!(Confirmed.transition_to(Bounced));
+        assert_ok!(Bounced.transition_to(PendingConfirmation));
+    }
+
+    #[test]
+    fn leaving_subscribers_cannot_be_confirmed_again_directly() {
+        assert_err!(Unsubscribed.transition_to(Confirmed));
+        assert_err!(Bounced.transition_to(Confirmed));
+        assert_err!(Confirmed.transition_to(PendingConfirmation));
+        assert_err!(Unsubscribed.transition_to(Bounced));
+        for status in ALL {
+            assert_err!(status.transition_to(status));
+        }
+    }
+}
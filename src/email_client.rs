@@ -8,10 +8,26 @@ pub struct EmailClient {
     base_url: String,
     sender: SubscriberEmail,
     auth_token: Secret<String>,
+    smtputf8: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmailClientError {
+    #[error("'{0}' can only be reached by an email API supporting SMTPUTF8")]
+    UnsupportedAddress(String),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
 }
 
 impl EmailClient {
-    pub fn new(base_url: String, sender: SubscriberEmail, auth_token: Secret<String>) -> Self {
+    /// `smtputf8` tells whether the email API delivers to internationalized
+    /// addresses as they are. Without it, we fall back to their ASCII form.
+    pub fn new(
+        base_url: String,
+        sender: SubscriberEmail,
+        auth_token: Secret<String>,
+        smtputf8: bool,
+    ) -> Self {
         let http_client = Client::builder()
             .timeout(std::time::Duration::from_secs(10))
             .build()
@@ -21,6 +37,22 @@ impl EmailClient {
             base_url,
             sender,
             auth_token,
+            smtputf8,
+        }
+    }
+
+    /// Whether the email API can deliver to `email` at all.
+    pub fn can_deliver_to(&self, email: &SubscriberEmail) -> bool {
+        self.address_for_api(email).is_ok()
+    }
+
+    fn address_for_api(&self, email: &SubscriberEmail) -> Result<String, EmailClientError> {
+        if self.smtputf8 {
+            Ok(email.as_ref().to_string())
+        } else {
+            email
+                .ascii()
+                .ok_or_else(|| EmailClientError::UnsupportedAddress(email.as_ref().to_string()))
         }
     }
 
@@ -30,12 +62,14 @@ impl EmailClient {
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
+    ) -> Result<(), EmailClientError> {
         let url = format!("{}/email", self.base_url);
 
+        let from = self.address_for_api(&self.sender)?;
+        let to = self.address_for_api(&recipient)?;
         let body = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recipient.as_ref(),
+            from: &from,
+            to: &to,
             subject,
             html_body: html_content,
             text_body: text_content,
@@ -71,7 +105,7 @@ mod tests {
     use fake::Faker;
     use fake::{faker::internet::en::SafeEmail, Fake};
     use secrecy::Secret;
-    use wiremock::matchers::{any, header_exists, method, path};
+    use wiremock::matchers::{any, body_partial_json, header_exists, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     use crate::domain::SubscriberEmail;
@@ -108,7 +142,7 @@ mod tests {
     }
 
     fn email_client(base_url: String) -> EmailClient {
-        EmailClient::new(base_url, email(), Secret::new(Faker.fake()))
+        EmailClient::new(base_url, email(), Secret::new(Faker.fake()), false)
     }
 
     #[tokio::test]
@@ -150,4 +184,56 @@ mod tests {
 
         assert_err!(result);
     }
+
+    #[tokio::test]
+    async fn send_email_uses_the_ascii_form_unless_the_api_supports_smtputf8() {
+        let mock_server = MockServer::start().await;
+        let idn_email = || SubscriberEmail::parse("ursula@bücher.example".into()).unwrap();
+
+        Mock::given(body_partial_json(serde_json::json!({
+            "To": "ursula@xn--bcher-kva.example"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+        let result = email_client(mock_server.uri())
+            .send_email(idn_email(), &subject(), &content(), &content())
+            .await;
+        assert_ok!(result);
+
+        mock_server.reset().await;
+        Mock::given(body_partial_json(serde_json::json!({
+            "To": "ursula@bücher.example"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+        let email_client =
+            EmailClient::new(mock_server.uri(), email(), Secret::new(Faker.fake()), true);
+        let result = email_client
+            .send_email(idn_email(), &subject(), &content(), &content())
+            .await;
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn send_email_refuses_non_ascii_local_parts_without_smtputf8() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let recipient = SubscriberEmail::parse("josé@example.com".into()).unwrap();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        assert!(!email_client.can_deliver_to(&recipient));
+        let result = email_client
+            .send_email(recipient, &subject(), &content(), &content())
+            .await;
+        assert_err!(result);
+    }
 }
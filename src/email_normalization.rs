@@ -0,0 +1,93 @@
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domain::{EmailPolicy, SubscriberEmail};
+
+/// Rows read per round trip while going through every subscriber.
+const BATCH_SIZE: i64 = 500;
+
+/// What `normalize_stored_emails` did.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct NormalizationReport {
+    /// Subscribers whose normalized address changed.
+    pub updated: u64,
+    /// Addresses left as they were because another subscriber already has
+    /// their normalized form. They have to be merged by hand.
+    pub conflicts: Vec<String>,
+    /// Stored addresses that aren't valid anymore, left as they were.
+    pub invalid: Vec<String>,
+}
+
+/// Recompute `email_normalized` for every subscriber with `EmailPolicy::canonical`.
+///
+/// Rows stored before the column existed only got their address lowercased,
+/// and changing `email_policy.plus_alias_domains` changes what the normalized
+/// form is, so run this after either for signups to find those subscribers.
+#[tracing::instrument(name = "Normalize stored emails", skip_all)]
+pub async fn normalize_stored_emails(
+    pool: &PgPool,
+    email_policy: &EmailPolicy,
+) -> Result<NormalizationReport, anyhow::Error> {
+    let mut report = NormalizationReport::default();
+    let mut after = Uuid::nil();
+    loop {
+        let batch = sqlx::query!(
+            r#"
+    SELECT id, email, email_normalized FROM subscriptions
+    WHERE id > $1
+    ORDER BY id
+    LIMIT $2
+    "#,
+            after,
+            BATCH_SIZE
+        )
+        .fetch_all(pool)
+        .await
+        .context("Failed to read stored subscribers")?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = last.id;
+
+        for row in batch {
+            let email = match SubscriberEmail::parse(row.email.clone()) {
+                Ok(email) => email,
+                Err(_) => {
+                    report.invalid.push(row.email);
+                    continue;
+                }
+            };
+            let canonical = email_policy.canonical(&email);
+            if canonical == row.email_normalized {
+                continue;
+            }
+
+            let updated = sqlx::query!(
+                r#"
+    UPDATE subscriptions SET email_normalized = $1
+    WHERE id = $2
+      AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE email_normalized = $1)
+    "#,
+                canonical,
+                row.id
+            )
+            .execute(pool)
+            .await
+            .context("Failed to update a normalized address")?;
+            if updated.rows_affected() > 0 {
+                report.updated += 1;
+            } else {
+                report.conflicts.push(row.email);
+            }
+        }
+    }
+
+    tracing::info!(
+        updated = report.updated,
+        conflicts = report.conflicts.len(),
+        invalid = report.invalid.len(),
+        "Normalized stored emails"
+    );
+    Ok(report)
+}
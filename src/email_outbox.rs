@@ -0,0 +1,493 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    error::BoxDynError,
+    postgres::{PgTypeInfo, PgValueRef},
+    PgPool, Postgres, Transaction,
+};
+use uuid::Uuid;
+
+use crate::{
+    configuration::Settings,
+    domain::{EventActor, SubscriberEmail, SubscriptionEventKind},
+    email_client::EmailClient,
+    email_suppressions::is_suppressed,
+    routes::record_subscription_event,
+    startup::get_db_conn_pool,
+};
+
+/// How long a claimed email is left to its dispatcher before others pick it up
+/// again. Well over the email API timeout, so that only happens if it died mid-send.
+const CLAIM_TIMEOUT_SECS: i64 = 120;
+
+/// How the dispatcher retries emails the email API did not take.
+#[derive(Clone, Debug)]
+pub struct OutboxPolicy {
+    /// Attempts after which an email is given up on, counting the first one.
+    pub max_attempts: i32,
+    /// Wait after the first failed attempt, doubled after every other one.
+    pub retry_base_delay: chrono::Duration,
+    pub retry_max_delay: chrono::Duration,
+}
+
+impl OutboxPolicy {
+    pub(crate) fn retry_delay(&self, failed_attempts: i32) -> chrono::Duration {
+        let doublings = failed_attempts.saturating_sub(1).clamp(0, 62) as u32;
+        let delay = self
+            .retry_base_delay
+            .num_seconds()
+            .saturating_mul(2_i64.saturating_pow(doublings));
+        chrono::Duration::seconds(delay.min(self.retry_max_delay.num_seconds()))
+    }
+}
+
+/// What an outbox email is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmailKind {
+    Confirmation,
+    ConfirmationReminder,
+    /// Sent instead of a confirmation email when double opt-in is off.
+    Welcome,
+    /// Asks the current address to approve a move to another one.
+    EmailChangeApproval,
+    EmailChangeConfirmation,
+    /// Tells the previous address that the subscription moved.
+    EmailChangeNotice,
+}
+
+impl EmailKind {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "confirmation" => Ok(Self::Confirmation),
+            "confirmation_reminder" => Ok(Self::ConfirmationReminder),
+            "welcome" => Ok(Self::Welcome),
+            "email_change_approval" => Ok(Self::EmailChangeApproval),
+            "email_change_confirmation" => Ok(Self::EmailChangeConfirmation),
+            "email_change_notice" => Ok(Self::EmailChangeNotice),
+            unknown => Err(format!("'{}' is not a kind of email", unknown)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Confirmation => "confirmation",
+            Self::ConfirmationReminder => "confirmation_reminder",
+            Self::Welcome => "welcome",
+            Self::EmailChangeApproval => "email_change_approval",
+            Self::EmailChangeConfirmation => "email_change_confirmation",
+            Self::EmailChangeNotice => "email_change_notice",
+        }
+    }
+
+    /// Whether the email asks to confirm the subscription, which reminders keep count of.
+    fn is_confirmation(self) -> bool {
+        matches!(self, Self::Confirmation | Self::ConfirmationReminder)
+    }
+
+    /// The event recorded in the subscriber history once the email is sent, if any.
+    fn sent_event(self) -> Option<SubscriptionEventKind> {
+        match self {
+            Self::Confirmation | Self::ConfirmationReminder => {
+                Some(SubscriptionEventKind::ConfirmationSent)
+            }
+            Self::Welcome
+            | Self::EmailChangeApproval
+            | Self::EmailChangeConfirmation
+            | Self::EmailChangeNotice => None,
+        }
+    }
+}
+
+impl sqlx::Type<Postgres> for EmailKind {
+    fn type_info() -> PgTypeInfo {
+        <&str as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl<'r> sqlx::Decode<'r, Postgres> for EmailKind {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let kind = <&str as sqlx::Decode<Postgres>>::decode(value)?;
+        Ok(Self::parse(kind)?)
+    }
+}
+
+/// An email waiting in the outbox.
+pub struct OutboxEmail<'a> {
+    pub subscriber_id: Uuid,
+    pub kind: EmailKind,
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+}
+
+struct ClaimedEmail {
+    id: Uuid,
+    subscriber_id: Uuid,
+    kind: EmailKind,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    attempts: i32,
+}
+
+/// Write an email to the outbox, as part of the transaction it is about.
+/// It goes out once that transaction commits, from `deliver_outbox_email`
+/// or the dispatcher.
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: OutboxEmail<'_>,
+) -> Result<Uuid, sqlx::Error> {
+    let ids = enqueue_emails(transaction, &[email]).await?;
+    Ok(ids[0])
+}
+
+/// Like `enqueue_email`, with a single statement for all of `emails`.
+/// Returns their ids, in the same order.
+pub async fn enqueue_emails(
+    transaction: &mut Transaction<'_, Postgres>,
+    emails: &[OutboxEmail<'_>],
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let ids: Vec<_> = emails.iter().map(|_| Uuid::new_v4()).collect();
+    let column = |field: for<'a> fn(&'a OutboxEmail<'a>) -> &'a str| -> Vec<String> {
+        emails.iter().map(|email| field(email).to_owned()).collect()
+    };
+    sqlx::query!(
+        r#"
+    INSERT INTO email_outbox
+      (id, subscriber_id, kind, recipient, subject, html_body, text_body,
+       created_at, next_attempt_at)
+    SELECT id, subscriber_id, kind, recipient, subject, html_body, text_body, $8, $8
+    FROM UNNEST($1::uuid[], $2::uuid[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[])
+      AS emails(id, subscriber_id, kind, recipient, subject, html_body, text_body)
+    "#,
+        &ids[..],
+        &emails
+            .iter()
+            .map(|email| email.subscriber_id)
+            .collect::<Vec<_>>()[..],
+        &column(|email| email.kind.as_str())[..],
+        &column(|email| email.recipient.as_ref())[..],
+        &column(|email| email.subject)[..],
+        &column(|email| email.html_body)[..],
+        &column(|email| email.text_body)[..],
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let confirmations: Vec<_> = emails
+        .iter()
+        .filter(|email| email.kind.is_confirmation())
+        .map(|email| email.subscriber_id)
+        .collect();
+    if !confirmations.is_empty() {
+        count_confirmation_emails(transaction, &confirmations).await?;
+    }
+    Ok(ids)
+}
+
+/// Count a confirmation email to each of these subscribers, sent just now as far
+/// as reminders are concerned. Called once per email, so a repeated id counts twice.
+pub(crate) async fn count_confirmation_emails(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET confirmation_emails_sent = confirmation_emails_sent + counted.emails,
+        last_confirmation_sent_at = now()
+    FROM (
+      SELECT id, COUNT(*)::int AS emails FROM UNNEST($1::uuid[]) AS ids(id) GROUP BY id
+    ) AS counted
+    WHERE subscriptions.id = counted.id
+    "#,
+        subscriber_ids
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Deliver the outbox email with this id right away, instead of waiting for the
+/// dispatcher. Returns whether it was sent; if not, the dispatcher retries it.
+pub async fn deliver_outbox_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    policy: &OutboxPolicy,
+    id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    let sent = deliver_next(pool, email_client, policy, Some(id)).await?;
+    Ok(sent.unwrap_or(false))
+}
+
+/// Check for emails due every `interval`, until the process stops.
+pub async fn run_dispatcher_until_stopped(config: Settings) {
+    let pool = get_db_conn_pool(&config.database);
+    let email_client = config.email_client.client();
+    let policy = config.email_outbox.policy();
+    let interval = config.email_outbox.poll_interval();
+
+    loop {
+        if let Err(e) = dispatch_outbox(&pool, &email_client, &policy).await {
+            tracing::error!("Failed to dispatch the email outbox: {:?}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Try to deliver every outbox email that is due. Returns how many went out.
+#[tracing::instrument(name = "Dispatch the email outbox", skip_all)]
+pub async fn dispatch_outbox(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    policy: &OutboxPolicy,
+) -> Result<u64, anyhow::Error> {
+    let mut sent = 0;
+    let mut attempted = 0;
+    // Failed emails are pushed into the future, so this ends
+    // once everything due has been attempted.
+    while let Some(delivered) = deliver_next(pool, email_client, policy, None).await? {
+        attempted += 1;
+        if delivered {
+            sent += 1;
+        }
+    }
+    if attempted > sent {
+        tracing::warn!(
+            failed = attempted - sent,
+            "Some outbox emails could not be sent"
+        );
+    }
+    Ok(sent)
+}
+
+/// Claim and deliver the next email due, or the one with this id.
+/// Returns whether it was sent, or `None` when there was nothing to deliver.
+async fn deliver_next(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    policy: &OutboxPolicy,
+    id: Option<Uuid>,
+) -> Result<Option<bool>, anyhow::Error> {
+    let Some(email) = claim(pool, policy, id).await? else {
+        return Ok(None);
+    };
+    deliver(pool, email_client, policy, email).await.map(Some)
+}
+
+/// Claim the next email due, or the one with this id if it is due, by counting
+/// the attempt and pushing it `CLAIM_TIMEOUT_SECS` into the future. Other
+/// dispatchers skip it from then on, and no lock is held while it is sent.
+async fn claim(
+    pool: &PgPool,
+    policy: &OutboxPolicy,
+    id: Option<Uuid>,
+) -> Result<Option<ClaimedEmail>, anyhow::Error> {
+    let now = Utc::now();
+    sqlx::query_as!(
+        ClaimedEmail,
+        r#"
+    UPDATE email_outbox
+    SET attempts = attempts + 1, next_attempt_at = $4
+    WHERE id = (
+      SELECT id FROM email_outbox
+      WHERE sent_at IS NULL
+        AND attempts < $1
+        AND next_attempt_at <= $2
+        AND ($3::uuid IS NULL OR id = $3)
+      ORDER BY next_attempt_at
+      LIMIT 1
+      FOR UPDATE SKIP LOCKED
+    )
+    RETURNING
+      id, subscriber_id, kind AS "kind: EmailKind", recipient, subject, html_body, text_body,
+      attempts
+    "#,
+        policy.max_attempts,
+        now,
+        id,
+        now + chrono::Duration::seconds(CLAIM_TIMEOUT_SECS)
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to claim an outbox email to deliver")
+}
+
+/// Send a claimed email, then record how it went.
+async fn deliver(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    policy: &OutboxPolicy,
+    email: ClaimedEmail,
+) -> Result<bool, anyhow::Error> {
+    // Addresses the email API can't take won't get any better with retries.
+    let recipient = match SubscriberEmail::parse(email.recipient) {
+        Ok(recipient) if email_client.can_deliver_to(&recipient) => recipient,
+        Ok(recipient) => {
+            let error = format!("The email API can't deliver to {}", recipient.as_ref());
+            give_up(pool, email.id, &error, policy.max_attempts).await?;
+            return Ok(false);
+        }
+        Err(error) => {
+            give_up(pool, email.id, &error, policy.max_attempts).await?;
+            return Ok(false);
+        }
+    };
+    // Checked at the last moment, the address may have been suppressed since it was queued.
+    if is_suppressed(pool, &recipient)
+        .await
+        .context("Failed to check the suppression list")?
+    {
+        let error = format!("{} is suppressed", recipient.as_ref());
+        give_up(pool, email.id, &error, policy.max_attempts).await?;
+        return Ok(false);
+    }
+
+    if let Err(e) = email_client
+        .send_email(
+            recipient,
+            &email.subject,
+            &email.html_body,
+            &email.text_body,
+        )
+        .await
+    {
+        let error = e.to_string();
+        if email.attempts >= policy.max_attempts {
+            tracing::error!(%error, outbox_email_id = %email.id, "Giving up on an outbox email");
+        } else {
+            tracing::warn!(%error, outbox_email_id = %email.id, "Failed to send an outbox email");
+        }
+        let next_attempt_at = Utc::now() + policy.retry_delay(email.attempts);
+        mark_failed(pool, email.id, &error, next_attempt_at).await?;
+        return Ok(false);
+    }
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    mark_sent(&mut transaction, email.id).await?;
+    if let Some(event) = email.kind.sent_event() {
+        record_subscription_event(
+            &mut transaction,
+            email.subscriber_id,
+            event,
+            EventActor::System,
+        )
+        .await
+        .context("Failed to record a sent outbox email")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the delivery of an outbox email")?;
+    Ok(true)
+}
+
+async fn mark_sent(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE email_outbox SET sent_at = $2 WHERE id = $1",
+        id,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to mark an outbox email as sent")?;
+    Ok(())
+}
+
+async fn mark_failed(
+    pool: &PgPool,
+    id: Uuid,
+    error: &str,
+    next_attempt_at: DateTime<Utc>,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE email_outbox SET last_error = $2, next_attempt_at = $3 WHERE id = $1",
+        id,
+        error,
+        next_attempt_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record a failed outbox email")?;
+    Ok(())
+}
+
+/// Fail an email for good, without spending its remaining attempts.
+async fn give_up(
+    pool: &PgPool,
+    id: Uuid,
+    error: &str,
+    max_attempts: i32,
+) -> Result<(), anyhow::Error> {
+    tracing::error!(%error, outbox_email_id = %id, "Giving up on an outbox email");
+    sqlx::query!(
+        "UPDATE email_outbox SET attempts = $3, last_error = $2 WHERE id = $1",
+        id,
+        error,
+        max_attempts
+    )
+    .execute(pool)
+    .await
+    .context("Failed to record a failed outbox email")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailKind, OutboxPolicy};
+
+    fn policy() -> OutboxPolicy {
+        OutboxPolicy {
+            max_attempts: 10,
+            retry_base_delay: chrono::Duration::seconds(30),
+            retry_max_delay: chrono::Duration::minutes(10),
+        }
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        let policy = policy();
+        assert_eq!(policy.retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(policy.retry_delay(2), chrono::Duration::seconds(60));
+        assert_eq!(policy.retry_delay(3), chrono::Duration::seconds(120));
+    }
+
+    #[test]
+    fn retries_wait_no_longer_than_the_maximum_delay() {
+        let policy = policy();
+        assert_eq!(policy.retry_delay(6), chrono::Duration::minutes(10));
+        assert_eq!(policy.retry_delay(i32::MAX), chrono::Duration::minutes(10));
+    }
+
+    #[test]
+    fn kinds_round_trip_through_their_text_form() {
+        use EmailKind::*;
+
+        for kind in [
+            Confirmation,
+            ConfirmationReminder,
+            Welcome,
+            EmailChangeApproval,
+            EmailChangeConfirmation,
+            EmailChangeNotice,
+        ] {
+            assert_eq!(EmailKind::parse(kind.as_str()), Ok(kind));
+        }
+    }
+
+    #[test]
+    fn only_confirmation_links_are_recorded_as_sent_confirmations() {
+        assert!(EmailKind::ConfirmationReminder.sent_event().is_some());
+        assert!(EmailKind::Welcome.sent_event().is_none());
+        assert!(EmailKind::EmailChangeNotice.sent_event().is_none());
+    }
+}
@@ -0,0 +1,19 @@
+use sqlx::PgExecutor;
+
+use crate::domain::SubscriberEmail;
+
+/// Whether `email` is on the list of addresses we must never email again,
+/// e.g. because it unsubscribed or bounced on the platform it was imported from.
+/// Addresses match whatever their case.
+pub async fn is_suppressed(
+    executor: impl PgExecutor<'_>,
+    email: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    let suppressed = sqlx::query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM email_suppressions WHERE lower(email) = lower($1))",
+        email.as_ref()
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(suppressed.unwrap_or(false))
+}
@@ -1,6 +1,22 @@
+pub mod authentication;
+pub mod background_tasks;
+pub mod bot_protection;
+pub mod captcha;
+pub mod cli;
+pub mod client_info;
 pub mod configuration;
+pub mod confirmation_reminders;
 pub mod domain;
 pub mod email_client;
+pub mod email_normalization;
+pub mod email_outbox;
+pub mod email_suppressions;
+pub mod rate_limiting;
+pub mod retention;
 pub mod routes;
+pub mod signed_tokens;
 pub mod startup;
+pub mod subscriber_import;
 pub mod telemetry;
+pub mod utils;
+pub mod webhooks;
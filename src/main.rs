@@ -1,18 +1,67 @@
+use clap::Parser;
 use mailbolt::{
+    cli::{self, Cli, Command},
     configuration::get_configuration,
+    confirmation_reminders::run_reminders_until_stopped,
+    email_outbox::run_dispatcher_until_stopped,
+    retention::run_retention_until_stopped,
     startup::Application,
     telemetry::{get_subscriber, init_subscriber},
+    webhooks::run_webhooks_until_stopped,
 };
 
 #[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    // Init telemetry subscriber to process tracing spans and logs
-    let subscriber = get_subscriber("mailbolt".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
 
-    let config = get_configuration().expect("Could not read configuration YML files");
-    let app = Application::build(config).await?;
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            // Init telemetry subscriber to process tracing spans and logs
+            let subscriber = get_subscriber("mailbolt".into(), "info".into(), std::io::stdout);
+            init_subscriber(subscriber);
+
+            let config = get_configuration().expect("Could not read configuration YML files");
+            let app = Application::build(config.clone()).await?;
+            tokio::spawn(run_dispatcher_until_stopped(config.clone()));
+            tokio::spawn(run_webhooks_until_stopped(config.clone()));
+            if config.reminders.enabled {
+                tokio::spawn(run_reminders_until_stopped(config.clone()));
+            }
+            if config.retention.enabled {
+                tokio::spawn(run_retention_until_stopped(config));
+            }
+
+            app.run_until_stopped().await?;
+        }
+        Command::Import {
+            path,
+            format,
+            confirmed,
+        } => {
+            // Keep stdout free for the command output
+            let subscriber = get_subscriber("mailbolt".into(), "info".into(), std::io::stderr);
+            init_subscriber(subscriber);
+
+            let config = get_configuration().expect("Could not read configuration YML files");
+            cli::import(config, path, format, confirmed).await?;
+        }
+        Command::PurgePending => {
+            // Keep stdout free for the command output
+            let subscriber = get_subscriber("mailbolt".into(), "info".into(), std::io::stderr);
+            init_subscriber(subscriber);
+
+            let config = get_configuration().expect("Could not read configuration YML files");
+            cli::purge_pending(config).await?;
+        }
+        Command::NormalizeEmails => {
+            // Keep stdout free for the command output
+            let subscriber = get_subscriber("mailbolt".into(), "info".into(), std::io::stderr);
+            init_subscriber(subscriber);
+
+            let config = get_configuration().expect("Could not read configuration YML files");
+            cli::normalize_emails(config).await?;
+        }
+    }
 
-    app.run_until_stopped().await?;
     Ok(())
 }
@@ -0,0 +1,391 @@
+use std::{
+    future::{ready, Ready},
+    hash::Hash,
+    net::IpAddr,
+    num::NonZeroUsize,
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::PayloadError,
+    http::{
+        header::{self, HeaderValue},
+        StatusCode,
+    },
+    web,
+    web::Bytes,
+    HttpMessage,
+};
+use futures_util::{future::LocalBoxFuture, stream, Stream};
+use lru::LruCache;
+
+use crate::{
+    client_info::client_ip,
+    configuration::{EndpointRateLimits, TokenBucketSettings},
+    domain::{EmailPolicy, SubscriberEmail},
+    routes::ProblemDetails,
+    startup::AppState,
+    utils::is_json_content_type,
+};
+
+/// Most keys a limiter tracks. Past it, the least recently seen key is
+/// forgotten, even if its bucket isn't full yet, to bound memory.
+const MAX_TRACKED_KEYS: usize = 50_000;
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Whether the bucket is full again by `now`, i.e. no different from a fresh one.
+    fn refilled_by(&self, settings: &TokenBucketSettings, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens + elapsed / refill_interval(settings) >= settings.capacity as f64
+    }
+
+    fn refill(&mut self, settings: &TokenBucketSettings, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed / refill_interval(settings)).min(settings.capacity as f64);
+        self.updated_at = now;
+    }
+}
+
+fn refill_interval(settings: &TokenBucketSettings) -> f64 {
+    settings.refill_interval_secs.max(1) as f64
+}
+
+/// One token bucket per key, e.g. per client IP, for at most `MAX_TRACKED_KEYS` keys.
+pub struct KeyedRateLimiter<K: Hash + Eq> {
+    settings: TokenBucketSettings,
+    /// Least recently seen first.
+    buckets: Mutex<LruCache<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> KeyedRateLimiter<K> {
+    pub fn new(settings: TokenBucketSettings) -> Self {
+        Self::with_max_keys(settings, MAX_TRACKED_KEYS)
+    }
+
+    fn with_max_keys(settings: TokenBucketSettings, max_keys: usize) -> Self {
+        let max_keys = NonZeroUsize::new(max_keys).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            settings,
+            buckets: Mutex::new(LruCache::new(max_keys)),
+        }
+    }
+
+    /// Take a token from the bucket of `key`.
+    /// When it's empty, return how long until the next token is available.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let settings = &self.settings;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Buckets that refilled completely behave exactly like the fresh one we'd
+        // create instead. We only look at the least recently seen ones, up to the
+        // first that hasn't refilled, so a check stays cheap however many keys there are.
+        while let Some((_, oldest)) = buckets.peek_lru() {
+            if !oldest.refilled_by(settings, now) {
+                break;
+            }
+            buckets.pop_lru();
+        }
+
+        let bucket = buckets.get_or_insert_mut(key, || TokenBucket {
+            tokens: settings.capacity as f64,
+            updated_at: now,
+        });
+        bucket.refill(settings, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(missing * refill_interval(settings)))
+        }
+    }
+}
+
+struct EmailRateLimitState {
+    per_ip: KeyedRateLimiter<IpAddr>,
+    per_email: KeyedRateLimiter<String>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+/// Middleware limiting how often an endpoint that emails the `email` address
+/// found in its body can be called, both per client IP and per target address.
+/// Requests over the limit get a 429 with a `Retry-After` header.
+///
+/// Limits are kept in memory and shared by every worker of the server.
+#[derive(Clone)]
+pub struct EmailRateLimit(Arc<EmailRateLimitState>);
+
+impl EmailRateLimit {
+    pub fn new(limits: &EndpointRateLimits, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self(Arc::new(EmailRateLimitState {
+            per_ip: KeyedRateLimiter::new(limits.per_ip),
+            per_email: KeyedRateLimiter::new(limits.per_email),
+            trusted_proxies,
+        }))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for EmailRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = EmailRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(EmailRateLimitMiddleware {
+            service: Rc::new(service),
+            state: Arc::clone(&self.0),
+        }))
+    }
+}
+
+pub struct EmailRateLimitMiddleware<S> {
+    service: Rc<S>,
+    state: Arc<EmailRateLimitState>,
+}
+
+impl<S, B> Service<ServiceRequest> for EmailRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let state = Arc::clone(&self.state);
+
+        Box::pin(async move {
+            if let Some(ip) = client_ip(req.request(), &state.trusted_proxies) {
+                if let Err(retry_after) = state.per_ip.check(ip) {
+                    tracing::warn!(client_ip = %ip, "Client IP went over its rate limit");
+                    return Ok(too_many_requests(req, retry_after));
+                }
+            }
+
+            // We need the body to find the target address, so we buffer it
+            // and hand a copy back to the request for the handler to consume.
+            let body = req.extract::<Bytes>().await?;
+            let email = match req.app_data::<web::Data<AppState>>() {
+                Some(app_state) => {
+                    email_from_body(req.content_type(), &body, &app_state.email_policy)
+                }
+                None => email_from_body(req.content_type(), &body, &EmailPolicy::default()),
+            };
+            req.set_payload(bytes_payload(body));
+
+            if let Some(email) = email {
+                if let Err(retry_after) = state.per_email.check(email) {
+                    tracing::warn!("Target email address went over its rate limit");
+                    return Ok(too_many_requests(req, retry_after));
+                }
+            }
+
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+fn too_many_requests<B>(
+    req: ServiceRequest,
+    retry_after: Duration,
+) -> ServiceResponse<EitherBody<B>> {
+    let retry_after_secs = (retry_after.as_secs_f64().ceil() as u64).max(1);
+    let mut response = ProblemDetails::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests")
+        .with_detail(format!(
+            "Please wait {} seconds before trying again.",
+            retry_after_secs
+        ))
+        .response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+
+    req.into_response(response).map_into_right_body()
+}
+
+fn bytes_payload(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(stream::once(async { Ok(body) }));
+    Payload::from(stream)
+}
+
+#[derive(serde::Deserialize)]
+struct EmailField {
+    email: String,
+}
+
+/// The mailbox a request is about to email, in the canonical form of `policy`,
+/// so writing its address differently is not a way around the limit.
+/// Invalid addresses are turned down before any email goes out, and have none.
+fn email_from_body(content_type: &str, body: &[u8], policy: &EmailPolicy) -> Option<String> {
+    let field: Option<EmailField> = if is_json_content_type(content_type) {
+        serde_json::from_slice(body).ok()
+    } else {
+        serde_urlencoded::from_bytes(body).ok()
+    };
+
+    let email = SubscriberEmail::parse(field?.email).ok()?;
+    Some(policy.canonical(&email))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+
+    use claims::{assert_err, assert_ok};
+
+    use super::{email_from_body, KeyedRateLimiter};
+    use crate::{configuration::TokenBucketSettings, domain::EmailPolicy};
+
+    fn limiter(capacity: u32, refill_interval_secs: u64) -> KeyedRateLimiter<&'static str> {
+        KeyedRateLimiter::new(TokenBucketSettings {
+            capacity,
+            refill_interval_secs,
+        })
+    }
+
+    #[test]
+    fn a_bucket_allows_a_burst_up_to_its_capacity() {
+        let limiter = limiter(3, 60);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_ok!(limiter.check_at("key", now));
+        }
+        let retry_after = limiter.check_at("key", now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_bucket_refills_over_time() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+
+        assert_ok!(limiter.check_at("key", now));
+        let retry_after = limiter
+            .check_at("key", now + Duration::from_secs(15))
+            .unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(45));
+        assert_ok!(limiter.check_at("key", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn every_key_has_its_own_bucket() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+
+        assert_ok!(limiter.check_at("first", now));
+        assert_err!(limiter.check_at("first", now));
+        assert_ok!(limiter.check_at("second", now));
+    }
+
+    #[test]
+    fn refilled_buckets_are_forgotten() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+
+        assert_ok!(limiter.check_at("first", now));
+        assert_ok!(limiter.check_at("second", now + Duration::from_secs(30)));
+        // Only the first one refilled by then
+        assert_ok!(limiter.check_at("third", now + Duration::from_secs(60)));
+        let tracked: Vec<_> = limiter
+            .buckets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| *key)
+            .collect();
+        assert_eq!(tracked, ["third", "second"]);
+    }
+
+    #[test]
+    fn past_the_maximum_the_least_recently_seen_key_is_forgotten() {
+        let limiter = KeyedRateLimiter::with_max_keys(
+            TokenBucketSettings {
+                capacity: 1,
+                refill_interval_secs: 60,
+            },
+            2,
+        );
+        let now = Instant::now();
+
+        assert_ok!(limiter.check_at("first", now));
+        assert_ok!(limiter.check_at("second", now));
+        assert_err!(limiter.check_at("first", now));
+        assert_ok!(limiter.check_at("third", now));
+
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        // "second" was seen least recently
+        assert_ok!(limiter.check_at("second", now));
+    }
+
+    #[test]
+    fn the_target_email_is_found_in_forms_and_json() {
+        let policy = EmailPolicy::default();
+        assert_eq!(
+            email_from_body(
+                "application/x-www-form-urlencoded",
+                b"name=le%20guin&email=Ursula%40Example.com",
+                &policy
+            ),
+            Some("ursula@example.com".into())
+        );
+        assert_eq!(
+            email_from_body(
+                "application/json",
+                br#"{"name": "le guin", "email": "ursula@example.com"}"#,
+                &policy
+            ),
+            Some("ursula@example.com".into())
+        );
+        assert_eq!(
+            email_from_body("application/json", b"name=le%20guin", &policy),
+            None
+        );
+    }
+
+    #[test]
+    fn aliases_of_a_mailbox_share_its_limit() {
+        let policy = EmailPolicy::new(HashSet::new(), false, ["gmail.com".to_owned()].into());
+        assert_eq!(
+            email_from_body(
+                "application/x-www-form-urlencoded",
+                b"email=Ursula%2Bnews%40gmail.com",
+                &policy
+            ),
+            Some("ursula@gmail.com".into())
+        );
+    }
+}
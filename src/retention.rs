@@ -0,0 +1,123 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::{
+    configuration::Settings,
+    domain::{EventActor, SubscriptionEventKind, SubscriptionStatus},
+    startup::get_db_conn_pool,
+};
+
+/// Purge stale pending subscriptions every `interval`, until the process stops.
+pub async fn run_retention_until_stopped(config: Settings) {
+    let pool = get_db_conn_pool(&config.database);
+    let max_age = config.retention.pending_max_age();
+    let interval = config.retention.interval();
+    let batch_size = config.retention.batch_size;
+
+    loop {
+        if let Err(e) = purge_stale_pending_subscriptions(&pool, max_age, batch_size).await {
+            tracing::error!("Failed to purge stale pending subscriptions: {:?}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Delete subscribers who became pending more than `max_age` ago and never
+/// confirmed, with their confirmation tokens, `batch_size` at a time.
+/// Their history stays, ending with an `erased` event. Returns how many
+/// subscribers were deleted.
+///
+/// Their queued webhook events and deliveries go with them, delivered or
+/// not, and no webhook is sent about the erasure: receivers that missed
+/// their `subscribed` event never hear of them.
+#[tracing::instrument(name = "Purge stale pending subscriptions", skip(pool))]
+pub async fn purge_stale_pending_subscriptions(
+    pool: &PgPool,
+    max_age: chrono::Duration,
+    batch_size: i64,
+) -> Result<u64, anyhow::Error> {
+    let cutoff = Utc::now() - max_age;
+    let batch_size = batch_size.max(1);
+    let (mut purged, mut tokens) = (0, 0);
+    loop {
+        let batch = purge_batch(pool, cutoff, batch_size).await?;
+        purged += batch.subscribers;
+        tokens += batch.tokens;
+        if batch.subscribers < batch_size as u64 {
+            break;
+        }
+    }
+
+    tracing::info!(purged, tokens, "Purged stale pending subscriptions");
+    Ok(purged)
+}
+
+struct PurgedBatch {
+    subscribers: u64,
+    tokens: u64,
+}
+
+async fn purge_batch(
+    pool: &PgPool,
+    cutoff: DateTime<Utc>,
+    batch_size: i64,
+) -> Result<PurgedBatch, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    // Rows a signup or a confirmation is busy with are left for the next run.
+    let stale = sqlx::query_scalar!(
+        r#"
+    SELECT id FROM subscriptions
+    WHERE status = $1 AND pending_since < $2
+    LIMIT $3
+    FOR UPDATE SKIP LOCKED
+    "#,
+        SubscriptionStatus::PendingConfirmation.as_str(),
+        cutoff,
+        batch_size
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to look for stale pending subscriptions")?;
+
+    let tokens = sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)",
+        &stale
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete the tokens of stale pending subscriptions")?
+    .rows_affected();
+    let subscribers = sqlx::query!("DELETE FROM subscriptions WHERE id = ANY($1)", &stale)
+        .execute(&mut transaction)
+        .await
+        .context("Failed to delete stale pending subscriptions")?
+        .rows_affected();
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_events (subscriber_id, kind, actor, occurred_at)
+    SELECT id, $2, $3, $4 FROM UNNEST($1::uuid[]) AS id
+    "#,
+        &stale,
+        SubscriptionEventKind::Erased.as_str(),
+        EventActor::System.as_str(),
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to record the purge in the subscription history")?;
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the purge of stale pending subscriptions")?;
+
+    Ok(PurgedBatch {
+        subscribers,
+        tokens,
+    })
+}
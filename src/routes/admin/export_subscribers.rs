@@ -0,0 +1,130 @@
+use actix_web::{http::header, web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+
+use super::{fetch_subscribers, SubscriberFilters, SubscriberRecord};
+use crate::{authentication::AdminAuth, startup::AppState};
+
+const CSV_HEADER: [&str; 13] = [
+    "id",
+    "email",
+    "name",
+    "status",
+    "subscribed_at",
+    "consent_source",
+    "consent_text_version",
+    "signup_ip",
+    "signup_user_agent",
+    "signed_up_at",
+    "confirmation_ip",
+    "confirmation_user_agent",
+    "confirmed_at",
+];
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ExportParameters {
+    format: Option<ExportFormat>,
+}
+
+/// Export every subscriber matching the same filters as the list endpoint.
+///
+/// Rows are serialized one by one as Postgres hands them over through a
+/// cursor, so the full list is never held in memory, no matter how big it is.
+#[tracing::instrument(name = "Export subscribers", skip(_admin, parameters, state))]
+pub async fn export_subscribers(
+    _admin: AdminAuth,
+    parameters: web::Query<ExportParameters>,
+    filters: web::Query<SubscriberFilters>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let format = parameters.0.format.unwrap_or_default();
+    let filters = filters.into_inner();
+
+    let body = async_stream::try_stream! {
+        if let ExportFormat::Csv = format {
+            yield to_csv_line(CSV_HEADER)?;
+        }
+
+        let mut rows = fetch_subscribers(&state.db_pool, &filters, None, 0);
+        while let Some(row) = rows
+            .try_next()
+            .await
+            .context("Failed to fetch subscribers")?
+        {
+            yield match format {
+                ExportFormat::Csv => to_csv_line(csv_fields(&row))?,
+                ExportFormat::Jsonl => to_json_line(&row)?,
+            };
+        }
+    };
+
+    let (content_type, file_name) = match format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "subscribers.csv"),
+        ExportFormat::Jsonl => ("application/x-ndjson", "subscribers.jsonl"),
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(r#"attachment; filename="{}""#, file_name),
+        ))
+        .streaming(body.inspect_err(|e: &anyhow::Error| {
+            tracing::error!("Subscriber export was interrupted: {:?}", e)
+        }))
+}
+
+fn csv_fields(row: &SubscriberRecord) -> [String; 13] {
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let timestamp = |value: Option<DateTime<Utc>>| {
+        value
+            .map(|timestamp| timestamp.to_rfc3339())
+            .unwrap_or_default()
+    };
+    [
+        row.id.to_string(),
+        row.email.clone(),
+        row.name.clone(),
+        row.status.to_string(),
+        row.subscribed_at.to_rfc3339(),
+        text(&row.consent_source),
+        text(&row.consent_text_version),
+        text(&row.signup_ip),
+        text(&row.signup_user_agent),
+        timestamp(row.signed_up_at),
+        text(&row.confirmation_ip),
+        text(&row.confirmation_user_agent),
+        timestamp(row.confirmed_at),
+    ]
+}
+
+fn to_csv_line<I, T>(fields: I) -> Result<web::Bytes, anyhow::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .write_record(fields)
+        .context("Failed to serialize a CSV row")?;
+    let line = writer
+        .into_inner()
+        .context("Failed to serialize a CSV row")?;
+    Ok(line.into())
+}
+
+fn to_json_line(row: &SubscriberRecord) -> Result<web::Bytes, anyhow::Error> {
+    let mut line = serde_json::to_vec(row).context("Failed to serialize a JSON row")?;
+    line.push(b'\n');
+    Ok(line.into())
+}
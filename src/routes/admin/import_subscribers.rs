@@ -0,0 +1,92 @@
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use anyhow::Context;
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    authentication::AdminAuth,
+    startup::AppState,
+    subscriber_import::{self, send_import_confirmations, ImportError, ImportFormat, ImportStatus},
+};
+
+#[derive(serde::Deserialize)]
+pub struct ImportParameters {
+    /// Layout of the uploaded file, defaults to our own `csv` one.
+    format: Option<ImportFormat>,
+    /// Status of the people who were subscribed according to the file.
+    /// Defaults to `pending_confirmation`, so nobody ends up on the list
+    /// without having confirmed it at some point.
+    status: Option<ImportStatus>,
+}
+
+impl ResponseError for ImportError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ImportError::InvalidFile(_) => StatusCode::BAD_REQUEST,
+            ImportError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Import subscribers from the CSV file sent as the request body,
+/// either in our own layout or as exported by Mailchimp or Substack.
+/// The body is streamed into the importer as it arrives instead of
+/// being buffered in memory first.
+#[tracing::instrument(name = "Import subscribers", skip(_admin, parameters, body, state))]
+pub async fn import_subscribers(
+    _admin: AdminAuth,
+    parameters: web::Query<ImportParameters>,
+    mut body: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ImportError> {
+    let format = parameters.0.format.unwrap_or_default();
+    let status = parameters
+        .0
+        .status
+        .unwrap_or(ImportStatus::PendingConfirmation);
+
+    // The actix payload can't leave the worker thread, so we pipe it
+    // through an in-memory duplex stream the CSV reader can consume.
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    let forward_body = async move {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Failed to read the request body")?;
+            writer
+                .write_all(&chunk)
+                .await
+                .context("Failed to forward the request body to the importer")?;
+        }
+        // Dropping the writer signals the end of the file to the reader.
+        Ok::<_, ImportError>(())
+    };
+
+    let (report, _) = tokio::try_join!(
+        subscriber_import::import_subscribers(
+            &state.db_pool,
+            reader,
+            format,
+            status,
+            &state.email_policy,
+            &state.base_url
+        ),
+        forward_body
+    )?;
+
+    let pending_confirmations = report.pending_confirmations;
+    if pending_confirmations > 0 {
+        // Sending thousands of emails would keep the upload open for hours,
+        // so deliveries carry on in the background once the import is committed.
+        let background_tasks = state.background_tasks.clone();
+        background_tasks.spawn(async move {
+            send_import_confirmations(
+                &state.db_pool,
+                &state.email_client,
+                &state.outbox_policy,
+                pending_confirmations,
+            )
+            .await
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
@@ -0,0 +1,11 @@
+mod export_subscribers;
+mod import_subscribers;
+mod subscriber_history;
+mod subscribers;
+mod webhook_deliveries;
+
+pub use export_subscribers::*;
+pub use import_subscribers::*;
+pub use subscriber_history::*;
+pub use subscribers::*;
+pub use webhook_deliveries::*;
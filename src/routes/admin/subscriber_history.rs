@@ -0,0 +1,75 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    authentication::AdminAuth,
+    domain::{EventActor, SubscriptionEventKind},
+    routes::ProblemDetails,
+    startup::AppState,
+    utils::e500,
+};
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriptionEvent {
+    pub kind: SubscriptionEventKind,
+    pub actor: EventActor,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct SubscriberHistory {
+    subscriber_id: Uuid,
+    events: Vec<SubscriptionEvent>,
+}
+
+/// Everything that happened to a subscriber, oldest first.
+/// Erased subscribers still have a history, without any of their personal data.
+#[tracing::instrument(name = "Get subscriber history", skip(_admin, state))]
+pub async fn subscriber_history(
+    _admin: AdminAuth,
+    subscriber_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscriber_id = subscriber_id.into_inner();
+    let events = fetch_subscriber_history(&state.db_pool, subscriber_id)
+        .await
+        .context("Failed to fetch the subscriber history")
+        .map_err(e500)?;
+
+    if events.is_empty() {
+        return Ok(
+            ProblemDetails::new(StatusCode::NOT_FOUND, "Unknown subscriber")
+                .with_detail(format!("There is no subscriber with id {}.", subscriber_id))
+                .response(),
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(SubscriberHistory {
+        subscriber_id,
+        events,
+    }))
+}
+
+pub async fn fetch_subscriber_history(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Vec<SubscriptionEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionEvent,
+        r#"
+    SELECT
+      kind AS "kind: SubscriptionEventKind",
+      actor AS "actor: EventActor",
+      occurred_at
+    FROM subscription_events
+    WHERE subscriber_id = $1
+    ORDER BY occurred_at, id
+    "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+}
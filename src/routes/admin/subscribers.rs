@@ -0,0 +1,197 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures_util::{stream::BoxStream, TryStreamExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    authentication::AdminAuth, domain::SubscriptionStatus, routes::ProblemDetails,
+    startup::AppState, utils::e500,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Filters shared by every endpoint returning a list of subscribers.
+#[derive(Debug, serde::Deserialize)]
+pub struct SubscriberFilters {
+    pub status: Option<SubscriptionStatus>,
+    /// Case-insensitive substring of the email address.
+    pub email: Option<String>,
+    pub subscribed_after: Option<DateTime<Utc>>,
+    pub subscribed_before: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriberRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: SubscriptionStatus,
+    pub subscribed_at: DateTime<Utc>,
+    // Proof of consent, missing for imported subscribers until they confirm.
+    pub consent_source: Option<String>,
+    pub consent_text_version: Option<String>,
+    pub signup_ip: Option<String>,
+    pub signup_user_agent: Option<String>,
+    pub signed_up_at: Option<DateTime<Utc>>,
+    pub confirmation_ip: Option<String>,
+    pub confirmation_user_agent: Option<String>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Pagination {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl Pagination {
+    /// The page asked for, with the defaults filled in and the size capped.
+    pub fn limit_and_offset(&self) -> (i64, i64) {
+        let limit = self
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let offset = self.offset.unwrap_or(0).max(0);
+        (limit, offset)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SubscriberPage {
+    subscribers: Vec<SubscriberRecord>,
+    limit: i64,
+    offset: i64,
+}
+
+#[tracing::instrument(name = "List subscribers", skip(_admin, pagination, state))]
+pub async fn list_subscribers(
+    _admin: AdminAuth,
+    filters: web::Query<SubscriberFilters>,
+    pagination: web::Query<Pagination>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (limit, offset) = pagination.limit_and_offset();
+
+    let subscribers = fetch_subscribers(&state.db_pool, &filters, Some(limit), offset)
+        .try_collect()
+        .await
+        .context("Failed to fetch subscribers")
+        .map_err(e500)?;
+
+    Ok(HttpResponse::Ok().json(SubscriberPage {
+        subscribers,
+        limit,
+        offset,
+    }))
+}
+
+/// Stream the subscribers matching `filters`, oldest first.
+/// Without a `limit`, every matching subscriber is returned.
+pub fn fetch_subscribers<'a>(
+    pool: &'a PgPool,
+    filters: &'a SubscriberFilters,
+    limit: Option<i64>,
+    offset: i64,
+) -> BoxStream<'a, Result<SubscriberRecord, sqlx::Error>> {
+    let email_pattern = filters
+        .email
+        .as_deref()
+        .map(|email| format!("%{}%", escape_like_pattern(email)));
+
+    // `LIMIT NULL` is the same as not having a limit at all.
+    sqlx::query_as!(
+        SubscriberRecord,
+        r#"
+    SELECT
+      id, email, name, status AS "status: SubscriptionStatus", subscribed_at,
+      c.source AS "consent_source?", c.consent_text_version AS "consent_text_version?",
+      c.signup_ip AS "signup_ip?", c.signup_user_agent AS "signup_user_agent?",
+      c.signed_up_at AS "signed_up_at?",
+      c.confirmation_ip AS "confirmation_ip?",
+      c.confirmation_user_agent AS "confirmation_user_agent?",
+      c.confirmed_at AS "confirmed_at?"
+    FROM subscriptions
+    LEFT JOIN subscription_consents c ON c.subscriber_id = subscriptions.id
+    WHERE ($1::text IS NULL OR status = $1)
+      AND ($2::text IS NULL OR email ILIKE $2)
+      AND ($3::timestamptz IS NULL OR subscribed_at >= $3)
+      AND ($4::timestamptz IS NULL OR subscribed_at < $4)
+    ORDER BY subscribed_at, id
+    LIMIT $5 OFFSET $6
+    "#,
+        filters.status.map(SubscriptionStatus::as_str),
+        email_pattern,
+        filters.subscribed_after,
+        filters.subscribed_before,
+        limit,
+        offset
+    )
+    .fetch(pool)
+}
+
+#[tracing::instrument(name = "Get subscriber", skip(_admin, state))]
+pub async fn get_subscriber(
+    _admin: AdminAuth,
+    subscriber_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscriber_id = subscriber_id.into_inner();
+    let subscriber = fetch_subscriber(&state.db_pool, subscriber_id)
+        .await
+        .context("Failed to fetch the subscriber")
+        .map_err(e500)?;
+
+    Ok(match subscriber {
+        Some(subscriber) => HttpResponse::Ok().json(subscriber),
+        None => ProblemDetails::new(StatusCode::NOT_FOUND, "Unknown subscriber")
+            .with_detail(format!("There is no subscriber with id {}.", subscriber_id))
+            .response(),
+    })
+}
+
+pub async fn fetch_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriberRecord,
+        r#"
+    SELECT
+      id, email, name, status AS "status: SubscriptionStatus", subscribed_at,
+      c.source AS "consent_source?", c.consent_text_version AS "consent_text_version?",
+      c.signup_ip AS "signup_ip?", c.signup_user_agent AS "signup_user_agent?",
+      c.signed_up_at AS "signed_up_at?",
+      c.confirmation_ip AS "confirmation_ip?",
+      c.confirmation_user_agent AS "confirmation_user_agent?",
+      c.confirmed_at AS "confirmed_at?"
+    FROM subscriptions
+    LEFT JOIN subscription_consents c ON c.subscriber_id = subscriptions.id
+    WHERE id = $1
+    "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Make sure `%` and `_` typed by an admin are matched literally by `ILIKE`.
+fn escape_like_pattern(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_like_pattern;
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like_pattern("john_doe%"), "john\\_doe\\%");
+        assert_eq!(escape_like_pattern("back\\slash"), "back\\\\slash");
+        assert_eq!(escape_like_pattern("plain"), "plain");
+    }
+}
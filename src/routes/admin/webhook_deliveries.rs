@@ -0,0 +1,293 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::Pagination;
+use crate::{
+    authentication::AdminAuth,
+    domain::SubscriptionEventKind,
+    routes::ProblemDetails,
+    startup::AppState,
+    utils::e500,
+    webhooks::{deliver_webhook, schedule_redelivery, WebhookPolicy},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Not delivered yet, with attempts left.
+    Pending,
+    Delivered,
+    /// Out of attempts. Only a redelivery sends it again.
+    Failed,
+}
+
+impl DeliveryState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct WebhookDeliveryFilters {
+    pub endpoint: Option<String>,
+    pub state: Option<DeliveryState>,
+    pub subscriber_id: Option<Uuid>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WebhookDeliveryRecord {
+    pub id: Uuid,
+    /// Id of the subscription event, the same for every endpoint it went to.
+    pub event_id: i64,
+    pub event_kind: SubscriptionEventKind,
+    pub subscriber_id: Uuid,
+    pub endpoint: String,
+    pub state: DeliveryState,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WebhookDeliveryAttempt {
+    pub attempted_at: DateTime<Utc>,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct WebhookDeliveryDetail {
+    #[serde(flatten)]
+    delivery: WebhookDeliveryRecord,
+    payload: serde_json::Value,
+    /// Oldest first.
+    attempt_log: Vec<WebhookDeliveryAttempt>,
+}
+
+#[derive(serde::Serialize)]
+struct WebhookDeliveryPage {
+    deliveries: Vec<WebhookDeliveryRecord>,
+    limit: i64,
+    offset: i64,
+}
+
+struct DeliveryRow {
+    id: Uuid,
+    event_id: i64,
+    event_kind: SubscriptionEventKind,
+    subscriber_id: Uuid,
+    endpoint: String,
+    payload: String,
+    attempts: i32,
+    last_status_code: Option<i32>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    next_attempt_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+}
+
+impl DeliveryRow {
+    fn into_record(self, max_attempts: i32) -> WebhookDeliveryRecord {
+        let state = if self.delivered_at.is_some() {
+            DeliveryState::Delivered
+        } else if self.attempts >= max_attempts {
+            DeliveryState::Failed
+        } else {
+            DeliveryState::Pending
+        };
+        WebhookDeliveryRecord {
+            id: self.id,
+            event_id: self.event_id,
+            event_kind: self.event_kind,
+            subscriber_id: self.subscriber_id,
+            endpoint: self.endpoint,
+            state,
+            attempts: self.attempts,
+            last_status_code: self.last_status_code,
+            last_error: self.last_error,
+            created_at: self.created_at,
+            next_attempt_at: (state == DeliveryState::Pending).then_some(self.next_attempt_at),
+            delivered_at: self.delivered_at,
+        }
+    }
+}
+
+/// The webhook delivery log, newest first.
+#[tracing::instrument(name = "List webhook deliveries", skip(_admin, pagination, state))]
+pub async fn list_webhook_deliveries(
+    _admin: AdminAuth,
+    filters: web::Query<WebhookDeliveryFilters>,
+    pagination: web::Query<Pagination>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (limit, offset) = pagination.limit_and_offset();
+    let max_attempts = state.webhook_policy.retries.max_attempts;
+
+    let deliveries = sqlx::query_as!(
+        DeliveryRow,
+        r#"
+    SELECT
+      id, event_id, event_kind AS "event_kind: SubscriptionEventKind", subscriber_id,
+      endpoint, payload, attempts, last_status_code, last_error,
+      created_at, next_attempt_at, delivered_at
+    FROM webhook_deliveries
+    WHERE ($1::text IS NULL OR endpoint = $1)
+      AND ($2::uuid IS NULL OR subscriber_id = $2)
+      AND ($3::text IS NULL
+        OR ($3 = 'delivered' AND delivered_at IS NOT NULL)
+        OR ($3 = 'pending' AND delivered_at IS NULL AND attempts < $4)
+        OR ($3 = 'failed' AND delivered_at IS NULL AND attempts >= $4))
+    ORDER BY created_at DESC, id
+    LIMIT $5 OFFSET $6
+    "#,
+        filters.endpoint,
+        filters.subscriber_id,
+        filters.state.map(DeliveryState::as_str),
+        max_attempts,
+        limit,
+        offset
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .context("Failed to fetch webhook deliveries")
+    .map_err(e500)?
+    .into_iter()
+    .map(|row| row.into_record(max_attempts))
+    .collect();
+
+    Ok(HttpResponse::Ok().json(WebhookDeliveryPage {
+        deliveries,
+        limit,
+        offset,
+    }))
+}
+
+/// A webhook delivery, with its payload and every attempt at it.
+#[tracing::instrument(name = "Get webhook delivery", skip(_admin, state))]
+pub async fn webhook_delivery(
+    _admin: AdminAuth,
+    delivery_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let delivery_id = delivery_id.into_inner();
+    let detail = fetch_delivery_detail(&state.db_pool, &state.webhook_policy, delivery_id)
+        .await
+        .map_err(e500)?;
+
+    Ok(match detail {
+        Some(detail) => HttpResponse::Ok().json(detail),
+        None => unknown_delivery(delivery_id),
+    })
+}
+
+/// Send a delivery again right away, whatever its state, with a fresh set of
+/// attempts. If this one fails too, the dispatcher keeps retrying it.
+#[tracing::instrument(name = "Redeliver webhook", skip(_admin, state))]
+pub async fn redeliver_webhook(
+    _admin: AdminAuth,
+    delivery_id: web::Path<Uuid>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let delivery_id = delivery_id.into_inner();
+    let (pool, policy) = (&state.db_pool, &state.webhook_policy);
+    let endpoint = sqlx::query_scalar!(
+        "SELECT endpoint FROM webhook_deliveries WHERE id = $1",
+        delivery_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to look for the webhook delivery")
+    .map_err(e500)?;
+    let Some(endpoint) = endpoint else {
+        return Ok(unknown_delivery(delivery_id));
+    };
+    if policy.endpoint(&endpoint).is_none() {
+        return Ok(
+            ProblemDetails::new(StatusCode::CONFLICT, "Unknown webhook endpoint")
+                .with_detail(format!(
+                    "The endpoint '{}' is not configured anymore.",
+                    endpoint
+                ))
+                .response(),
+        );
+    }
+
+    schedule_redelivery(pool, delivery_id).await.map_err(e500)?;
+    deliver_webhook(pool, &state.webhook_client, policy, delivery_id)
+        .await
+        .map_err(e500)?;
+
+    let detail = fetch_delivery_detail(pool, policy, delivery_id)
+        .await
+        .map_err(e500)?;
+    Ok(match detail {
+        Some(detail) => HttpResponse::Ok().json(detail),
+        // The subscriber was deleted in the meantime
+        None => unknown_delivery(delivery_id),
+    })
+}
+
+fn unknown_delivery(delivery_id: Uuid) -> HttpResponse {
+    ProblemDetails::new(StatusCode::NOT_FOUND, "Unknown webhook delivery")
+        .with_detail(format!(
+            "There is no webhook delivery with id {}.",
+            delivery_id
+        ))
+        .response()
+}
+
+async fn fetch_delivery_detail(
+    pool: &PgPool,
+    policy: &WebhookPolicy,
+    delivery_id: Uuid,
+) -> Result<Option<WebhookDeliveryDetail>, anyhow::Error> {
+    let row = sqlx::query_as!(
+        DeliveryRow,
+        r#"
+    SELECT
+      id, event_id, event_kind AS "event_kind: SubscriptionEventKind", subscriber_id,
+      endpoint, payload, attempts, last_status_code, last_error,
+      created_at, next_attempt_at, delivered_at
+    FROM webhook_deliveries
+    WHERE id = $1
+    "#,
+        delivery_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to fetch the webhook delivery")?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let attempt_log = sqlx::query_as!(
+        WebhookDeliveryAttempt,
+        r#"
+    SELECT attempted_at, status_code, error
+    FROM webhook_delivery_attempts
+    WHERE delivery_id = $1
+    ORDER BY attempted_at, id
+    "#,
+        delivery_id
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to fetch the webhook delivery attempts")?;
+
+    let payload = serde_json::from_str(&row.payload).context("Invalid webhook payload")?;
+    Ok(Some(WebhookDeliveryDetail {
+        delivery: row.into_record(policy.retries.max_attempts),
+        payload,
+        attempt_log,
+    }))
+}
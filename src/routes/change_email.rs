@@ -0,0 +1,510 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use anyhow::Context;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{
+    email_change_response, generate_subscription_token, ConfirmationPolicy, EmailChangeOutcome,
+    SubscribeError, SubscriptionPayload,
+};
+use crate::{
+    domain::{
+        EventActor, FieldError, SubscriberEmail, SubscriptionEventKind, SubscriptionStatus,
+        ValidationErrors,
+    },
+    email_outbox::{deliver_outbox_email, enqueue_email, EmailKind, OutboxEmail},
+    startup::AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct ChangeEmailData {
+    /// The address the subscription is at now.
+    pub email: String,
+    pub new_email: String,
+}
+
+/// Ask for a subscription to move to another address. The current address
+/// gets a link to approve the move, then the new one a link to confirm it,
+/// and nothing changes until both are followed: knowing an address is not
+/// enough to take its subscription away.
+///
+/// Like `resend_confirmation`, the response doesn't tell whether there is a
+/// subscription at `email`, nor does it wait for the email to go out.
+#[tracing::instrument(name = "Ask for an email change", skip(payload, state))]
+pub async fn request_email_change(
+    payload: SubscriptionPayload<ChangeEmailData>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, SubscribeError> {
+    let data = payload.into_form_data();
+    let email =
+        SubscriberEmail::parse(data.email).map_err(|reason| FieldError::new("email", reason));
+    let new_email = SubscriberEmail::parse(data.new_email)
+        .and_then(|email| state.email_policy.check(&email).map(|_| email))
+        .and_then(|email| {
+            if state.email_client.can_deliver_to(&email) {
+                Ok(email)
+            } else {
+                Err(
+                    "We can't send emails to addresses with non-ASCII characters before the @ yet"
+                        .into(),
+                )
+            }
+        })
+        .map_err(|reason| FieldError::new("new_email", reason));
+    let (email, new_email) = match (email, new_email) {
+        (Ok(email), Ok(new_email)) => (email, new_email),
+        (email, new_email) => {
+            return Err(ValidationErrors(
+                [email.err(), new_email.err()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+            )
+            .into())
+        }
+    };
+    let email_normalized = state.email_policy.canonical(&email);
+    let new_email_normalized = state.email_policy.canonical(&new_email);
+    if email_normalized == new_email_normalized {
+        return Err(ValidationErrors(vec![FieldError::new(
+            "new_email",
+            "This is the address you are subscribed with already",
+        )])
+        .into());
+    }
+
+    let background_tasks = state.background_tasks.clone();
+    background_tasks.spawn(async move {
+        if let Err(e) =
+            start_email_change(&state, &email_normalized, new_email, &new_email_normalized).await
+        {
+            tracing::error!("Failed to start an email change: {:?}", e);
+        }
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn start_email_change(
+    state: &AppState,
+    email_normalized: &str,
+    new_email: SubscriberEmail,
+    new_email_normalized: &str,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = state
+        .db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let subscriber = sqlx::query!(
+        r#"
+    SELECT id, email FROM subscriptions
+    WHERE email_normalized = $1 AND status = $2
+    FOR UPDATE
+    "#,
+        email_normalized,
+        SubscriptionStatus::Confirmed.as_str()
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look for the subscriber changing address")?;
+    let Some(subscriber) = subscriber else {
+        tracing::info!("No confirmed subscriber to change the address of");
+        return Ok(());
+    };
+    let subscriber_id = subscriber.id;
+    let current_email = SubscriberEmail::parse(subscriber.email)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to parse a stored email address")?;
+    if address_taken(&mut transaction, new_email_normalized).await? {
+        tracing::info!("The new address is subscribed already");
+        return Ok(());
+    }
+
+    // Only the latest request counts.
+    sqlx::query!(
+        "DELETE FROM email_change_requests WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete the previous email change requests")?;
+    let approval_token = generate_subscription_token();
+    sqlx::query!(
+        r#"
+    INSERT INTO email_change_requests
+      (token, approval_token, subscriber_id, new_email, new_email_normalized, created_at)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    "#,
+        generate_subscription_token(),
+        approval_token,
+        subscriber_id,
+        new_email.as_ref(),
+        new_email_normalized,
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to store the email change request")?;
+    let (html_body, text_body) =
+        email_change_approval_bodies(&state.base_url, &approval_token, new_email.as_ref());
+    let outbox_email_id = enqueue_email(
+        &mut transaction,
+        OutboxEmail {
+            subscriber_id,
+            kind: EmailKind::EmailChangeApproval,
+            recipient: &current_email,
+            subject: "Approve the move of your subscription",
+            html_body: &html_body,
+            text_body: &text_body,
+        },
+    )
+    .await
+    .context("Failed to queue the email change approval")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the email change request")?;
+
+    deliver_outbox_email(
+        &state.db_pool,
+        &state.email_client,
+        &state.outbox_policy,
+        outbox_email_id,
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct EmailChangeParameters {
+    token: String,
+}
+
+/// The current address agreed to the move: send the new one its link.
+#[tracing::instrument(name = "Approve an email change", skip(req, parameters, state))]
+pub async fn approve_email_change(
+    req: HttpRequest,
+    parameters: web::Query<EmailChangeParameters>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let outcome = match approve(&state, &parameters.token).await {
+        Ok(Ok(outbox_email_id)) => {
+            if let Err(e) = deliver_outbox_email(
+                &state.db_pool,
+                &state.email_client,
+                &state.outbox_policy,
+                outbox_email_id,
+            )
+            .await
+            {
+                tracing::error!("Failed to send the email change confirmation: {:?}", e);
+            }
+            EmailChangeOutcome::Approved
+        }
+        Ok(Err(outcome)) => outcome,
+        Err(e) => {
+            tracing::error!("Failed to approve an email change: {:?}", e);
+            EmailChangeOutcome::Failed
+        }
+    };
+
+    email_change_response(&req, outcome)
+}
+
+/// Returns the outbox email with the link for the new address,
+/// or why the approval was refused.
+async fn approve(
+    state: &AppState,
+    approval_token: &str,
+) -> Result<Result<Uuid, EmailChangeOutcome>, anyhow::Error> {
+    let mut transaction = state
+        .db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let request = sqlx::query!(
+        r#"
+    SELECT token, subscriber_id, new_email, new_email_normalized, created_at, approved_at
+    FROM email_change_requests
+    WHERE approval_token = $1
+    FOR UPDATE
+    "#,
+        approval_token
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look for the email change request")?;
+    let Some(request) = request else {
+        return Ok(Err(EmailChangeOutcome::Invalid));
+    };
+    if request.approved_at.is_some() {
+        return Ok(Err(EmailChangeOutcome::Invalid));
+    }
+    if request.created_at + state.confirmation_policy.token_ttl < Utc::now() {
+        return Ok(Err(EmailChangeOutcome::Expired));
+    }
+    if address_taken(&mut transaction, &request.new_email_normalized).await? {
+        return Ok(Err(EmailChangeOutcome::AddressTaken));
+    }
+    let new_email = SubscriberEmail::parse(request.new_email)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to parse the stored new address")?;
+
+    sqlx::query!(
+        "UPDATE email_change_requests SET approved_at = $1 WHERE approval_token = $2",
+        Utc::now(),
+        approval_token
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to approve the email change request")?;
+    let (html_body, text_body) = email_change_confirmation_bodies(&state.base_url, &request.token);
+    let outbox_email_id = enqueue_email(
+        &mut transaction,
+        OutboxEmail {
+            subscriber_id: request.subscriber_id,
+            kind: EmailKind::EmailChangeConfirmation,
+            recipient: &new_email,
+            subject: "Confirm your new address",
+            html_body: &html_body,
+            text_body: &text_body,
+        },
+    )
+    .await
+    .context("Failed to queue the email change confirmation")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the email change approval")?;
+
+    Ok(Ok(outbox_email_id))
+}
+
+/// Move the subscription to the new address, notify the old one
+/// and record the change in the subscriber history.
+#[tracing::instrument(name = "Confirm an email change", skip(req, parameters, state))]
+pub async fn confirm_email_change(
+    req: HttpRequest,
+    parameters: web::Query<EmailChangeParameters>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let outcome = match change_email(
+        &state.db_pool,
+        &state.confirmation_policy,
+        &parameters.token,
+    )
+    .await
+    {
+        Ok(ChangeEmailResult::Changed { notice }) => {
+            // The change is done, the notice is a courtesy.
+            if let Some(outbox_email_id) = notice {
+                if let Err(e) = deliver_outbox_email(
+                    &state.db_pool,
+                    &state.email_client,
+                    &state.outbox_policy,
+                    outbox_email_id,
+                )
+                .await
+                {
+                    tracing::error!("Failed to notify the previous address: {:?}", e);
+                }
+            }
+            EmailChangeOutcome::Changed
+        }
+        Ok(ChangeEmailResult::Refused(outcome)) => outcome,
+        Err(e) => {
+            tracing::error!("Failed to change a subscriber address: {:?}", e);
+            EmailChangeOutcome::Failed
+        }
+    };
+
+    email_change_response(&req, outcome)
+}
+
+enum ChangeEmailResult {
+    /// With the outbox email telling the previous address, if it can be told.
+    Changed {
+        notice: Option<Uuid>,
+    },
+    Refused(EmailChangeOutcome),
+}
+
+async fn change_email(
+    pool: &PgPool,
+    policy: &ConfirmationPolicy,
+    token: &str,
+) -> Result<ChangeEmailResult, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let request = sqlx::query!(
+        r#"
+    SELECT r.subscriber_id, r.new_email, r.new_email_normalized, r.approved_at AS "approved_at!", s.email
+    FROM email_change_requests r
+    JOIN subscriptions s ON s.id = r.subscriber_id
+    WHERE r.token = $1 AND r.approved_at IS NOT NULL
+    FOR UPDATE
+    "#,
+        token
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look for the email change request")?;
+    let Some(request) = request else {
+        return Ok(ChangeEmailResult::Refused(EmailChangeOutcome::Invalid));
+    };
+    if request.approved_at + policy.token_ttl < Utc::now() {
+        return Ok(ChangeEmailResult::Refused(EmailChangeOutcome::Expired));
+    }
+    if address_taken(&mut transaction, &request.new_email_normalized).await? {
+        return Ok(ChangeEmailResult::Refused(EmailChangeOutcome::AddressTaken));
+    }
+
+    sqlx::query!(
+        "UPDATE subscriptions SET email = $1, email_normalized = $2 WHERE id = $3",
+        request.new_email,
+        request.new_email_normalized,
+        request.subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update the subscriber address")?;
+    sqlx::query!(
+        "DELETE FROM email_change_requests WHERE subscriber_id = $1",
+        request.subscriber_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to delete the email change requests")?;
+    let event_id = sqlx::query_scalar!(
+        r#"
+    INSERT INTO subscription_events (subscriber_id, kind, actor, occurred_at)
+    VALUES ($1, $2, $3, $4)
+    RETURNING id
+    "#,
+        request.subscriber_id,
+        SubscriptionEventKind::EmailChanged.as_str(),
+        EventActor::Subscriber.as_str(),
+        Utc::now()
+    )
+    .fetch_one(&mut transaction)
+    .await
+    .context("Failed to record the email change")?;
+    // Webhook receivers know the subscriber by the address they left. It goes
+    // into the queued webhook only, the history keeps no addresses.
+    sqlx::query!(
+        r#"
+    UPDATE webhook_events
+    SET payload = (payload::jsonb || jsonb_build_object('previous_email', $2::text))::text
+    WHERE event_id = $1
+    "#,
+        event_id,
+        request.email
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to add the previous address to the email change webhook")?;
+    let notice = match SubscriberEmail::parse(request.email) {
+        Ok(old_email) => {
+            let (html_body, text_body) = email_change_notice_bodies(&request.new_email);
+            let id = enqueue_email(
+                &mut transaction,
+                OutboxEmail {
+                    subscriber_id: request.subscriber_id,
+                    kind: EmailKind::EmailChangeNotice,
+                    recipient: &old_email,
+                    subject: "Your subscription moved to another address",
+                    html_body: &html_body,
+                    text_body: &text_body,
+                },
+            )
+            .await
+            .context("Failed to queue the email change notice")?;
+            Some(id)
+        }
+        Err(e) => {
+            tracing::warn!("Not notifying an invalid previous address: {}", e);
+            None
+        }
+    };
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the email change")?;
+
+    Ok(ChangeEmailResult::Changed { notice })
+}
+
+async fn address_taken(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_normalized: &str,
+) -> Result<bool, anyhow::Error> {
+    let taken = sqlx::query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM subscriptions WHERE email_normalized = $1)",
+        email_normalized
+    )
+    .fetch_one(transaction)
+    .await
+    .context("Failed to check whether an address is subscribed")?;
+    Ok(taken.unwrap_or(false))
+}
+
+fn email_change_approval_bodies(
+    base_url: &str,
+    approval_token: &str,
+    new_email: &str,
+) -> (String, String) {
+    let approval_link = format!(
+        "{}/subscriptions/change-email/approve?token={}",
+        base_url, approval_token
+    );
+    let html_body = format!(
+        "Someone asked to move your Mailbolt subscription to {}.<br/>\
+                Click <a href=\"{}\">here</a> to approve, or ignore this email to keep it here.",
+        new_email, approval_link
+    );
+    let plain_body = format!(
+        "Someone asked to move your Mailbolt subscription to {}.\n\
+                Visit {} to approve, or ignore this email to keep it here.",
+        new_email, approval_link
+    );
+    (html_body, plain_body)
+}
+
+fn email_change_confirmation_bodies(base_url: &str, token: &str) -> (String, String) {
+    let confirmation_link = format!(
+        "{}/subscriptions/change-email/confirm?token={}",
+        base_url, token
+    );
+    let html_body = format!(
+        "You asked to move your Mailbolt subscription to this address.<br/>\
+                Click <a href=\"{}\">here</a> to confirm.",
+        confirmation_link
+    );
+    let plain_body = format!(
+        "You asked to move your Mailbolt subscription to this address.\nVisit {} to confirm.",
+        confirmation_link
+    );
+    (html_body, plain_body)
+}
+
+fn email_change_notice_bodies(new_email: &str) -> (String, String) {
+    let html_body = format!(
+        "Your Mailbolt subscription moved to {}, this address won't get any more issues.<br/>\
+                If you didn't ask for this, subscribe again.",
+        new_email
+    );
+    let plain_body = format!(
+        "Your Mailbolt subscription moved to {}, this address won't get any more issues.\n\
+                If you didn't ask for this, subscribe again.",
+        new_email
+    );
+    (html_body, plain_body)
+}
@@ -1,63 +1,324 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
+use super::{
+    confirmation_response, error_chain_fmt, generate_subscription_token, record_subscription_event,
+    store_token, ConfirmationOutcome,
+};
+use crate::{
+    client_info::ClientInfo,
+    domain::{EventActor, InvalidTransition, SubscriptionStatus},
+    signed_tokens::{TokenPurpose, TokenSigner},
+    startup::AppState,
+};
+
 #[derive(Deserialize)]
 pub struct Parameters {
     subscription_token: String,
 }
 
+/// How confirmation links behave, see `ConfirmationSettings`.
+#[derive(Clone, Debug)]
+pub struct ConfirmationPolicy {
+    pub token_ttl: Duration,
+    pub redirect_url: Option<Url>,
+    /// Without double opt-in, people are subscribed as soon as they sign up.
+    pub double_opt_in: bool,
+    /// The kind of token new confirmation links carry.
+    pub token_mode: ConfirmationTokenMode,
+    /// Verifies signed tokens, whichever mode issues new ones,
+    /// so links sent before a switch keep working.
+    pub token_signer: Option<TokenSigner>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmationTokenMode {
+    /// A random token, kept in `subscription_tokens`.
+    Stored,
+    /// A token signed by `TokenSigner`, kept nowhere.
+    Signed,
+}
+
+impl ConfirmationPolicy {
+    /// Issue the token of a new confirmation link, storing it if it has to be.
+    pub async fn issue_token(
+        &self,
+        transaction: &mut Transaction<'_, Postgres>,
+        subscriber_id: Uuid,
+    ) -> Result<String, sqlx::Error> {
+        match (self.token_mode, &self.token_signer) {
+            (ConfirmationTokenMode::Signed, Some(signer)) => {
+                let generation = sqlx::query_scalar!(
+                    "SELECT token_generation FROM subscriptions WHERE id = $1",
+                    subscriber_id
+                )
+                .fetch_one(&mut *transaction)
+                .await?;
+                Ok(signer.sign(
+                    subscriber_id,
+                    generation,
+                    TokenPurpose::ConfirmSubscription,
+                    Utc::now() + self.token_ttl,
+                ))
+            }
+            _ => {
+                let token = generate_subscription_token();
+                store_token(transaction, subscriber_id, &token).await?;
+                Ok(token)
+            }
+        }
+    }
+}
+
 /// using the web::Query<T> extractor, query parameters that are not optional
 /// are automatically populated in the struct T and any request that does not
 /// provide these parameters are faced with a 400 response automatically.
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, db_pool))]
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(req, parameters, state, client)
+)]
 pub async fn confirm(
+    req: HttpRequest,
     parameters: web::Query<Parameters>,
-    db_pool: web::Data<PgPool>,
+    state: web::Data<AppState>,
+    client: ClientInfo,
 ) -> HttpResponse {
-    let maybe_id =
-        match get_subscriber_id_from_confirmation_token(&db_pool, &parameters.0.subscription_token)
+    let db_pool = &state.db_pool;
+    let policy = &state.confirmation_policy;
+    let outcome =
+        match resolve_confirmation_token(db_pool, policy, &parameters.0.subscription_token).await {
+            Err(_) => ConfirmationOutcome::Failed,
+            Ok(None) => ConfirmationOutcome::Invalid,
+            Ok(Some(token)) if token.status == SubscriptionStatus::Confirmed => {
+                ConfirmationOutcome::AlreadyConfirmed
+            }
+            Ok(Some(token)) if token.expires_at < Utc::now() => ConfirmationOutcome::Expired,
+            Ok(Some(token)) => match confirm_subscriber(
+                db_pool,
+                token.subscriber_id,
+                &client,
+                &state.consent_text_version,
+            )
             .await
-        {
-            Ok(id) => id,
-            Err(_) => return HttpResponse::InternalServerError().finish(),
+            {
+                Ok(()) => ConfirmationOutcome::Confirmed,
+                // e.g. someone who unsubscribed following an old confirmation link
+                Err(StatusChangeError::InvalidTransition(e)) => {
+                    tracing::warn!("Refused to confirm subscriber: {}", e);
+                    ConfirmationOutcome::Cancelled
+                }
+                Err(e) => {
+                    tracing::error!("Failed to confirm subscriber: {:?}", e);
+                    ConfirmationOutcome::Failed
+                }
+            },
         };
 
-    match maybe_id {
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&db_pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
+    confirmation_response(&req, outcome, policy)
+}
+
+#[derive(thiserror::Error)]
+pub enum StatusChangeError {
+    #[error(transparent)]
+    InvalidTransition(#[from] InvalidTransition),
+    #[error("There is no subscriber with id {0}")]
+    UnknownSubscriber(Uuid),
+    #[error("Failed to update the subscription status")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl std::fmt::Debug for StatusChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[tracing::instrument(name = "Confirm subscriber", skip(pool, subscriber_id, client))]
+pub async fn confirm_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    client: &ClientInfo,
+    consent_text_version: &str,
+) -> Result<(), StatusChangeError> {
+    let mut transaction = pool.begin().await?;
+    match change_subscription_status(
+        &mut transaction,
+        subscriber_id,
+        SubscriptionStatus::Confirmed,
+        EventActor::Subscriber,
+    )
+    .await
+    {
+        // Following the same link twice is fine, the first time did the job.
+        Err(StatusChangeError::InvalidTransition(InvalidTransition {
+            from: SubscriptionStatus::Confirmed,
+            ..
+        })) => {}
+        result => {
+            result?;
+            store_confirmation_consent(
+                &mut transaction,
+                subscriber_id,
+                client,
+                consent_text_version,
+            )
+            .await?;
         }
     }
+    transaction.commit().await?;
+
+    Ok(())
 }
 
-#[tracing::instrument(name = "Confirm subscriber", skip(pool, subscriber_id))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
+/// Add the confirmation to the consent proof of a subscriber. Imported
+/// subscribers have none yet, the version of the consent text we show now is theirs.
+#[tracing::instrument(name = "Store confirmation consent", skip(transaction, client))]
+pub async fn store_confirmation_consent(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    client: &ClientInfo,
+    consent_text_version: &str,
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        "UPDATE subscriptions SET status = 'confirmed' WHERE id = $1",
-        subscriber_id
+        r#"
+    INSERT INTO subscription_consents
+      (subscriber_id, consent_text_version, confirmation_ip, confirmation_user_agent, confirmed_at)
+    VALUES ($1, $2, $3, $4, $5)
+    ON CONFLICT (subscriber_id) DO UPDATE SET
+      confirmation_ip = EXCLUDED.confirmation_ip,
+      confirmation_user_agent = EXCLUDED.confirmation_user_agent,
+      confirmed_at = EXCLUDED.confirmed_at
+    "#,
+        subscriber_id,
+        consent_text_version,
+        client.ip.map(|ip| ip.to_string()),
+        client.user_agent,
+        Utc::now()
     )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to fetch subscription token: {:?}", e);
-        e
-    })?;
+    .execute(transaction)
+    .await?;
 
     Ok(())
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(pool, token))]
-pub async fn get_subscriber_id_from_confirmation_token(
+/// Move a subscriber to the `to` status, as long as `SubscriptionStatus`
+/// allows it, and record the change in their history.
+/// Returns the status they had before.
+/// The row stays locked until `transaction` ends, so concurrent
+/// changes can't bypass the check.
+pub async fn change_subscription_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    to: SubscriptionStatus,
+    actor: EventActor,
+) -> Result<SubscriptionStatus, StatusChangeError> {
+    let current = sqlx::query!(
+        r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(StatusChangeError::UnknownSubscriber(subscriber_id))?
+    .status;
+
+    current.transition_to(to)?;
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET status = $1,
+        pending_since = CASE WHEN $1 = $3 THEN now() ELSE pending_since END,
+        confirmation_emails_sent = CASE WHEN $1 = $3 THEN 0 ELSE confirmation_emails_sent END
+    WHERE id = $2
+    "#,
+        to.as_str(),
+        subscriber_id,
+        SubscriptionStatus::PendingConfirmation.as_str()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    record_subscription_event(&mut *transaction, subscriber_id, to.into(), actor).await?;
+
+    Ok(current)
+}
+
+pub struct ConfirmationToken {
+    pub subscriber_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// Status of the subscriber the token was issued to.
+    pub status: SubscriptionStatus,
+}
+
+/// A confirmation token we issued, whichever kind it is.
+pub struct ResolvedConfirmationToken {
+    pub subscriber_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    /// Status of the subscriber the token was issued to.
+    pub status: SubscriptionStatus,
+}
+
+/// Find out who a confirmation token was issued to,
+/// or `None` if it's not a token we issued to anyone still around.
+pub async fn resolve_confirmation_token(
     pool: &PgPool,
+    policy: &ConfirmationPolicy,
     token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        "SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1",
+) -> Result<Option<ResolvedConfirmationToken>, sqlx::Error> {
+    if !TokenSigner::is_signed(token) {
+        let token = get_confirmation_token(pool, token).await?;
+        return Ok(token.map(|token| ResolvedConfirmationToken {
+            subscriber_id: token.subscriber_id,
+            expires_at: token.created_at + policy.token_ttl,
+            status: token.status,
+        }));
+    }
+
+    let Some(signer) = &policy.token_signer else {
+        return Ok(None);
+    };
+    let claims = match signer.open(token, TokenPurpose::ConfirmSubscription) {
+        Ok(claims) => claims,
+        Err(e) => {
+            tracing::warn!("Rejected a signed confirmation token: {}", e);
+            return Ok(None);
+        }
+    };
+
+    // Links sent before the latest one were revoked by bumping the generation.
+    let status = sqlx::query_scalar!(
+        r#"
+    SELECT status AS "status: SubscriptionStatus" FROM subscriptions
+    WHERE id = $1 AND token_generation = $2
+    "#,
+        claims.subscriber_id,
+        claims.generation
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(status.map(|status| ResolvedConfirmationToken {
+        subscriber_id: claims.subscriber_id,
+        expires_at: claims.expires_at,
+        status,
+    }))
+}
+
+#[tracing::instrument(name = "Get confirmation token", skip(pool, token))]
+pub async fn get_confirmation_token(
+    pool: &PgPool,
+    token: &str,
+) -> Result<Option<ConfirmationToken>, sqlx::Error> {
+    sqlx::query_as!(
+        ConfirmationToken,
+        r#"
+    SELECT t.subscriber_id, t.created_at, s.status AS "status: SubscriptionStatus"
+    FROM subscription_tokens t
+    JOIN subscriptions s ON s.id = t.subscriber_id
+    WHERE t.subscription_token = $1
+    "#,
         token
     )
     .fetch_optional(pool)
@@ -65,7 +326,5 @@ pub async fn get_subscriber_id_from_confirmation_token(
     .map_err(|e| {
         tracing::error!("Failed to fetch subscription token: {:?}", e);
         e
-    })?;
-
-    Ok(result.map(|r| r.subscriber_id))
+    })
 }
@@ -0,0 +1,184 @@
+use actix_web::{
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    HttpRequest, HttpResponse,
+};
+
+use super::ConfirmationPolicy;
+use crate::utils::accepts_json;
+
+/// What following a confirmation link led to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfirmationOutcome {
+    Confirmed,
+    AlreadyConfirmed,
+    Expired,
+    /// There is no such token, e.g. because the link was mangled.
+    Invalid,
+    /// The subscriber unsubscribed or bounced since they got the link.
+    Cancelled,
+    Failed,
+}
+
+impl ConfirmationOutcome {
+    /// How the outcome is named in the `status` parameter of redirects.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Confirmed => "confirmed",
+            Self::AlreadyConfirmed => "already_confirmed",
+            Self::Expired => "expired",
+            Self::Invalid => "invalid",
+            Self::Cancelled => "cancelled",
+            Self::Failed => "failed",
+        }
+    }
+
+    /// API clients relied on these before there were any pages.
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            Self::Confirmed | Self::AlreadyConfirmed => StatusCode::OK,
+            Self::Expired => StatusCode::GONE,
+            Self::Invalid => StatusCode::UNAUTHORIZED,
+            Self::Cancelled => StatusCode::CONFLICT,
+            Self::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Confirmed => "You're subscribed!",
+            Self::AlreadyConfirmed => "You're already subscribed",
+            Self::Expired => "This link has expired",
+            Self::Invalid => "This link is not valid",
+            Self::Cancelled => "This subscription was cancelled",
+            Self::Failed => "Something went wrong",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::Confirmed => "Thanks for confirming your email address, the next issue is on its way.",
+            Self::AlreadyConfirmed => "Your email address was confirmed already, there is nothing left to do.",
+            Self::Expired => "Confirmation links only work for a limited time. Subscribe again to get a new one.",
+            Self::Invalid => "Make sure you copied the whole link from the email, or subscribe again to get a new one.",
+            Self::Cancelled => "You unsubscribed since we sent this link. Subscribe again if you changed your mind.",
+            Self::Failed => "We could not confirm your subscription, please try again later.",
+        }
+    }
+}
+
+/// Answer a confirmation link the way the client wants it: a bare status code for
+/// API clients, our own site when we have a page there, and our page otherwise.
+pub fn confirmation_response(
+    req: &HttpRequest,
+    outcome: ConfirmationOutcome,
+    policy: &ConfirmationPolicy,
+) -> HttpResponse {
+    if accepts_json(req) {
+        return HttpResponse::build(outcome.status_code()).finish();
+    }
+
+    if let Some(redirect_url) = &policy.redirect_url {
+        let mut location = redirect_url.clone();
+        location
+            .query_pairs_mut()
+            .append_pair("status", outcome.as_str());
+        return HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, location.as_str()))
+            .finish();
+    }
+
+    HttpResponse::build(outcome.status_code())
+        .content_type(ContentType::html())
+        .body(page(outcome.title(), outcome.message()))
+}
+
+/// What following an email change link led to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmailChangeOutcome {
+    /// The current address approved, the new one got its link.
+    Approved,
+    Changed,
+    Expired,
+    /// There is no such token, e.g. because the link was used already.
+    Invalid,
+    /// Someone subscribed with the new address since the change was asked for.
+    AddressTaken,
+    Failed,
+}
+
+impl EmailChangeOutcome {
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            Self::Approved | Self::Changed => StatusCode::OK,
+            Self::Expired => StatusCode::GONE,
+            Self::Invalid => StatusCode::UNAUTHORIZED,
+            Self::AddressTaken => StatusCode::CONFLICT,
+            Self::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Approved => "Check your new inbox",
+            Self::Changed => "Your address was changed",
+            Self::Expired => "This link has expired",
+            Self::Invalid => "This link is not valid",
+            Self::AddressTaken => "This address is subscribed already",
+            Self::Failed => "Something went wrong",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::Approved => "We sent a link to your new address, follow it to finish the move.",
+            Self::Changed => "The next issues will be sent to this address.",
+            Self::Expired => {
+                "Links to change your address only work for a limited time. Ask for a new one."
+            }
+            Self::Invalid => {
+                "Make sure you copied the whole link from the email, or ask for a new one."
+            }
+            Self::AddressTaken => {
+                "This address has a subscription of its own now, so we left yours as it was."
+            }
+            Self::Failed => "We could not change your address, please try again later.",
+        }
+    }
+}
+
+/// Answer an email change link with a bare status code for API clients, and our page otherwise.
+pub fn email_change_response(req: &HttpRequest, outcome: EmailChangeOutcome) -> HttpResponse {
+    if accepts_json(req) {
+        return HttpResponse::build(outcome.status_code()).finish();
+    }
+
+    HttpResponse::build(outcome.status_code())
+        .content_type(ContentType::html())
+        .body(page(outcome.title(), outcome.message()))
+}
+
+fn page(title: &str, message: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>{title} - Mailbolt</title>
+  <style>
+    body {{ font-family: system-ui, sans-serif; max-width: 32rem; margin: 4rem auto; padding: 0 1rem; color: #222; }}
+  </style>
+</head>
+<body>
+  <h1>{title}</h1>
+  <p>{message}</p>
+</body>
+</html>
+"#,
+        title = title,
+        message = message,
+    )
+}
@@ -1,7 +1,17 @@
+mod admin;
+mod change_email;
 mod confirm_subscriptions;
+mod confirmation_pages;
 mod health_check;
+mod problem_details;
+mod resend_confirmation;
 mod subscriptions;
 
+pub use admin::*;
+pub use change_email::*;
 pub use confirm_subscriptions::*;
+pub use confirmation_pages::*;
 pub use health_check::*;
+pub use problem_details::*;
+pub use resend_confirmation::*;
 pub use subscriptions::*;
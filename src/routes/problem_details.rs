@@ -0,0 +1,61 @@
+use actix_web::{http::StatusCode, HttpResponse};
+
+use crate::domain::FieldError;
+
+/// An RFC 7807 "problem details" body, describing why a request failed
+/// in a way clients can process without parsing error messages.
+#[derive(Debug, serde::Serialize)]
+pub struct ProblemDetails {
+    /// We don't document problem types (yet), which RFC 7807
+    /// spells as `about:blank`.
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Every input field that failed validation, following the
+    /// extension member used as an example by RFC 7807 itself.
+    #[serde(rename = "invalid-params", skip_serializing_if = "Vec::is_empty")]
+    pub invalid_params: Vec<FieldError>,
+}
+
+impl ProblemDetails {
+    pub fn new(status: StatusCode, title: impl Into<String>) -> Self {
+        Self {
+            problem_type: "about:blank",
+            title: title.into(),
+            status: status.as_u16(),
+            detail: None,
+            invalid_params: Vec::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with_invalid_params(mut self, invalid_params: Vec<FieldError>) -> Self {
+        self.invalid_params = invalid_params;
+        self
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        HttpResponse::build(status)
+            .content_type("application/problem+json")
+            .json(self)
+    }
+}
+
+/// Turn payloads that can't even be deserialized, e.g. because of
+/// a missing field, into problem details as well.
+pub fn payload_error_handler(
+    err: impl std::fmt::Display + std::fmt::Debug + 'static,
+) -> actix_web::Error {
+    let response = ProblemDetails::new(StatusCode::BAD_REQUEST, "The request body is invalid")
+        .with_detail(err.to_string())
+        .response();
+    actix_web::error::InternalError::from_response(err, response).into()
+}
@@ -0,0 +1,138 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use uuid::Uuid;
+
+use super::{
+    confirmation_email_bodies, ConfirmationPolicy, SubscribeError, SubscriptionPayload,
+    CONFIRMATION_EMAIL_INTRO, CONFIRMATION_EMAIL_SUBJECT,
+};
+use crate::{
+    domain::{FieldError, SubscriberEmail, SubscriptionStatus, ValidationErrors},
+    email_outbox::{deliver_outbox_email, enqueue_email, EmailKind, OutboxEmail},
+    startup::AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct ResendConfirmationData {
+    pub email: String,
+}
+
+/// Send a fresh confirmation link to a pending subscriber, replacing the previous ones.
+///
+/// The response is the same whether or not there is such a subscriber, and it
+/// doesn't wait for the email either: the time it takes would tell them apart.
+#[tracing::instrument(name = "Resend a confirmation email", skip(payload, state))]
+pub async fn resend_confirmation(
+    payload: SubscriptionPayload<ResendConfirmationData>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, SubscribeError> {
+    let email = SubscriberEmail::parse(payload.into_form_data().email)
+        .map_err(|reason| ValidationErrors(vec![FieldError::new("email", reason)]))?;
+    let email_normalized = state.email_policy.canonical(&email);
+
+    let background_tasks = state.background_tasks.clone();
+    background_tasks.spawn(async move {
+        if let Err(e) = resend_to_pending_subscriber(&state, &email_normalized).await {
+            tracing::error!("Failed to resend the confirmation email: {:?}", e);
+        }
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn resend_to_pending_subscriber(
+    state: &AppState,
+    email_normalized: &str,
+) -> Result<(), anyhow::Error> {
+    let mut transaction = state
+        .db_pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let subscriber = sqlx::query!(
+        r#"
+    SELECT id, email FROM subscriptions
+    WHERE email_normalized = $1 AND status = $2
+    FOR UPDATE
+    "#,
+        email_normalized,
+        SubscriptionStatus::PendingConfirmation.as_str()
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .context("Failed to look for a pending subscriber")?;
+    let Some(subscriber) = subscriber else {
+        tracing::info!("No pending subscriber to resend a confirmation email to");
+        return Ok(());
+    };
+
+    // Only the address is needed, names stored under an older name policy
+    // shouldn't keep anyone from getting their link.
+    let recipient = SubscriberEmail::parse(subscriber.email)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to parse a stored email address")?;
+    if !state.email_client.can_deliver_to(&recipient) {
+        return Ok(());
+    }
+
+    let token = replace_tokens(&mut transaction, subscriber.id, &state.confirmation_policy).await?;
+    let (html_body, text_body) =
+        confirmation_email_bodies(CONFIRMATION_EMAIL_INTRO, &state.base_url, &token);
+    let outbox_email_id = enqueue_email(
+        &mut transaction,
+        OutboxEmail {
+            subscriber_id: subscriber.id,
+            kind: EmailKind::Confirmation,
+            recipient: &recipient,
+            subject: CONFIRMATION_EMAIL_SUBJECT,
+            html_body: &html_body,
+            text_body: &text_body,
+        },
+    )
+    .await
+    .context("Failed to queue the confirmation email")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the new subscription token")?;
+
+    deliver_outbox_email(
+        &state.db_pool,
+        &state.email_client,
+        &state.outbox_policy,
+        outbox_email_id,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Invalidate every confirmation link sent to the subscriber so far,
+/// stored or signed, and issue the token of the new one.
+pub async fn replace_tokens(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    subscriber_id: Uuid,
+    confirmation_policy: &ConfirmationPolicy,
+) -> Result<String, anyhow::Error> {
+    sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete the previous subscription tokens")?;
+    sqlx::query!(
+        "UPDATE subscriptions SET token_generation = token_generation + 1 WHERE id = $1",
+        subscriber_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to revoke the previous signed tokens")?;
+
+    let token = confirmation_policy
+        .issue_token(transaction, subscriber_id)
+        .await
+        .context("Failed to store the new subscription token")?;
+
+    Ok(token)
+}
@@ -1,18 +1,36 @@
-use std::{fmt::Debug, write, writeln};
+use std::{fmt::Debug, writeln};
 
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{
+    dev::Payload,
+    http::{header, StatusCode},
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use anyhow::Context;
 use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use sqlx::{PgPool, Postgres, Transaction};
+use serde::de::DeserializeOwned;
+use sqlx::{PgExecutor, Postgres, Transaction};
 use uuid::Uuid;
 
+use super::{
+    change_subscription_status, payload_error_handler, replace_tokens, store_confirmation_consent,
+    ProblemDetails,
+};
 use crate::{
-    domain::{NewSubscriber, SubscriberEmail, SubscriberName},
-    email_client::EmailClient,
-    startup::ApplicationBaseUrl,
+    bot_protection::{spend_form_token, BotSignal},
+    client_info::ClientInfo,
+    domain::{
+        EmailPolicy, EventActor, FieldError, NamePolicy, NewSubscriber, SubscriberEmail,
+        SubscriberName, SubscriptionEventKind, SubscriptionStatus, ValidationErrors,
+    },
+    email_outbox::{deliver_outbox_email, enqueue_email, EmailKind, OutboxEmail},
+    email_suppressions::is_suppressed,
+    startup::AppState,
+    utils::is_json_content_type,
 };
 
-fn error_chain_fmt(
+pub fn error_chain_fmt(
     e: &impl std::error::Error,
     f: &mut std::fmt::Formatter<'_>,
 ) -> std::fmt::Result {
@@ -27,128 +45,412 @@ fn error_chain_fmt(
     Ok(())
 }
 
-pub struct StoreTokenError(sqlx::Error);
-
-impl std::fmt::Display for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "A database error has occurred while storing the subscription token"
-        )
-    }
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("{0}")]
+    ValidationError(#[from] ValidationErrors),
+    #[error("Failed to {0}")]
+    DatabaseError(&'static str, #[source] sqlx::Error),
+    #[error("The CAPTCHA challenge was not solved")]
+    CaptchaFailed,
+    #[error("Failed to verify the CAPTCHA response")]
+    CaptchaError(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
 }
 
-impl Debug for StoreTokenError {
+impl Debug for SubscribeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         error_chain_fmt(self, f)
     }
 }
 
-impl ResponseError for StoreTokenError {}
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) | SubscribeError::CaptchaFailed => {
+                StatusCode::BAD_REQUEST
+            }
+            SubscribeError::DatabaseError(_, _)
+            | SubscribeError::CaptchaError(_)
+            | SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 
-impl std::error::Error for StoreTokenError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(&self.0)
+    fn error_response(&self) -> HttpResponse {
+        let problem = match self {
+            SubscribeError::ValidationError(e) => {
+                ProblemDetails::new(self.status_code(), "Your subscription request is invalid")
+                    .with_invalid_params(e.0.clone())
+            }
+            // Internal details stay in our logs, not in the response.
+            SubscribeError::DatabaseError(_, _) | SubscribeError::UnexpectedError(_) => {
+                ProblemDetails::new(self.status_code(), "Your subscription could not be saved")
+                    .with_detail("Something went wrong on our side, please try again later.")
+            }
+            SubscribeError::CaptchaFailed => {
+                ProblemDetails::new(self.status_code(), "Your subscription request is invalid")
+                    .with_invalid_params(vec![FieldError::new(
+                        "captcha_response",
+                        "The CAPTCHA challenge was not solved.",
+                    )])
+            }
+            SubscribeError::CaptchaError(_) => ProblemDetails::new(
+                self.status_code(),
+                "Your subscription could not be verified",
+            )
+            .with_detail("Something went wrong on our side, please try again later."),
+        };
+        problem.response()
     }
 }
 
-#[
-    tracing::instrument(
-        name = "Adding a new subscriber",
-        skip(form, conn_pool, email_client, base_url),
-        fields(
-            subscriber_email = %form.email,
-            subscriber_name = %form.name
-        )
+#[tracing::instrument(
+    name = "Adding a new subscriber",
+    skip(payload, state, client),
+    fields(
+        subscriber_email = %payload.form_data().email,
+        subscriber_name = %payload.form_data().name
     )
-]
+)]
 pub async fn subscribe(
-    form: web::Form<FormData>,
-    conn_pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-    base_url: web::Data<ApplicationBaseUrl>,
-) -> Result<HttpResponse, actix_web::Error> {
-    let new_subscriber = match form.0.try_into() {
-        Ok(subscriber) => subscriber,
-        Err(_) => return Ok(HttpResponse::BadRequest().finish()),
+    payload: SubscriptionPayload,
+    state: web::Data<AppState>,
+    client: ClientInfo,
+) -> Result<HttpResponse, SubscribeError> {
+    let responds_with_json = matches!(payload, SubscriptionPayload::Json(_));
+    let status = if state.confirmation_policy.double_opt_in {
+        SubscriptionStatus::PendingConfirmation
+    } else {
+        SubscriptionStatus::Confirmed
     };
 
-    let mut transaction = match conn_pool.begin().await {
-        Ok(t) => t,
-        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    let form_data = payload.into_form_data();
+    // Before validation: bots get the same answer as people, not field errors to learn from.
+    let form_token = match state.bot_protection.inspect(
+        form_data.website.as_deref(),
+        form_data.form_token.as_deref(),
+    ) {
+        Ok(form_token) => form_token,
+        Err(signal) => return Ok(drop_automated_signup(signal, responds_with_json, status)),
     };
+    let captcha_response = form_data.captcha_response.clone();
+    let source = form_data.source.clone();
+    let new_subscriber = form_data.validate(&state.email_policy, &state.name_policy)?;
+    if !state.email_client.can_deliver_to(&new_subscriber.email) {
+        return Err(ValidationErrors(vec![FieldError::new(
+            "email",
+            "We can't send emails to addresses with non-ASCII characters before the @ yet",
+        )])
+        .into());
+    }
 
-    match insert_subscriber(&mut transaction, &new_subscriber).await {
-        Ok(subscriber_id) => {
-            let token = generate_subscription_token();
-            store_token(&mut transaction, subscriber_id, &token).await?;
+    let suppressed = is_suppressed(&state.db_pool, &new_subscriber.email)
+        .await
+        .map_err(|e| SubscribeError::DatabaseError("check the suppression list", e))?;
+    if suppressed {
+        // Same answer again, so the list can't be probed through the form.
+        tracing::info!("Dropping a signup for a suppressed address");
+        return Ok(subscribe_response(responds_with_json, status));
+    }
 
-            if transaction.commit().await.is_err() {
-                return Ok(HttpResponse::InternalServerError().finish());
-            }
+    let solved = state
+        .captcha
+        .check(captcha_response.as_deref())
+        .await
+        .map_err(SubscribeError::CaptchaError)?;
+    if !solved {
+        return Err(SubscribeError::CaptchaFailed);
+    }
 
-            match send_confirmation_email(&email_client, new_subscriber, &base_url.0, &token).await
-            {
-                Ok(_) => Ok(HttpResponse::Ok().finish()),
-                Err(_) => Ok(HttpResponse::InternalServerError().finish()),
+    let mut transaction = state.db_pool.begin().await.map_err(|e| {
+        SubscribeError::DatabaseError("acquire a Postgres connection from the pool", e)
+    })?;
+    if let Some(form_token) = &form_token {
+        let spent = spend_form_token(&mut transaction, form_token)
+            .await
+            .map_err(|e| SubscribeError::DatabaseError("spend the form token", e))?;
+        if !spent {
+            let signal = BotSignal::ReplayedFormToken;
+            return Ok(drop_automated_signup(signal, responds_with_json, status));
+        }
+    }
+
+    let email_normalized = state.email_policy.canonical(&new_subscriber.email);
+    let inserted = insert_subscriber(&mut transaction, &new_subscriber, &email_normalized, status)
+        .await
+        .map_err(|e| SubscribeError::DatabaseError("insert the new subscriber", e))?;
+    let (subscriber_id, subscriber_status) = match inserted {
+        Some(subscriber_id) => {
+            record_subscription_event(
+                &mut transaction,
+                subscriber_id,
+                SubscriptionEventKind::Subscribed,
+                EventActor::Subscriber,
+            )
+            .await
+            .map_err(|e| SubscribeError::DatabaseError("record the subscription", e))?;
+            if status == SubscriptionStatus::Confirmed {
+                record_subscription_event(
+                    &mut transaction,
+                    subscriber_id,
+                    SubscriptionEventKind::Confirmed,
+                    EventActor::Subscriber,
+                )
+                .await
+                .map_err(|e| SubscribeError::DatabaseError("record the subscription", e))?;
+            }
+            store_signup_consent(
+                &mut transaction,
+                subscriber_id,
+                &client,
+                source.as_deref(),
+                &state.consent_text_version,
+            )
+            .await
+            .map_err(|e| {
+                SubscribeError::DatabaseError("store the consent of the new subscriber", e)
+            })?;
+            if status == SubscriptionStatus::Confirmed {
+                // Signing up is all the confirmation there is.
+                store_confirmation_consent(
+                    &mut transaction,
+                    subscriber_id,
+                    &client,
+                    &state.consent_text_version,
+                )
+                .await
+                .map_err(|e| {
+                    SubscribeError::DatabaseError("store the consent of the new subscriber", e)
+                })?;
             }
+            (subscriber_id, status)
         }
-        Err(e) => {
-            // Using "{:?}" so we get the output of the Debug trait,
-            // which gives us a better message in this case, including the query.
-            tracing::error!("Could not save subscriber: {:?}", e);
-            Ok(HttpResponse::InternalServerError().finish())
+        None => {
+            let rejoined = rejoin_known_subscriber(
+                &mut transaction,
+                &new_subscriber.email,
+                &email_normalized,
+                &client,
+                source.as_deref(),
+                &state.consent_text_version,
+            )
+            .await?;
+            let Some(subscriber_id) = rejoined else {
+                // The form token stays spent all the same.
+                transaction.commit().await.map_err(|e| {
+                    SubscribeError::DatabaseError("commit the transaction spending the token", e)
+                })?;
+                tracing::info!("The address is subscribed already");
+                return Ok(subscribe_response(responds_with_json, status));
+            };
+            (subscriber_id, SubscriptionStatus::PendingConfirmation)
+        }
+    };
+
+    let (kind, subject, html_body, text_body) =
+        if subscriber_status == SubscriptionStatus::Confirmed {
+            (
+                EmailKind::Welcome,
+                WELCOME_EMAIL_SUBJECT,
+                WELCOME_EMAIL_HTML_BODY.to_owned(),
+                WELCOME_EMAIL_TEXT_BODY.to_owned(),
+            )
+        } else {
+            let token =
+                replace_tokens(&mut transaction, subscriber_id, &state.confirmation_policy).await?;
+            let (html_body, text_body) =
+                confirmation_email_bodies(CONFIRMATION_EMAIL_INTRO, &state.base_url, &token);
+            (
+                EmailKind::Confirmation,
+                CONFIRMATION_EMAIL_SUBJECT,
+                html_body,
+                text_body,
+            )
+        };
+    let outbox_email_id = enqueue_email(
+        &mut transaction,
+        OutboxEmail {
+            subscriber_id,
+            kind,
+            recipient: &new_subscriber.email,
+            subject,
+            html_body: &html_body,
+            text_body: &text_body,
+        },
+    )
+    .await
+    .map_err(|e| SubscribeError::DatabaseError("queue the email to the new subscriber", e))?;
+
+    transaction.commit().await.map_err(|e| {
+        SubscribeError::DatabaseError("commit the transaction storing the new subscriber", e)
+    })?;
+
+    // The email is safe in the outbox: if it can't go out right away,
+    // the dispatcher retries it later. Sending it in the background answers new
+    // signups as fast as the suppressed and confirmed ones, which send nothing.
+    let background_tasks = state.background_tasks.clone();
+    background_tasks.spawn(async move {
+        match deliver_outbox_email(
+            &state.db_pool,
+            &state.email_client,
+            &state.outbox_policy,
+            outbox_email_id,
+        )
+        .await
+        {
+            Ok(true) => {}
+            Ok(false) => tracing::warn!(
+                kind = kind.as_str(),
+                "The email was left for the dispatcher"
+            ),
+            Err(e) => tracing::error!("Failed to deliver the {} email: {:?}", kind.as_str(), e),
+        }
+    });
+
+    Ok(subscribe_response(responds_with_json, subscriber_status))
+}
+
+/// Bots get the same answer as people, we just don't act on their submission.
+fn drop_automated_signup(
+    signal: BotSignal,
+    responds_with_json: bool,
+    status: SubscriptionStatus,
+) -> HttpResponse {
+    tracing::warn!(?signal, "Dropping a signup that looks automated");
+    subscribe_response(responds_with_json, status)
+}
+
+/// Someone signing up with an address we know already: pending subscribers
+/// get a fresh link, and people who left or bounced have to confirm their
+/// address again. Returns `None` for confirmed subscribers, who are all set.
+async fn rejoin_known_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+    email_normalized: &str,
+    client: &ClientInfo,
+    source: Option<&str>,
+    consent_text_version: &str,
+) -> Result<Option<Uuid>, anyhow::Error> {
+    let subscriber = sqlx::query!(
+        r#"
+    SELECT id, status AS "status: SubscriptionStatus" FROM subscriptions
+    WHERE email_normalized = $1 OR email = $2
+    FOR UPDATE
+    "#,
+        email_normalized,
+        email.as_ref()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to look for the known subscriber")?
+    .ok_or_else(|| anyhow::anyhow!("The subscriber the address conflicts with is gone"))?;
+
+    match subscriber.status {
+        SubscriptionStatus::Confirmed => return Ok(None),
+        SubscriptionStatus::PendingConfirmation => {}
+        SubscriptionStatus::Unsubscribed | SubscriptionStatus::Bounced => {
+            change_subscription_status(
+                transaction,
+                subscriber.id,
+                SubscriptionStatus::PendingConfirmation,
+                EventActor::Subscriber,
+            )
+            .await
+            .context("Failed to subscribe a former subscriber again")?;
+            store_signup_consent(
+                transaction,
+                subscriber.id,
+                client,
+                source,
+                consent_text_version,
+            )
+            .await
+            .context("Failed to store the consent of the former subscriber")?;
         }
     }
+
+    Ok(Some(subscriber.id))
 }
 
-#[tracing::instrument(
-    name = "Send a confirmation email to a new subscriber",
-    skip(client, new_subscriber, base_url)
-)]
-pub async fn send_confirmation_email(
-    client: &EmailClient,
-    new_subscriber: NewSubscriber,
+fn subscribe_response(responds_with_json: bool, status: SubscriptionStatus) -> HttpResponse {
+    if responds_with_json {
+        HttpResponse::Ok().json(SubscribeResponse { status })
+    } else {
+        HttpResponse::Ok().finish()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct FormTokenResponse {
+    form_token: String,
+}
+
+/// Hand out the token the signup form must send back with its submission.
+pub async fn subscription_form_token(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(FormTokenResponse {
+            form_token: state.bot_protection.issue_form_token(),
+        })
+}
+
+pub const CONFIRMATION_EMAIL_SUBJECT: &str = "Welcome!";
+pub const CONFIRMATION_EMAIL_INTRO: &str = "Welcome to Mailbolt!";
+
+const WELCOME_EMAIL_SUBJECT: &str = "Welcome!";
+const WELCOME_EMAIL_HTML_BODY: &str = "Welcome to Mailbolt!<br/>\
+                You're subscribed, the next issue will land in your inbox.";
+const WELCOME_EMAIL_TEXT_BODY: &str = "Welcome to Mailbolt!\n\
+                You're subscribed, the next issue will land in your inbox.";
+
+/// The HTML and plain text bodies of an email with a confirmation link,
+/// opening with `intro`.
+pub fn confirmation_email_bodies(
+    intro: &str,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> (String, String) {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token
     );
-    let html_body = &format!(
-        "Welcome to Mailbolt!<br/>\
+    let html_body = format!(
+        "{}<br/>\
                 Click <a href=\"{}\">here</a> to confirm your sub.",
-        confirmation_link
+        intro, confirmation_link
     );
-    let plain_body = &format!(
-        "Welcome to Mailbolt!\nVisit {} to confirm your sub",
-        confirmation_link
-    );
-    client
-        .send_email(new_subscriber.email, "Welcome!", html_body, plain_body)
-        .await
+    let plain_body = format!("{}\nVisit {} to confirm your sub", intro, confirmation_link);
+    (html_body, plain_body)
 }
 
 #[tracing::instrument(
     name = "Inserting new sub details to DB",
-    skip(transaction, subscriber)
+    skip(transaction, subscriber, email_normalized)
 )]
 pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
+    email_normalized: &str,
+    status: SubscriptionStatus,
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+    let inserted = sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-    VALUES ($1, $2, $3, $4, $5)
+    INSERT INTO subscriptions
+      (id, email, email_normalized, name, subscribed_at, status, pending_since)
+    SELECT $1, $2, $3, $4, $5, $6, CASE WHEN $6 = $7 THEN $5::timestamptz END
+    -- Rows not normalized yet may only match on the address itself.
+    WHERE NOT EXISTS (SELECT 1 FROM subscriptions WHERE email = $2)
+    ON CONFLICT (email_normalized) DO NOTHING
     "#,
         subscriber_id,
         subscriber.email.as_ref(),
+        email_normalized,
         subscriber.name.as_ref(),
         Utc::now(),
-        "pending_confirmation"
+        status.as_str(),
+        SubscriptionStatus::PendingConfirmation.as_str()
     )
     .execute(transaction)
     .await
@@ -157,7 +459,7 @@ pub async fn insert_subscriber(
         e
     })?;
 
-    Ok(subscriber_id)
+    Ok((inserted.rows_affected() > 0).then_some(subscriber_id))
 }
 
 #[tracing::instrument(
@@ -168,7 +470,7 @@ pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
     subscription_token: &str,
-) -> Result<(), StoreTokenError> {
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
         r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)"#,
         subscription_token,
@@ -178,13 +480,86 @@ pub async fn store_token(
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
-        StoreTokenError(e)
+        e
+    })?;
+
+    Ok(())
+}
+
+/// Keep what proves the new subscriber opted in: where they did it from,
+/// the consent text they were shown and the client they used.
+/// Signing up again replaces it, confirmation included.
+#[tracing::instrument(name = "Store signup consent", skip(transaction, client))]
+pub async fn store_signup_consent(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    client: &ClientInfo,
+    source: Option<&str>,
+    consent_text_version: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_consents
+      (subscriber_id, source, consent_text_version, signup_ip, signup_user_agent, signed_up_at)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    ON CONFLICT (subscriber_id) DO UPDATE SET
+      source = EXCLUDED.source,
+      consent_text_version = EXCLUDED.consent_text_version,
+      signup_ip = EXCLUDED.signup_ip,
+      signup_user_agent = EXCLUDED.signup_user_agent,
+      signed_up_at = EXCLUDED.signed_up_at,
+      confirmation_ip = NULL,
+      confirmation_user_agent = NULL,
+      confirmed_at = NULL
+    "#,
+        subscriber_id,
+        source,
+        consent_text_version,
+        client.ip.map(|ip| ip.to_string()),
+        client.user_agent,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to store signup consent: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+/// Append an event to the history of a subscriber.
+/// A trigger queues the events webhooks care about, with a snapshot of the
+/// subscriber as they are now.
+#[tracing::instrument(name = "Record subscription event", skip(executor))]
+pub async fn record_subscription_event(
+    executor: impl PgExecutor<'_>,
+    subscriber_id: Uuid,
+    kind: SubscriptionEventKind,
+    actor: EventActor,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_events (subscriber_id, kind, actor, occurred_at)
+    VALUES ($1, $2, $3, $4)
+    "#,
+        subscriber_id,
+        kind.as_str(),
+        actor.as_str(),
+        Utc::now()
+    )
+    .execute(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to record subscription event: {:?}", e);
+        e
     })?;
 
     Ok(())
 }
 
-fn generate_subscription_token() -> String {
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
@@ -192,18 +567,131 @@ fn generate_subscription_token() -> String {
         .collect()
 }
 
-#[derive(serde::Deserialize)]
+const MAX_SOURCE_LENGTH: usize = 100;
+
+#[derive(Default, serde::Deserialize)]
 pub struct FormData {
     pub name: String,
     pub email: String,
+    /// Honeypot field, which the form hides from people.
+    #[serde(default)]
+    pub website: Option<String>,
+    /// Token from `GET /subscriptions/form_token`.
+    #[serde(default)]
+    pub form_token: Option<String>,
+    /// What the CAPTCHA widget handed the form, under our name or the widget's own.
+    #[serde(default, alias = "h-captcha-response", alias = "cf-turnstile-response")]
+    pub captcha_response: Option<String>,
+    /// Set when people insist on an address we suggested a correction for.
+    #[serde(default)]
+    pub ignore_email_suggestion: bool,
+    /// Identifies the form people subscribed from, kept with their consent.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// The body of a request to the `/subscriptions` endpoints, which browsers send
+/// as a url-encoded form and our apps as JSON. We pick the extractor from the content
+/// type, so clients get the deserialization error of the format they actually used.
+pub enum SubscriptionPayload<T = FormData> {
+    Form(T),
+    Json(T),
+}
+
+impl<T> SubscriptionPayload<T> {
+    pub fn form_data(&self) -> &T {
+        match self {
+            SubscriptionPayload::Form(data) | SubscriptionPayload::Json(data) => data,
+        }
+    }
+
+    pub fn into_form_data(self) -> T {
+        match self {
+            SubscriptionPayload::Form(data) | SubscriptionPayload::Json(data) => data,
+        }
+    }
+}
+
+impl<T> FromRequest for SubscriptionPayload<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if is_json_content_type(req.content_type()) {
+            let json = web::Json::<serde_json::Value>::from_request(req, payload);
+            Box::pin(async move {
+                // Serde would fill the fields from an array in order, honeypot included.
+                let value = json.await?.into_inner();
+                if !value.is_object() {
+                    return Err(payload_error_handler("Expected a JSON object"));
+                }
+                let data = serde_json::from_value(value).map_err(payload_error_handler)?;
+                Ok(SubscriptionPayload::Json(data))
+            })
+        } else {
+            let form = web::Form::<T>::from_request(req, payload);
+            Box::pin(async move { Ok(SubscriptionPayload::Form(form.await?.into_inner())) })
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SubscribeResponse {
+    status: SubscriptionStatus,
 }
 
 impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+    type Error = ValidationErrors;
 
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { email, name })
+        value.validate(&EmailPolicy::default(), &NamePolicy::default())
+    }
+}
+
+impl FormData {
+    /// Validate every field, so clients learn about all
+    /// the problems of their input at once.
+    pub fn validate(
+        self,
+        email_policy: &EmailPolicy,
+        name_policy: &NamePolicy,
+    ) -> Result<NewSubscriber, ValidationErrors> {
+        let ignore_email_suggestion = self.ignore_email_suggestion;
+        let email = SubscriberEmail::parse(self.email)
+            .and_then(|email| email_policy.check(&email).map(|_| email))
+            .map_err(|reason| FieldError::new("email", reason))
+            .and_then(|email| match email_policy.suggest(&email) {
+                Some(suggestion) if !ignore_email_suggestion => Err(FieldError::new(
+                    "email",
+                    format!("Did you mean {}?", suggestion),
+                )
+                .with_suggestion(suggestion)),
+                _ => Ok(email),
+            });
+        let name = SubscriberName::parse_with(self.name, name_policy)
+            .map_err(|reason| FieldError::new("name", reason));
+        let source = match self.source {
+            Some(source) if source.chars().count() > MAX_SOURCE_LENGTH => Err(FieldError::new(
+                "source",
+                format!(
+                    "The source cannot be longer than {} characters",
+                    MAX_SOURCE_LENGTH
+                ),
+            )),
+            _ => Ok(()),
+        };
+
+        match (name, email, source) {
+            (Ok(name), Ok(email), Ok(())) => Ok(NewSubscriber { email, name }),
+            (name, email, source) => Err(ValidationErrors(
+                [name.err(), email.err(), source.err()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+            )),
+        }
     }
 }
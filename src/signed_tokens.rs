@@ -0,0 +1,315 @@
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shorter secrets are within reach of brute force.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// What a signed token lets its bearer do. Part of the signature,
+/// so a token issued for one purpose is worthless for any other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenPurpose {
+    ConfirmSubscription,
+}
+
+impl TokenPurpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ConfirmSubscription => "confirm_subscription",
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct SigningKey {
+    /// Named in every token signed with the key, so it can be found again.
+    pub id: String,
+    pub secret: Secret<String>,
+}
+
+/// What a token we signed says.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenClaims {
+    pub subscriber_id: Uuid,
+    /// The subscriber's token generation when it was issued, see `sign`.
+    pub generation: i32,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SignedTokenError {
+    #[error("The token is malformed")]
+    Malformed,
+    #[error("The token was signed with a key we don't have")]
+    UnknownKey,
+    #[error("The token signature does not match")]
+    InvalidSignature,
+    #[error("The token was issued for another purpose")]
+    WrongPurpose,
+}
+
+/// Issues and verifies tokens that carry a subscriber id, a generation, a
+/// purpose and an expiry, with an HMAC signature vouching for them, so
+/// checking one takes no token lookup. Bumping the generation the holder
+/// compares them against revokes every token issued before.
+///
+/// The first key signs new tokens and every key verifies them: to rotate
+/// keys, put the new one first and drop the old one once its tokens expired.
+/// A token reads `<key id>.<subscriber id>.<generation>.<expiry>.<purpose>.<signature>`.
+#[derive(Clone, Debug)]
+pub struct TokenSigner {
+    keys: Vec<SigningKey>,
+}
+
+impl TokenSigner {
+    pub fn new(keys: Vec<SigningKey>) -> Result<Self, String> {
+        if keys.is_empty() {
+            return Err("At least one signing key is needed".into());
+        }
+        if let Some(key) = keys
+            .iter()
+            .find(|key| key.id.is_empty() || key.id.contains('.'))
+        {
+            return Err(format!(
+                "'{}' is not a valid signing key id, it must be non-empty and without dots",
+                key.id
+            ));
+        }
+        if let Some(key) = keys
+            .iter()
+            .find(|key| key.secret.expose_secret().len() < MIN_SECRET_LEN)
+        {
+            return Err(format!(
+                "The secret of signing key '{}' is too short, it needs at least {} bytes",
+                key.id, MIN_SECRET_LEN
+            ));
+        }
+        Ok(Self { keys })
+    }
+
+    /// Whether a token is one of ours rather than a random stored one,
+    /// which never contain dots.
+    pub fn is_signed(token: &str) -> bool {
+        token.contains('.')
+    }
+
+    /// `generation` is whatever the caller checks the token against when it
+    /// comes back, e.g. a counter bumped every time a new link is sent.
+    pub fn sign(
+        &self,
+        subscriber_id: Uuid,
+        generation: i32,
+        purpose: TokenPurpose,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        let key = &self.keys[0];
+        let payload = format!(
+            "{}.{}.{}.{}.{}",
+            key.id,
+            subscriber_id.simple(),
+            generation,
+            expires_at.timestamp(),
+            purpose.as_str()
+        );
+        let signature = mac(key, &payload).finalize().into_bytes();
+        format!("{}.{}", payload, hex::encode(signature))
+    }
+
+    /// What a token that is ours, for `purpose`, says, leaving its expiry
+    /// and generation to the caller.
+    pub fn open(
+        &self,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> Result<TokenClaims, SignedTokenError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(SignedTokenError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| SignedTokenError::Malformed)?;
+        let [key_id, subscriber_id, generation, expires_at, token_purpose]: [&str; 5] = payload
+            .split('.')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| SignedTokenError::Malformed)?;
+
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == key_id)
+            .ok_or(SignedTokenError::UnknownKey)?;
+        mac(key, payload)
+            .verify_slice(&signature)
+            .map_err(|_| SignedTokenError::InvalidSignature)?;
+
+        if token_purpose != purpose.as_str() {
+            return Err(SignedTokenError::WrongPurpose);
+        }
+        let expires_at = expires_at
+            .parse()
+            .ok()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+            .ok_or(SignedTokenError::Malformed)?;
+        let subscriber_id =
+            Uuid::parse_str(subscriber_id).map_err(|_| SignedTokenError::Malformed)?;
+        let generation = generation
+            .parse()
+            .map_err(|_| SignedTokenError::Malformed)?;
+        Ok(TokenClaims {
+            subscriber_id,
+            generation,
+            expires_at,
+        })
+    }
+}
+
+fn mac(key: &SigningKey, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key.secret.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    use super::{SignedTokenError, SigningKey, TokenClaims, TokenPurpose, TokenSigner};
+
+    const SECRET: &str = "a-secret-of-at-least-thirty-two-bytes";
+    const OTHER_SECRET: &str = "another-secret-of-thirty-two-bytes";
+
+    fn key(id: &str, secret: &str) -> SigningKey {
+        SigningKey {
+            id: id.into(),
+            secret: Secret::new(secret.into()),
+        }
+    }
+
+    fn signer_with(keys: &[(&str, &str)]) -> TokenSigner {
+        TokenSigner::new(keys.iter().map(|(id, secret)| key(id, secret)).collect()).unwrap()
+    }
+
+    fn subscriber_of(
+        claims: Result<TokenClaims, SignedTokenError>,
+    ) -> Result<Uuid, SignedTokenError> {
+        claims.map(|claims| claims.subscriber_id)
+    }
+
+    #[test]
+    fn tokens_say_who_they_were_issued_to_until_when() {
+        let signer = signer_with(&[("k1", SECRET)]);
+        let subscriber_id = Uuid::new_v4();
+        let expires_at = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let token = signer.sign(
+            subscriber_id,
+            3,
+            TokenPurpose::ConfirmSubscription,
+            expires_at,
+        );
+
+        assert!(TokenSigner::is_signed(&token));
+        assert_eq!(
+            signer.open(&token, TokenPurpose::ConfirmSubscription),
+            Ok(TokenClaims {
+                subscriber_id,
+                generation: 3,
+                expires_at,
+            })
+        );
+    }
+
+    #[test]
+    fn tokens_signed_with_a_previous_key_still_verify_after_a_rotation() {
+        let old = signer_with(&[("k1", SECRET)]);
+        let rotated = signer_with(&[("k2", OTHER_SECRET), ("k1", SECRET)]);
+        let retired = signer_with(&[("k2", OTHER_SECRET)]);
+        let subscriber_id = Uuid::new_v4();
+        let expires_at = Utc::now() + Duration::hours(1);
+        let old_token = old.sign(
+            subscriber_id,
+            0,
+            TokenPurpose::ConfirmSubscription,
+            expires_at,
+        );
+        let new_token = rotated.sign(
+            subscriber_id,
+            0,
+            TokenPurpose::ConfirmSubscription,
+            expires_at,
+        );
+
+        assert!(new_token.starts_with("k2."));
+        for token in [&old_token, &new_token] {
+            assert_eq!(
+                subscriber_of(rotated.open(token, TokenPurpose::ConfirmSubscription)),
+                Ok(subscriber_id)
+            );
+        }
+        assert_eq!(
+            retired.open(&old_token, TokenPurpose::ConfirmSubscription),
+            Err(SignedTokenError::UnknownKey)
+        );
+    }
+
+    #[test]
+    fn tampered_or_malformed_tokens_are_rejected() {
+        let signer = signer_with(&[("k1", SECRET)]);
+        let token = signer.sign(
+            Uuid::new_v4(),
+            0,
+            TokenPurpose::ConfirmSubscription,
+            Utc::now() + Duration::hours(1),
+        );
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let with_claim = |index: usize, value: &str| {
+            let mut claims: Vec<_> = payload.split('.').collect();
+            claims[index] = value;
+            format!("{}.{}", claims.join("."), signature)
+        };
+        let other_subscriber = with_claim(1, &Uuid::new_v4().simple().to_string());
+        let next_generation = with_claim(2, "1");
+        let forged_by_another_key = signer_with(&[("k1", OTHER_SECRET)]).sign(
+            Uuid::new_v4(),
+            0,
+            TokenPurpose::ConfirmSubscription,
+            Utc::now() + Duration::hours(1),
+        );
+
+        for token in [
+            other_subscriber.as_str(),
+            next_generation.as_str(),
+            forged_by_another_key.as_str(),
+        ] {
+            assert_eq!(
+                signer.open(token, TokenPurpose::ConfirmSubscription),
+                Err(SignedTokenError::InvalidSignature)
+            );
+        }
+        for token in ["", "k1", "k1.a.b.c.d.zz", "k1.a.b.c.d.e.f"] {
+            assert_err!(signer.open(token, TokenPurpose::ConfirmSubscription));
+        }
+    }
+
+    #[test]
+    fn key_ids_cannot_be_empty_or_contain_dots() {
+        assert_err!(TokenSigner::new(vec![]));
+        assert_err!(TokenSigner::new(vec![key("", SECRET)]));
+        assert_err!(TokenSigner::new(vec![key("2023.08", SECRET)]));
+        assert_ok!(TokenSigner::new(vec![key("2023-08", SECRET)]));
+    }
+
+    #[test]
+    fn secrets_need_at_least_32_bytes() {
+        assert_err!(TokenSigner::new(vec![key("2023-08", "secret")]));
+        assert_err!(TokenSigner::new(vec![
+            key("2023-09", SECRET),
+            key("2023-08", &"s".repeat(31))
+        ]));
+        assert_ok!(TokenSigner::new(vec![key("2023-08", &"s".repeat(32))]));
+    }
+}
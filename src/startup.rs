@@ -5,47 +5,101 @@ use sqlx::PgPool;
 use std::net::TcpListener;
 use tracing_actix_web::TracingLogger;
 
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::authentication::AdminApiToken;
+use crate::background_tasks::BackgroundTasks;
+use crate::bot_protection::BotProtection;
+use crate::captcha::SignupCaptcha;
+use crate::client_info::TrustedProxies;
+use crate::configuration::{DatabaseSettings, RateLimitSettings, Settings};
+use crate::domain::{EmailPolicy, NamePolicy};
 use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, subscribe};
+use crate::email_outbox::OutboxPolicy;
+use crate::rate_limiting::EmailRateLimit;
+use crate::routes::{
+    approve_email_change, confirm, confirm_email_change, export_subscribers, get_subscriber,
+    health_check, import_subscribers, list_subscribers, list_webhook_deliveries,
+    payload_error_handler, redeliver_webhook, request_email_change, resend_confirmation, subscribe,
+    subscriber_history, subscription_form_token, webhook_delivery, ConfirmationPolicy,
+};
+use crate::webhooks::{WebhookClient, WebhookPolicy};
 
 pub struct Application {
     port: u16,
     server: Server,
+    background_tasks: BackgroundTasks,
 }
 
-pub struct ApplicationBaseUrl(pub String);
+/// Everything request handlers share, built once by `Application::build`
+/// and handed to them as `web::Data<AppState>`.
+pub struct AppState {
+    pub db_pool: PgPool,
+    pub email_client: EmailClient,
+    pub base_url: String,
+    pub admin_api_token: AdminApiToken,
+    pub trusted_proxies: TrustedProxies,
+    pub bot_protection: BotProtection,
+    pub captcha: SignupCaptcha,
+    pub email_policy: EmailPolicy,
+    pub name_policy: NamePolicy,
+    /// Version of the consent text signup forms show, see `ConsentSettings`.
+    pub consent_text_version: String,
+    pub confirmation_policy: ConfirmationPolicy,
+    pub outbox_policy: OutboxPolicy,
+    pub webhook_client: WebhookClient,
+    pub webhook_policy: WebhookPolicy,
+    pub background_tasks: BackgroundTasks,
+}
+
+impl AppState {
+    pub fn build(config: Settings) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            db_pool: get_db_conn_pool(&config.database),
+            email_client: config.email_client.client(),
+            base_url: config.application.base_url,
+            admin_api_token: AdminApiToken(config.admin.api_token),
+            trusted_proxies: TrustedProxies(config.rate_limit.trusted_proxies),
+            bot_protection: BotProtection::new(config.bot_protection),
+            captcha: config.captcha.verifier(),
+            email_policy: config.email_policy.policy()?,
+            name_policy: config.name_policy.policy(),
+            consent_text_version: config.consent.text_version,
+            confirmation_policy: config.confirmation.policy()?,
+            outbox_policy: config.email_outbox.policy(),
+            webhook_client: config.webhooks.client(),
+            webhook_policy: config.webhooks.policy()?,
+            background_tasks: BackgroundTasks::default(),
+        })
+    }
+}
 
 impl Application {
     pub async fn build(config: Settings) -> Result<Self, std::io::Error> {
-        let conn_pool = get_db_conn_pool(&config.database);
-
-        let sender_email = config.email_client.sender().expect("Invalid sender email");
-        let email_client = EmailClient::new(
-            config.email_client.base_url,
-            sender_email,
-            config.email_client.auth_token,
-        );
-
         let address = format!("{}:{}", config.application.host, config.application.port);
 
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
 
-        let server = run(
-            listener,
-            conn_pool,
-            email_client,
-            config.application.base_url,
-        )?;
+        let rate_limit = config.rate_limit.clone();
+        let state = AppState::build(config)?;
+        let background_tasks = state.background_tasks.clone();
+        let server = run(listener, state, rate_limit)?;
 
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            background_tasks,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// What request handlers left running in the background.
+    pub fn background_tasks(&self) -> BackgroundTasks {
+        self.background_tasks.clone()
+    }
+
     // Make it clear that this function only returns when the app server shutsdown
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
         tracing::info!("Server started on port {}", &self.port);
@@ -57,26 +111,87 @@ impl Application {
 /// on the given listener configuration.
 pub fn run(
     listener: TcpListener,
-    pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    state: AppState,
+    rate_limit: RateLimitSettings,
 ) -> Result<Server, std::io::Error> {
-    // Wrap the DB connection in web::Data which wraps this instance
-    // in an Arc reference that can be cloned across threads for the
-    // Actix web workers that will be spun up after the `run` call.
-    let conn_pool = web::Data::new(pool);
-    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
-    let email_client = web::Data::new(email_client);
+    // Wrap the state in web::Data, an Arc reference that can be cloned
+    // across threads for the Actix web workers that will be spun up
+    // after the `run` call.
+    let state = web::Data::new(state);
+    // Built outside of the factory so every worker shares the same buckets.
+    let signup_rate_limit =
+        EmailRateLimit::new(&rate_limit.signup, rate_limit.trusted_proxies.clone());
+    let resend_confirmation_rate_limit = EmailRateLimit::new(
+        &rate_limit.resend_confirmation,
+        rate_limit.trusted_proxies.clone(),
+    );
+    let change_email_rate_limit =
+        EmailRateLimit::new(&rate_limit.change_email, rate_limit.trusted_proxies.clone());
 
     let server = HttpServer::new(move || {
         App::new()
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(signup_rate_limit.clone())
+                    .route(web::post().to(subscribe)),
+            )
+            .service(
+                web::resource("/subscriptions/resend-confirmation")
+                    .wrap(resend_confirmation_rate_limit.clone())
+                    .route(web::post().to(resend_confirmation)),
+            )
+            .service(
+                web::resource("/subscriptions/change-email")
+                    .wrap(change_email_rate_limit.clone())
+                    .route(web::post().to(request_email_change)),
+            )
+            .route(
+                "/subscriptions/change-email/approve",
+                web::get().to(approve_email_change),
+            )
+            .route(
+                "/subscriptions/change-email/confirm",
+                web::get().to(confirm_email_change),
+            )
+            .route(
+                "/subscriptions/form_token",
+                web::get().to(subscription_form_token),
+            )
             .route("/subscriptions/confirm", web::get().to(confirm))
-            .app_data(conn_pool.clone())
-            .app_data(email_client.clone())
-            .app_data(base_url.clone())
+            .route("/admin/subscribers", web::get().to(list_subscribers))
+            .route(
+                "/admin/subscribers/export",
+                web::get().to(export_subscribers),
+            )
+            .route(
+                "/admin/subscribers/import",
+                web::post().to(import_subscribers),
+            )
+            .route(
+                "/admin/subscribers/{subscriber_id}",
+                web::get().to(get_subscriber),
+            )
+            .route(
+                "/admin/subscribers/{subscriber_id}/history",
+                web::get().to(subscriber_history),
+            )
+            .route(
+                "/admin/webhooks/deliveries",
+                web::get().to(list_webhook_deliveries),
+            )
+            .route(
+                "/admin/webhooks/deliveries/{delivery_id}",
+                web::get().to(webhook_delivery),
+            )
+            .route(
+                "/admin/webhooks/deliveries/{delivery_id}/redeliver",
+                web::post().to(redeliver_webhook),
+            )
+            .app_data(web::FormConfig::default().error_handler(|e, _| payload_error_handler(e)))
+            .app_data(web::JsonConfig::default().error_handler(|e, _| payload_error_handler(e)))
+            .app_data(state.clone())
     })
     .listen(listener)?
     .run();
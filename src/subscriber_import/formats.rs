@@ -0,0 +1,324 @@
+use csv_async::StringRecord;
+
+use super::{ImportError, ImportStatus};
+use crate::{
+    domain::{NewSubscriber, SubscriptionStatus},
+    routes::FormData,
+};
+
+/// Column layouts of the CSV files we know how to import.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// Our own layout, with `email` and `name` columns.
+    #[default]
+    Csv,
+    /// A Mailchimp audience export, with `Email Address`, `First Name` and `Last Name` columns.
+    /// Mailchimp exports subscribed, unsubscribed and cleaned contacts as separate files,
+    /// we tell them apart by their `Status`, `UNSUB_TIME` or `CLEAN_TIME` columns.
+    Mailchimp,
+    /// A Substack subscriber export, with `email`, and optionally `name`
+    /// and `email_disabled` columns.
+    Substack,
+}
+
+/// The status a subscriber had on the platform they are migrating from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceStatus {
+    Subscribed,
+    Pending,
+    Unsubscribed,
+    /// Mailchimp "cleans" addresses that hard-bounced.
+    Cleaned,
+}
+
+impl SourceStatus {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "subscribed" => Ok(Self::Subscribed),
+            "pending" => Ok(Self::Pending),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "cleaned" => Ok(Self::Cleaned),
+            unsupported => Err(format!("'{}' is not a supported status", unsupported)),
+        }
+    }
+
+    /// The mailbolt status the subscriber is stored with, and the reason to
+    /// suppress the address from any future email, if there is one.
+    /// `subscribed_as` is the status requested for people who were subscribed.
+    pub fn stored_as(
+        self,
+        subscribed_as: ImportStatus,
+    ) -> (SubscriptionStatus, Option<&'static str>) {
+        match self {
+            SourceStatus::Subscribed => (subscribed_as.into(), None),
+            SourceStatus::Pending => (SubscriptionStatus::PendingConfirmation, None),
+            SourceStatus::Unsubscribed => (SubscriptionStatus::Unsubscribed, Some("unsubscribed")),
+            SourceStatus::Cleaned => (SubscriptionStatus::Bounced, Some("bounced")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ImportedRow {
+    pub subscriber: NewSubscriber,
+    pub source_status: SourceStatus,
+}
+
+#[derive(Debug)]
+enum NameColumns {
+    /// Rows without a name are rejected.
+    Required(usize),
+    /// Rows without a name get the local part of their email as a name.
+    Optional(Option<usize>),
+    /// Same as `Optional`, with the name spread over two columns.
+    Split {
+        first: Option<usize>,
+        last: Option<usize>,
+    },
+}
+
+#[derive(Debug)]
+enum StatusColumn {
+    Fixed(SourceStatus),
+    Status(usize),
+    EmailDisabled(usize),
+}
+
+/// Position of the columns we care about in the CSV header.
+#[derive(Debug)]
+pub struct ColumnMapping {
+    email: usize,
+    name: NameColumns,
+    status: StatusColumn,
+}
+
+impl ColumnMapping {
+    pub fn from_headers(format: ImportFormat, headers: &StringRecord) -> Result<Self, ImportError> {
+        let find = |column: &str| {
+            headers
+                .iter()
+                // Spreadsheet tools like to prefix UTF-8 files with a byte order mark
+                .map(|header| header.trim_start_matches('\u{feff}'))
+                .position(|header| header.eq_ignore_ascii_case(column))
+        };
+        let require = |column: &str| {
+            find(column).ok_or_else(|| {
+                ImportError::InvalidFile(format!(
+                    "The CSV header is missing the '{}' column",
+                    column
+                ))
+            })
+        };
+
+        let mapping = match format {
+            ImportFormat::Csv => Self {
+                email: require("email")?,
+                name: NameColumns::Required(require("name")?),
+                status: StatusColumn::Fixed(SourceStatus::Subscribed),
+            },
+            ImportFormat::Mailchimp => Self {
+                email: require("Email Address")?,
+                name: NameColumns::Split {
+                    first: find("First Name"),
+                    last: find("Last Name"),
+                },
+                status: match find("Status") {
+                    Some(i) => StatusColumn::Status(i),
+                    None if find("CLEAN_TIME").is_some() => {
+                        StatusColumn::Fixed(SourceStatus::Cleaned)
+                    }
+                    None if find("UNSUB_TIME").is_some() => {
+                        StatusColumn::Fixed(SourceStatus::Unsubscribed)
+                    }
+                    None => StatusColumn::Fixed(SourceStatus::Subscribed),
+                },
+            },
+            ImportFormat::Substack => Self {
+                email: require("email")?,
+                name: NameColumns::Optional(find("name")),
+                status: match find("email_disabled") {
+                    Some(i) => StatusColumn::EmailDisabled(i),
+                    None => StatusColumn::Fixed(SourceStatus::Subscribed),
+                },
+            },
+        };
+
+        Ok(mapping)
+    }
+
+    pub fn email<'r>(&self, record: &'r StringRecord) -> Option<&'r str> {
+        record.get(self.email).filter(|email| !email.is_empty())
+    }
+
+    pub fn parse(&self, record: &StringRecord) -> Result<ImportedRow, String> {
+        let field = |i: Option<usize>| i.and_then(|i| record.get(i)).unwrap_or_default();
+
+        let email = field(Some(self.email)).to_string();
+        let name = match self.name {
+            NameColumns::Required(i) => field(Some(i)).to_string(),
+            NameColumns::Optional(i) => field(i).to_string(),
+            NameColumns::Split { first, last } => [field(first), field(last)]
+                .into_iter()
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        let name = match self.name {
+            NameColumns::Optional(_) | NameColumns::Split { .. } if name.is_empty() => {
+                email.split('@').next().unwrap_or_default().to_string()
+            }
+            _ => name,
+        };
+
+        let source_status = match self.status {
+            StatusColumn::Fixed(status) => status,
+            StatusColumn::Status(i) => SourceStatus::parse(field(Some(i)))?,
+            StatusColumn::EmailDisabled(i) => match field(Some(i)).to_lowercase().as_str() {
+                "true" => SourceStatus::Unsubscribed,
+                "false" | "" => SourceStatus::Subscribed,
+                other => return Err(format!("'{}' is not a valid email_disabled value", other)),
+            },
+        };
+
+        let subscriber = NewSubscriber::try_from(FormData {
+            email,
+            name,
+            ..Default::default()
+        })
+        .map_err(|e| e.to_string())?;
+
+        Ok(ImportedRow {
+            subscriber,
+            source_status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok};
+    use csv_async::StringRecord;
+
+    use super::{ColumnMapping, ImportFormat, SourceStatus};
+
+    fn mapping(format: ImportFormat, headers: Vec<&str>) -> ColumnMapping {
+        ColumnMapping::from_headers(format, &StringRecord::from(headers)).unwrap()
+    }
+
+    #[test]
+    fn headers_are_matched_case_insensitively_in_any_order() {
+        let columns = mapping(ImportFormat::Csv, vec!["\u{feff}Name", "EMAIL", "country"]);
+
+        let record = StringRecord::from(vec!["Ursula Le Guin", "ursula@example.com", "US"]);
+        let row = columns.parse(&record).unwrap();
+
+        assert_eq!(row.subscriber.email.as_ref(), "ursula@example.com");
+        assert_eq!(row.subscriber.name.as_ref(), "Ursula Le Guin");
+        assert_eq!(row.source_status, SourceStatus::Subscribed);
+    }
+
+    #[test]
+    fn a_header_without_an_email_column_is_rejected() {
+        let headers = StringRecord::from(vec!["name", "country"]);
+        assert_err!(ColumnMapping::from_headers(ImportFormat::Csv, &headers));
+        assert_err!(ColumnMapping::from_headers(
+            ImportFormat::Mailchimp,
+            &headers
+        ));
+    }
+
+    #[test]
+    fn rows_with_missing_fields_are_rejected() {
+        let columns = mapping(ImportFormat::Csv, vec!["email", "name"]);
+
+        assert_err!(columns.parse(&StringRecord::from(vec!["ursula@example.com"])));
+        assert_ok!(columns.parse(&StringRecord::from(vec!["ursula@example.com", "Ursula"])));
+    }
+
+    #[test]
+    fn mailchimp_names_are_joined_and_default_to_the_local_part() {
+        let columns = mapping(
+            ImportFormat::Mailchimp,
+            vec!["Email Address", "First Name", "Last Name", "MEMBER_RATING"],
+        );
+
+        let row = columns
+            .parse(&StringRecord::from(vec![
+                "ursula@example.com",
+                "Ursula",
+                "Le Guin",
+                "2",
+            ]))
+            .unwrap();
+        assert_eq!(row.subscriber.name.as_ref(), "Ursula Le Guin");
+
+        let row = columns
+            .parse(&StringRecord::from(vec!["terry@example.com", "", "", "2"]))
+            .unwrap();
+        assert_eq!(row.subscriber.name.as_ref(), "terry");
+    }
+
+    #[test]
+    fn mailchimp_statuses_are_read_from_the_status_column() {
+        let columns = mapping(ImportFormat::Mailchimp, vec!["Email Address", "Status"]);
+        let status = |value: &str| {
+            columns
+                .parse(&StringRecord::from(vec!["ursula@example.com", value]))
+                .map(|row| row.source_status)
+        };
+
+        assert_eq!(status("subscribed"), Ok(SourceStatus::Subscribed));
+        assert_eq!(status("Unsubscribed"), Ok(SourceStatus::Unsubscribed));
+        assert_eq!(status("cleaned"), Ok(SourceStatus::Cleaned));
+        assert_eq!(status("pending"), Ok(SourceStatus::Pending));
+        assert_err!(status("archived"));
+    }
+
+    #[test]
+    fn mailchimp_statuses_are_inferred_from_the_exported_file() {
+        let record = StringRecord::from(vec!["ursula@example.com", "2023-01-01 10:00:00"]);
+
+        let columns = mapping(ImportFormat::Mailchimp, vec!["Email Address", "UNSUB_TIME"]);
+        assert_eq!(
+            columns.parse(&record).unwrap().source_status,
+            SourceStatus::Unsubscribed
+        );
+
+        let columns = mapping(ImportFormat::Mailchimp, vec!["Email Address", "CLEAN_TIME"]);
+        assert_eq!(
+            columns.parse(&record).unwrap().source_status,
+            SourceStatus::Cleaned
+        );
+
+        let columns = mapping(
+            ImportFormat::Mailchimp,
+            vec!["Email Address", "CONFIRM_TIME"],
+        );
+        assert_eq!(
+            columns.parse(&record).unwrap().source_status,
+            SourceStatus::Subscribed
+        );
+    }
+
+    #[test]
+    fn substack_disabled_emails_are_unsubscribed() {
+        let columns = mapping(
+            ImportFormat::Substack,
+            vec!["email", "active_subscription", "email_disabled"],
+        );
+        let status = |value: &str| {
+            columns
+                .parse(&StringRecord::from(vec![
+                    "ursula@example.com",
+                    "false",
+                    value,
+                ]))
+                .map(|row| row.source_status)
+        };
+
+        assert_eq!(status("true"), Ok(SourceStatus::Unsubscribed));
+        assert_eq!(status("false"), Ok(SourceStatus::Subscribed));
+        assert_err!(status("maybe"));
+    }
+}
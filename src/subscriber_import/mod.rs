@@ -0,0 +1,482 @@
+use anyhow::Context;
+use chrono::Utc;
+use csv_async::{AsyncReaderBuilder, StringRecord, Trim};
+use sqlx::{postgres::PgCopyIn, PgConnection, PgPool, Postgres, Transaction};
+use tokio::io::AsyncRead;
+use uuid::Uuid;
+
+mod formats;
+
+use formats::ColumnMapping;
+pub use formats::ImportFormat;
+
+use crate::{
+    domain::{EmailPolicy, EventActor, SubscriberEmail, SubscriptionEventKind, SubscriptionStatus},
+    email_client::EmailClient,
+    email_outbox::{dispatch_outbox, enqueue_emails, EmailKind, OutboxEmail, OutboxPolicy},
+    routes::{
+        confirmation_email_bodies, error_chain_fmt, generate_subscription_token,
+        CONFIRMATION_EMAIL_INTRO, CONFIRMATION_EMAIL_SUBJECT,
+    },
+};
+
+/// How many validated rows we buffer before shipping them to Postgres.
+const COPY_BATCH_SIZE: usize = 5_000;
+/// How many confirmation emails of imported subscribers are built and queued at once.
+const CONFIRMATION_BATCH_SIZE: i64 = 500;
+
+/// The status imported subscribers start with,
+/// unless the file says they unsubscribed or bounced.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    /// The list owner already collected consent, so no confirmation is needed.
+    Confirmed,
+    /// Every imported subscriber receives a confirmation email.
+    PendingConfirmation,
+}
+
+impl From<ImportStatus> for SubscriptionStatus {
+    fn from(status: ImportStatus) -> Self {
+        match status {
+            ImportStatus::Confirmed => SubscriptionStatus::Confirmed,
+            ImportStatus::PendingConfirmation => SubscriptionStatus::PendingConfirmation,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RejectedRow {
+    /// Line of the CSV file the row starts at, counting the header as line 1.
+    pub line: u64,
+    pub email: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ImportReport {
+    pub imported: u64,
+    /// Addresses of the file that must never be emailed from now on,
+    /// because they unsubscribed or bounced on the previous platform.
+    /// Rows rejected as duplicates are suppressed all the same.
+    pub suppressed: u64,
+    pub rejected: Vec<RejectedRow>,
+    /// How many confirmation emails to imported subscribers were queued in the outbox.
+    /// Delivering them is left to the caller, once the import has been committed,
+    /// and the dispatcher picks up whatever it doesn't get to.
+    #[serde(skip)]
+    pub pending_confirmations: u64,
+}
+
+#[derive(thiserror::Error)]
+pub enum ImportError {
+    #[error("{0}")]
+    InvalidFile(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Stream a CSV file laid out according to `format` into the `subscriptions` table.
+///
+/// Every row goes through the same validation as the subscription form.
+/// Valid rows are `COPY`'ed into `subscription_import_staging` and moved into
+/// `subscriptions` with a single statement, so a file with hundreds of thousands
+/// of rows does not cost one round-trip per row. The whole import runs in one
+/// transaction: either every valid row is imported or none is.
+#[tracing::instrument(
+    name = "Import subscribers from CSV",
+    skip(pool, reader, email_policy, base_url)
+)]
+pub async fn import_subscribers<R>(
+    pool: &PgPool,
+    reader: R,
+    format: ImportFormat,
+    status: ImportStatus,
+    email_policy: &EmailPolicy,
+    base_url: &str,
+) -> Result<ImportReport, ImportError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let import_id = Uuid::new_v4();
+    let mut csv_reader = AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .create_reader(reader);
+
+    let headers = csv_reader
+        .headers()
+        .await
+        .map_err(|e| ImportError::InvalidFile(format!("Could not read the CSV header: {}", e)))?
+        .clone();
+    let columns = ColumnMapping::from_headers(format, &headers)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let mut copy = transaction
+        .copy_in_raw(
+            "COPY subscription_import_staging \
+            (import_id, line, id, email, email_normalized, name, status, \
+            suppression_reason, subscription_token) \
+            FROM STDIN WITH (FORMAT csv)",
+        )
+        .await
+        .context("Failed to start copying into the import staging table")?;
+
+    let mut rejected = match stage_rows(
+        &mut copy,
+        &mut csv_reader,
+        &columns,
+        import_id,
+        status,
+        email_policy,
+    )
+    .await
+    {
+        Ok(rejected) => rejected,
+        Err(e) => {
+            // The connection is unusable until the COPY is either finished or aborted.
+            copy.abort("Import aborted")
+                .await
+                .context("Failed to abort copying into the import staging table")?;
+            return Err(e);
+        }
+    };
+    copy.finish()
+        .await
+        .context("Failed to copy rows into the import staging table")?;
+
+    let imported = sqlx::query!(
+        r#"
+    INSERT INTO subscriptions
+      (id, email, email_normalized, name, subscribed_at, status, pending_since)
+    SELECT id, email, email_normalized, name, $2, status, CASE WHEN status = $3 THEN $2::timestamptz END
+    FROM subscription_import_staging
+    WHERE import_id = $1
+    ORDER BY line
+    ON CONFLICT DO NOTHING
+    "#,
+        import_id,
+        Utc::now(),
+        SubscriptionStatus::PendingConfirmation.as_str()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to move staged rows into subscriptions")?
+    .rows_affected();
+
+    // Imported subscribers who already confirmed, unsubscribed or bounced also get
+    // the event entering that status, which has the same name as the status.
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_events (subscriber_id, kind, actor, occurred_at)
+    SELECT s.id, kind, $2, $3
+    FROM subscription_import_staging s
+    JOIN subscriptions ON subscriptions.id = s.id
+    CROSS JOIN LATERAL (VALUES (1, $4), (2, s.status)) AS events(position, kind)
+    WHERE s.import_id = $1 AND (events.position = 1 OR s.status <> $5)
+    ORDER BY s.line, events.position
+    "#,
+        import_id,
+        EventActor::Import.as_str(),
+        Utc::now(),
+        SubscriptionEventKind::Subscribed.as_str(),
+        SubscriptionStatus::PendingConfirmation.as_str()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to record the history of imported subscribers")?;
+
+    // Rows that did not make it are either already subscribed
+    // or appear more than once in the file.
+    let duplicates = sqlx::query!(
+        r#"
+    SELECT line, email FROM subscription_import_staging s
+    WHERE import_id = $1
+      AND NOT EXISTS (SELECT 1 FROM subscriptions WHERE subscriptions.id = s.id)
+    "#,
+        import_id
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to fetch duplicated rows")?;
+
+    rejected.extend(duplicates.into_iter().map(|row| RejectedRow {
+        line: row.line as u64,
+        reason: format!("'{}' is already subscribed", row.email),
+        email: Some(row.email),
+    }));
+    rejected.sort_by_key(|row| row.line);
+
+    let suppressed = sqlx::query!(
+        r#"
+    INSERT INTO email_suppressions (email, reason, created_at)
+    SELECT s.email, s.suppression_reason, $2
+    FROM subscription_import_staging s
+    WHERE s.import_id = $1 AND s.suppression_reason IS NOT NULL
+    ON CONFLICT DO NOTHING
+    "#,
+        import_id,
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to store suppressed addresses")?
+    .rows_affected();
+
+    let pending_confirmations =
+        queue_import_confirmations(&mut transaction, import_id, base_url).await?;
+
+    sqlx::query!(
+        "DELETE FROM subscription_import_staging WHERE import_id = $1",
+        import_id
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to clean up the import staging table")?;
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the import transaction")?;
+
+    tracing::info!(
+        imported,
+        suppressed,
+        rejected = rejected.len(),
+        "Finished importing subscribers"
+    );
+
+    Ok(ImportReport {
+        imported,
+        suppressed,
+        rejected,
+        pending_confirmations,
+    })
+}
+
+/// Validate every record of the CSV file, sending the valid ones
+/// to Postgres in batches and collecting the rejected ones.
+async fn stage_rows<R>(
+    copy: &mut PgCopyIn<&mut PgConnection>,
+    csv_reader: &mut csv_async::AsyncReader<R>,
+    columns: &ColumnMapping,
+    import_id: Uuid,
+    status: ImportStatus,
+    email_policy: &EmailPolicy,
+) -> Result<Vec<RejectedRow>, ImportError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut rejected = Vec::new();
+    let mut batch = csv::Writer::from_writer(Vec::new());
+    let mut batch_len = 0;
+    let mut record = StringRecord::new();
+
+    loop {
+        match csv_reader.read_record(&mut record).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) if e.is_io_error() => {
+                return Err(anyhow::Error::new(e)
+                    .context("Failed to read the CSV file")
+                    .into())
+            }
+            Err(e) => {
+                rejected.push(RejectedRow {
+                    line: e.position().map(|p| p.line()).unwrap_or_default(),
+                    email: None,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        }
+
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let row = match columns.parse(&record) {
+            Ok(row) => row,
+            Err(reason) => {
+                rejected.push(RejectedRow {
+                    line,
+                    email: columns.email(&record).map(String::from),
+                    reason,
+                });
+                continue;
+            }
+        };
+
+        let (stored_status, suppression_reason) = row.source_status.stored_as(status);
+        let subscription_token = if stored_status == SubscriptionStatus::PendingConfirmation {
+            generate_subscription_token()
+        } else {
+            String::new()
+        };
+        // An unquoted empty field is how the CSV format spells NULL.
+        batch
+            .write_record([
+                import_id.to_string().as_str(),
+                line.to_string().as_str(),
+                Uuid::new_v4().to_string().as_str(),
+                row.subscriber.email.as_ref(),
+                email_policy.canonical(&row.subscriber.email).as_str(),
+                row.subscriber.name.as_ref(),
+                stored_status.as_str(),
+                suppression_reason.unwrap_or_default(),
+                subscription_token.as_str(),
+            ])
+            .context("Failed to serialize a staged row")?;
+        batch_len += 1;
+
+        if batch_len == COPY_BATCH_SIZE {
+            send_batch(copy, &mut batch).await?;
+            batch_len = 0;
+        }
+    }
+
+    if batch_len > 0 {
+        send_batch(copy, &mut batch).await?;
+    }
+
+    Ok(rejected)
+}
+
+async fn send_batch(
+    copy: &mut PgCopyIn<&mut PgConnection>,
+    batch: &mut csv::Writer<Vec<u8>>,
+) -> Result<(), anyhow::Error> {
+    let data = std::mem::replace(batch, csv::Writer::from_writer(Vec::new()))
+        .into_inner()
+        .context("Failed to serialize staged rows")?;
+    copy.send(data)
+        .await
+        .context("Failed to copy rows into the import staging table")?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Queue confirmation emails for imported subscribers",
+    skip(transaction, base_url)
+)]
+async fn queue_import_confirmations(
+    transaction: &mut Transaction<'_, Postgres>,
+    import_id: Uuid,
+    base_url: &str,
+) -> Result<u64, anyhow::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_tokens (subscription_token, subscriber_id)
+    SELECT s.subscription_token, s.id
+    FROM subscription_import_staging s
+    JOIN subscriptions ON subscriptions.id = s.id
+    WHERE s.import_id = $1 AND s.subscription_token IS NOT NULL
+    "#,
+        import_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store subscription tokens for imported subscribers")?;
+
+    // A batch at a time, so large imports don't hold every email body in memory.
+    let mut queued = 0;
+    let mut after_line = 0;
+    loop {
+        let rows = sqlx::query_as!(
+            ImportedSubscriber,
+            r#"
+    SELECT s.line, s.id, s.email, s.subscription_token AS "subscription_token!"
+    FROM subscription_import_staging s
+    JOIN subscriptions ON subscriptions.id = s.id
+    WHERE s.import_id = $1 AND s.subscription_token IS NOT NULL AND s.line > $2
+    ORDER BY s.line
+    LIMIT $3
+    "#,
+            import_id,
+            after_line,
+            CONFIRMATION_BATCH_SIZE
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .context("Failed to fetch imported subscribers")?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after_line = last.line;
+
+        queued += queue_confirmation_batch(transaction, rows, base_url).await?;
+    }
+
+    Ok(queued)
+}
+
+struct ImportedSubscriber {
+    line: i64,
+    id: Uuid,
+    email: String,
+    subscription_token: String,
+}
+
+async fn queue_confirmation_batch(
+    transaction: &mut Transaction<'_, Postgres>,
+    rows: Vec<ImportedSubscriber>,
+    base_url: &str,
+) -> Result<u64, anyhow::Error> {
+    let emails = rows
+        .into_iter()
+        .map(|row| {
+            let recipient = SubscriberEmail::parse(row.email)
+                .map_err(|e| anyhow::anyhow!(e))
+                .context("Failed to parse an imported address")?;
+            let (html_body, text_body) = confirmation_email_bodies(
+                CONFIRMATION_EMAIL_INTRO,
+                base_url,
+                &row.subscription_token,
+            );
+            Ok((row.id, recipient, html_body, text_body))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    let emails: Vec<_> = emails
+        .iter()
+        .map(
+            |(subscriber_id, recipient, html_body, text_body)| OutboxEmail {
+                subscriber_id: *subscriber_id,
+                kind: EmailKind::Confirmation,
+                recipient,
+                subject: CONFIRMATION_EMAIL_SUBJECT,
+                html_body,
+                text_body,
+            },
+        )
+        .collect();
+    enqueue_emails(transaction, &emails)
+        .await
+        .context("Failed to queue confirmation emails for imported subscribers")?;
+    Ok(emails.len() as u64)
+}
+
+/// Deliver the confirmation emails of imported subscribers, one after the other,
+/// by going through the outbox once rather than waiting for the dispatcher.
+/// Whatever else is due goes out with them, and those that fail are left to
+/// the dispatcher.
+#[tracing::instrument(
+    name = "Send confirmation emails to imported subscribers",
+    skip(pool, email_client, outbox_policy)
+)]
+pub async fn send_import_confirmations(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    outbox_policy: &OutboxPolicy,
+    pending_confirmations: u64,
+) {
+    if let Err(e) = dispatch_outbox(pool, email_client, outbox_policy).await {
+        tracing::error!("Failed to send confirmation emails: {:?}", e);
+    }
+}
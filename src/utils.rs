@@ -0,0 +1,26 @@
+use actix_web::{http::header, HttpRequest};
+
+/// Return an opaque 500 while preserving the error's root cause for logging.
+pub fn e500<T>(e: T) -> actix_web::Error
+where
+    T: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    actix_web::error::ErrorInternalServerError(e)
+}
+
+/// Whether the client asked for JSON through its `Accept` header.
+pub fn accepts_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            let media_type = media_range.split(';').next().unwrap_or_default().trim();
+            is_json_content_type(media_type)
+        })
+}
+
+/// Whether a request body of this content type should be read as JSON.
+pub fn is_json_content_type(content_type: &str) -> bool {
+    content_type == "application/json" || content_type.ends_with("+json")
+}
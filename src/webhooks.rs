@@ -0,0 +1,535 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    configuration::Settings, domain::SubscriptionEventKind, email_outbox::OutboxPolicy,
+    startup::get_db_conn_pool,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long a claimed delivery is left to its dispatcher before others pick it up
+/// again. Well over the webhook timeout, so that only happens if it died mid-post.
+const CLAIM_TIMEOUT_SECS: i64 = 120;
+
+/// Events that are sent to webhook endpoints. Erasures aren't: there is
+/// nothing left to send once a subscriber is gone.
+/// The `queue_webhook_event` trigger queues these kinds, keep it in step.
+pub const WEBHOOK_EVENTS: [SubscriptionEventKind; 5] = [
+    SubscriptionEventKind::Subscribed,
+    SubscriptionEventKind::Confirmed,
+    SubscriptionEventKind::Unsubscribed,
+    SubscriptionEventKind::Bounced,
+    SubscriptionEventKind::EmailChanged,
+];
+
+/// `t=<unix timestamp>,v1=<hex HMAC-SHA256 of "<timestamp>.<body>">`, keyed with
+/// the secret of the endpoint. The timestamp lets receivers reject replays.
+pub const SIGNATURE_HEADER: &str = "Mailbolt-Signature";
+/// Id of the delivery, the same for every attempt at it.
+pub const DELIVERY_HEADER: &str = "Mailbolt-Delivery";
+pub const EVENT_HEADER: &str = "Mailbolt-Event";
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct WebhookEndpoint {
+    /// Named in the delivery log. Renaming an endpoint strands its pending deliveries.
+    pub name: String,
+    pub url: String,
+    pub secret: Secret<String>,
+    /// Events the endpoint gets, all of `WEBHOOK_EVENTS` when left out.
+    #[serde(default = "all_webhook_events")]
+    pub events: Vec<SubscriptionEventKind>,
+}
+
+fn all_webhook_events() -> Vec<SubscriptionEventKind> {
+    WEBHOOK_EVENTS.to_vec()
+}
+
+impl WebhookEndpoint {
+    fn wants(&self, kind: &str) -> bool {
+        self.events.iter().any(|event| event.as_str() == kind)
+    }
+}
+
+/// Where subscription events go, and how failed deliveries are retried.
+#[derive(Clone, Debug)]
+pub struct WebhookPolicy {
+    pub endpoints: Vec<WebhookEndpoint>,
+    pub retries: OutboxPolicy,
+}
+
+impl WebhookPolicy {
+    pub fn new(endpoints: Vec<WebhookEndpoint>, retries: OutboxPolicy) -> Result<Self, String> {
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            if endpoint.name.trim().is_empty() {
+                return Err("Webhook endpoints need a name".into());
+            }
+            if endpoints[..i]
+                .iter()
+                .any(|other| other.name == endpoint.name)
+            {
+                return Err(format!(
+                    "There is more than one webhook endpoint named '{}'",
+                    endpoint.name
+                ));
+            }
+            reqwest::Url::parse(&endpoint.url).map_err(|e| {
+                format!(
+                    "Invalid URL for the webhook endpoint '{}': {}",
+                    endpoint.name, e
+                )
+            })?;
+            if let Some(kind) = endpoint
+                .events
+                .iter()
+                .find(|kind| !WEBHOOK_EVENTS.contains(kind))
+            {
+                return Err(format!(
+                    "'{}' events are not sent to webhooks",
+                    kind.as_str()
+                ));
+            }
+        }
+        Ok(Self { endpoints, retries })
+    }
+
+    pub fn endpoint(&self, name: &str) -> Option<&WebhookEndpoint> {
+        self.endpoints.iter().find(|endpoint| endpoint.name == name)
+    }
+
+    fn endpoint_names(&self) -> Vec<String> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.name.clone())
+            .collect()
+    }
+}
+
+pub struct WebhookClient {
+    http_client: Client,
+}
+
+impl WebhookClient {
+    pub fn new(timeout: std::time::Duration) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self { http_client }
+    }
+
+    /// POST a signed payload to `endpoint` and return the status it answered with.
+    async fn post(
+        &self,
+        endpoint: &WebhookEndpoint,
+        delivery_id: Uuid,
+        event_kind: &str,
+        payload: &str,
+    ) -> Result<StatusCode, reqwest::Error> {
+        let signature = signature(&endpoint.secret, Utc::now().timestamp(), payload);
+        let response = self
+            .http_client
+            .post(&endpoint.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .header(DELIVERY_HEADER, delivery_id.to_string())
+            .header(EVENT_HEADER, event_kind)
+            .body(payload.to_owned())
+            .send()
+            .await?;
+        Ok(response.status())
+    }
+}
+
+/// Value of the `SIGNATURE_HEADER` of a payload sent at `timestamp`.
+pub fn signature(secret: &Secret<String>, timestamp: i64, payload: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    format!(
+        "t={},v1={}",
+        timestamp,
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+struct QueuedEvent {
+    event_id: i64,
+    subscriber_id: Uuid,
+    event_kind: String,
+    payload: String,
+}
+
+struct ClaimedDelivery {
+    id: Uuid,
+    endpoint: String,
+    event_kind: String,
+    payload: String,
+    attempts: i32,
+}
+
+/// Dispatch webhooks every `interval`, until the process stops.
+pub async fn run_webhooks_until_stopped(config: Settings) {
+    let pool = get_db_conn_pool(&config.database);
+    let client = config.webhooks.client();
+    let policy = config
+        .webhooks
+        .policy()
+        .expect("Invalid webhook configuration");
+    let interval = config.webhooks.poll_interval();
+
+    loop {
+        if let Err(e) = dispatch_webhooks(&pool, &client, &policy).await {
+            tracing::error!("Failed to dispatch webhooks: {:?}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Hand the queued events to the endpoints that want them, then try every
+/// delivery that is due. Returns how many deliveries went through.
+#[tracing::instrument(name = "Dispatch webhooks", skip_all)]
+pub async fn dispatch_webhooks(
+    pool: &PgPool,
+    client: &WebhookClient,
+    policy: &WebhookPolicy,
+) -> Result<u64, anyhow::Error> {
+    while fan_out_events(pool, policy).await? > 0 {}
+
+    let mut delivered = 0;
+    let mut attempted = 0;
+    // Failed deliveries are pushed into the future, so this ends
+    // once everything due has been attempted.
+    while let Some(success) = deliver_next(pool, client, policy, None).await? {
+        attempted += 1;
+        if success {
+            delivered += 1;
+        }
+    }
+    if attempted > delivered {
+        tracing::warn!(
+            failed = attempted - delivered,
+            "Some webhooks could not be delivered"
+        );
+    }
+    Ok(delivered)
+}
+
+/// Turn a batch of queued events into deliveries, one per endpoint wanting them.
+/// Events no endpoint wants are dropped. Returns how many events were handled.
+async fn fan_out_events(pool: &PgPool, policy: &WebhookPolicy) -> Result<usize, anyhow::Error> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let events = sqlx::query_as!(
+        QueuedEvent,
+        r#"
+    SELECT event_id, subscriber_id, event_kind, payload
+    FROM webhook_events
+    ORDER BY event_id
+    LIMIT 100
+    FOR UPDATE SKIP LOCKED
+    "#
+    )
+    .fetch_all(&mut transaction)
+    .await
+    .context("Failed to look for queued webhook events")?;
+
+    let now = Utc::now();
+    for event in &events {
+        for endpoint in policy
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.wants(&event.event_kind))
+        {
+            sqlx::query!(
+                r#"
+    INSERT INTO webhook_deliveries
+      (id, event_id, subscriber_id, endpoint, event_kind, payload, created_at, next_attempt_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+    ON CONFLICT (event_id, endpoint) DO NOTHING
+    "#,
+                Uuid::new_v4(),
+                event.event_id,
+                event.subscriber_id,
+                endpoint.name,
+                event.event_kind,
+                event.payload,
+                now
+            )
+            .execute(&mut transaction)
+            .await
+            .context("Failed to schedule a webhook delivery")?;
+        }
+    }
+    let event_ids: Vec<i64> = events.iter().map(|event| event.event_id).collect();
+    sqlx::query!(
+        "DELETE FROM webhook_events WHERE event_id = ANY($1)",
+        &event_ids
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to dequeue webhook events")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the webhook deliveries")?;
+
+    Ok(events.len())
+}
+
+/// Attempt the delivery with this id right away, if it is due.
+/// Returns whether it went through; if not, the dispatcher retries it.
+pub async fn deliver_webhook(
+    pool: &PgPool,
+    client: &WebhookClient,
+    policy: &WebhookPolicy,
+    id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    let delivered = deliver_next(pool, client, policy, Some(id)).await?;
+    Ok(delivered.unwrap_or(false))
+}
+
+/// Make a delivery due again with a fresh set of attempts,
+/// even if it went through already. Returns whether it exists.
+pub async fn schedule_redelivery(pool: &PgPool, id: Uuid) -> Result<bool, anyhow::Error> {
+    let updated = sqlx::query!(
+        r#"
+    UPDATE webhook_deliveries
+    SET attempts = 0, delivered_at = NULL, next_attempt_at = $2
+    WHERE id = $1
+    "#,
+        id,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to schedule a webhook redelivery")?
+    .rows_affected();
+    Ok(updated > 0)
+}
+
+/// Claim and attempt the next delivery due, or the one with this id.
+/// Returns whether it went through, or `None` when there was nothing to deliver.
+async fn deliver_next(
+    pool: &PgPool,
+    client: &WebhookClient,
+    policy: &WebhookPolicy,
+    id: Option<Uuid>,
+) -> Result<Option<bool>, anyhow::Error> {
+    let Some(delivery) = claim(pool, policy, id).await? else {
+        return Ok(None);
+    };
+    deliver(pool, client, policy, delivery).await.map(Some)
+}
+
+/// Claim the next delivery due to a configured endpoint, or the one with this id
+/// if it is due, by counting the attempt and pushing it `CLAIM_TIMEOUT_SECS` into
+/// the future. Other dispatchers skip it from then on, and no lock is held while
+/// it is posted.
+async fn claim(
+    pool: &PgPool,
+    policy: &WebhookPolicy,
+    id: Option<Uuid>,
+) -> Result<Option<ClaimedDelivery>, anyhow::Error> {
+    let now = Utc::now();
+    sqlx::query_as!(
+        ClaimedDelivery,
+        r#"
+    UPDATE webhook_deliveries
+    SET attempts = attempts + 1, next_attempt_at = $5
+    WHERE id = (
+      SELECT id FROM webhook_deliveries
+      WHERE delivered_at IS NULL
+        AND attempts < $1
+        AND next_attempt_at <= $2
+        AND endpoint = ANY($3)
+        AND ($4::uuid IS NULL OR id = $4)
+      ORDER BY next_attempt_at
+      LIMIT 1
+      FOR UPDATE SKIP LOCKED
+    )
+    RETURNING id, endpoint, event_kind, payload, attempts
+    "#,
+        policy.retries.max_attempts,
+        now,
+        &policy.endpoint_names(),
+        id,
+        now + chrono::Duration::seconds(CLAIM_TIMEOUT_SECS)
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to claim a webhook delivery")
+}
+
+/// Post a claimed delivery, then record how it went.
+async fn deliver(
+    pool: &PgPool,
+    client: &WebhookClient,
+    policy: &WebhookPolicy,
+    delivery: ClaimedDelivery,
+) -> Result<bool, anyhow::Error> {
+    let endpoint = policy
+        .endpoint(&delivery.endpoint)
+        .context("Claimed a webhook delivery for an unknown endpoint")?;
+    let (status_code, error) = match client
+        .post(
+            endpoint,
+            delivery.id,
+            &delivery.event_kind,
+            &delivery.payload,
+        )
+        .await
+    {
+        Ok(status) if status.is_success() => (Some(status), None),
+        Ok(status) => (
+            Some(status),
+            Some(format!("The endpoint answered {}", status)),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let status_code = status_code.map(|status| i32::from(status.as_u16()));
+    let now = Utc::now();
+
+    let delivered_at = match &error {
+        None => Some(now),
+        Some(error) => {
+            if delivery.attempts >= policy.retries.max_attempts {
+                tracing::error!(%error, webhook_delivery_id = %delivery.id, "Giving up on a webhook delivery");
+            } else {
+                tracing::warn!(%error, webhook_delivery_id = %delivery.id, "Failed to deliver a webhook");
+            }
+            None
+        }
+    };
+    let next_attempt_at = now + policy.retries.retry_delay(delivery.attempts);
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    record_attempt(
+        &mut transaction,
+        delivery.id,
+        now,
+        status_code,
+        error.as_deref(),
+    )
+    .await?;
+    sqlx::query!(
+        r#"
+    UPDATE webhook_deliveries
+    SET last_status_code = $2, last_error = $3, delivered_at = $4, next_attempt_at = $5
+    WHERE id = $1
+    "#,
+        delivery.id,
+        status_code,
+        error,
+        delivered_at,
+        next_attempt_at
+    )
+    .execute(&mut transaction)
+    .await
+    .context("Failed to update a webhook delivery")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit a webhook delivery attempt")?;
+    Ok(delivered_at.is_some())
+}
+
+async fn record_attempt(
+    transaction: &mut Transaction<'_, Postgres>,
+    delivery_id: Uuid,
+    attempted_at: DateTime<Utc>,
+    status_code: Option<i32>,
+    error: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO webhook_delivery_attempts (delivery_id, attempted_at, status_code, error)
+    VALUES ($1, $2, $3, $4)
+    "#,
+        delivery_id,
+        attempted_at,
+        status_code,
+        error
+    )
+    .execute(transaction)
+    .await
+    .context("Failed to record a webhook delivery attempt")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    use super::{signature, WebhookEndpoint, WebhookPolicy, WEBHOOK_EVENTS};
+    use crate::{domain::SubscriptionEventKind, email_outbox::OutboxPolicy};
+
+    fn endpoint(name: &str, url: &str) -> WebhookEndpoint {
+        WebhookEndpoint {
+            name: name.into(),
+            url: url.into(),
+            secret: Secret::new("secret".into()),
+            events: WEBHOOK_EVENTS.to_vec(),
+        }
+    }
+
+    fn policy_with(endpoints: Vec<WebhookEndpoint>) -> Result<WebhookPolicy, String> {
+        let retries = OutboxPolicy {
+            max_attempts: 3,
+            retry_base_delay: chrono::Duration::seconds(1),
+            retry_max_delay: chrono::Duration::seconds(10),
+        };
+        WebhookPolicy::new(endpoints, retries)
+    }
+
+    #[test]
+    fn signatures_cover_the_timestamp_and_the_payload() {
+        let secret = Secret::new("secret".to_string());
+        let signed = signature(&secret, 1_700_000_000, r#"{"id":1}"#);
+
+        assert!(signed.starts_with("t=1700000000,v1="));
+        assert_eq!(signed.len(), "t=1700000000,v1=".len() + 64);
+        assert_ne!(signed, signature(&secret, 1_700_000_001, r#"{"id":1}"#));
+        assert_ne!(signed, signature(&secret, 1_700_000_000, r#"{"id":2}"#));
+        assert_ne!(
+            signed,
+            signature(&Secret::new("other".into()), 1_700_000_000, r#"{"id":1}"#)
+        );
+    }
+
+    #[test]
+    fn endpoints_need_a_unique_name_and_a_valid_url() {
+        assert_ok!(policy_with(vec![]));
+        assert_ok!(policy_with(vec![
+            endpoint("crm", "https://crm.example.com/hooks"),
+            endpoint("audit", "https://audit.example.com/hooks"),
+        ]));
+        assert_err!(policy_with(vec![endpoint("", "https://crm.example.com")]));
+        assert_err!(policy_with(vec![endpoint("crm", "not a url")]));
+        assert_err!(policy_with(vec![
+            endpoint("crm", "https://crm.example.com/hooks"),
+            endpoint("crm", "https://crm.example.com/other-hooks"),
+        ]));
+    }
+
+    #[test]
+    fn endpoints_only_get_the_events_webhooks_carry() {
+        let mut erasures = endpoint("crm", "https://crm.example.com/hooks");
+        erasures.events = vec![SubscriptionEventKind::Erased];
+        assert_err!(policy_with(vec![erasures]));
+
+        let mut confirmations = endpoint("crm", "https://crm.example.com/hooks");
+        confirmations.events = vec![SubscriptionEventKind::Confirmed];
+        assert!(confirmations.wants("confirmed"));
+        assert!(!confirmations.wants("subscribed"));
+    }
+}
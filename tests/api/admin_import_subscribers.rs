@@ -0,0 +1,289 @@
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn import_requires_the_admin_token() {
+    let app = spawn_app().await;
+
+    let resp = reqwest::Client::new()
+        .post(format!("{}/admin/subscribers/import", &app.address))
+        .bearer_auth("not-the-admin-token")
+        .body("email,name\nursula@example.com,Ursula")
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(resp.status().as_u16(), 401);
+    assert!(resp.headers().get("WWW-Authenticate").is_some());
+}
+
+#[tokio::test]
+async fn import_persists_valid_rows_and_reports_rejected_ones() {
+    let app = spawn_app().await;
+    let csv = "name,email\n\
+        Ursula Le Guin,ursula@example.com\n\
+        Octavia Butler,not-an-email\n\
+        ,terry@example.com\n\
+        Ursula Again,ursula@example.com\n\
+        Terry Pratchett,terry@example.com\n";
+
+    let resp = app.post_import(csv.into(), "?status=confirmed").await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let report: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(report["imported"], 2);
+    let rejected_lines: Vec<_> = report["rejected"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| row["line"].as_u64().unwrap())
+        .collect();
+    assert_eq!(rejected_lines, vec![3, 4, 5]);
+
+    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+
+    assert_eq!(saved.len(), 2);
+    assert_eq!(saved[0].email, "terry@example.com");
+    assert_eq!(saved[0].status, "confirmed");
+    assert_eq!(saved[1].email, "ursula@example.com");
+    assert_eq!(saved[1].name, "Ursula Le Guin");
+}
+
+#[tokio::test]
+async fn import_rejects_people_who_are_already_subscribed() {
+    let app = spawn_app().await;
+    app.post_import(
+        "email,name\nursula@example.com,Ursula".into(),
+        "?status=confirmed",
+    )
+    .await;
+
+    let resp = app
+        .post_import(
+            "email,name\nursula@example.com,Ursula\nterry@example.com,Terry".into(),
+            "?status=confirmed",
+        )
+        .await;
+
+    let report: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["rejected"][0]["email"], "ursula@example.com");
+}
+
+#[tokio::test]
+async fn import_sends_a_confirmation_email_to_pending_subscribers() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .post_import(
+            "email,name\nursula@example.com,Ursula\nterry@example.com,Terry".into(),
+            "",
+        )
+        .await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    // Confirmation emails are sent in the background after the import response
+    app.wait_for_emails(2).await;
+
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_req);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let statuses = sqlx::query!("SELECT status FROM subscriptions ORDER BY status")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(statuses[0].status, "confirmed");
+    assert_eq!(statuses[1].status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn import_queues_a_confirmation_email_for_every_row_of_large_files() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    // More rows than are queued at once
+    let rows = 1_201;
+    let csv = std::iter::once("email,name".to_string())
+        .chain((0..rows).map(|i| format!("reader{}@example.com,Reader {}", i, i)))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let resp = app.post_import(csv, "").await;
+    assert_eq!(resp.status().as_u16(), 200);
+    app.wait_for_background_tasks().await;
+
+    let queued = sqlx::query!(
+        "SELECT COUNT(*) AS emails, COUNT(DISTINCT subscriber_id) AS subscribers, \
+        COUNT(sent_at) AS sent FROM email_outbox WHERE kind = 'confirmation'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(queued.emails, Some(rows));
+    assert_eq!(queued.subscribers, Some(rows));
+    assert_eq!(queued.sent, Some(rows));
+}
+
+#[tokio::test]
+async fn import_returns_400_when_a_required_column_is_missing() {
+    let app = spawn_app().await;
+
+    let resp = app
+        .post_import("email,country\nursula@example.com,US".into(), "")
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 400);
+    assert!(resp.text().await.unwrap().contains("'name'"));
+}
+
+#[tokio::test]
+async fn import_maps_mailchimp_statuses_onto_subscriptions_and_suppressions() {
+    let app = spawn_app().await;
+    let subscribed = "Email Address,First Name,Last Name,MEMBER_RATING,CONFIRM_TIME\n\
+        ursula@example.com,Ursula,Le Guin,2,2023-01-01 10:00:00\n";
+    let unsubscribed = "Email Address,First Name,Last Name,MEMBER_RATING,UNSUB_TIME\n\
+        terry@example.com,Terry,Pratchett,2,2023-02-01 10:00:00\n";
+    let cleaned = "Email Address,First Name,Last Name,MEMBER_RATING,CLEAN_TIME\n\
+        octavia@example.com,,,1,2023-03-01 10:00:00\n";
+
+    for csv in [subscribed, unsubscribed, cleaned] {
+        let resp = app
+            .post_import(csv.into(), "?format=mailchimp&status=confirmed")
+            .await;
+        assert_eq!(resp.status().as_u16(), 200);
+    }
+
+    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    let saved: Vec<_> = saved
+        .iter()
+        .map(|s| (s.email.as_str(), s.name.as_str(), s.status.as_str()))
+        .collect();
+    assert_eq!(
+        saved,
+        vec![
+            ("octavia@example.com", "octavia", "bounced"),
+            ("terry@example.com", "Terry Pratchett", "unsubscribed"),
+            ("ursula@example.com", "Ursula Le Guin", "confirmed"),
+        ]
+    );
+
+    let suppressed = sqlx::query!("SELECT email, reason FROM email_suppressions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not fetch suppressions from db");
+    assert_eq!(suppressed.len(), 2);
+    assert_eq!(suppressed[0].reason, "bounced");
+    assert_eq!(suppressed[1].reason, "unsubscribed");
+}
+
+#[tokio::test]
+async fn import_suppresses_addresses_already_subscribed_and_they_get_no_more_emails() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let csv = "Email Address,First Name,Last Name,MEMBER_RATING,UNSUB_TIME\n\
+        Ursula@Example.com,Ursula,Le Guin,2,2023-02-01 10:00:00\n";
+
+    let resp = app
+        .post_import(csv.into(), "?format=mailchimp&status=confirmed")
+        .await;
+
+    let report: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(report["imported"], 0);
+    assert_eq!(report["suppressed"], 1);
+    assert_eq!(report["rejected"].as_array().unwrap().len(), 1);
+
+    // The confirmation email queued for the resend is given up on instead of sent
+    app.post_resend_confirmation("ursula@example.com").await;
+    for _ in 0..50 {
+        let given_up = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM email_outbox WHERE last_error LIKE '%is suppressed'"
+        )
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+        if given_up == Some(1) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("The email to the suppressed address was not given up on");
+}
+
+#[tokio::test]
+async fn import_only_sends_confirmation_emails_to_subscribed_substack_readers() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let csv = "email,active_subscription,expiry,plan,email_disabled,created_at\n\
+        ursula@example.com,false,,,false,2023-01-01T10:00:00.000Z\n\
+        terry@example.com,false,,,true,2023-01-02T10:00:00.000Z\n";
+
+    let resp = app.post_import(csv.into(), "?format=substack").await;
+
+    let report: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(report["imported"], 2);
+    assert_eq!(report["suppressed"], 1);
+    app.wait_for_emails(1).await;
+
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_req.body).unwrap();
+    assert_eq!(body["To"], "ursula@example.com");
+}
+
+#[tokio::test]
+async fn import_treats_case_and_plus_alias_variants_as_duplicates() {
+    let app =
+        spawn_app_with(|c| c.email_policy.plus_alias_domains = vec!["gmail.com".into()]).await;
+    let csv = "name,email\n\
+        Ursula Le Guin,Ursula@Example.com\n\
+        Ursula Again,ursula@EXAMPLE.com\n\
+        Octavia Butler,octavia@gmail.com\n\
+        Octavia Again,Octavia+news@gmail.com\n";
+
+    let resp = app.post_import(csv.into(), "?status=confirmed").await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let report: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(report["imported"], 2);
+    assert_eq!(report["rejected"].as_array().unwrap().len(), 2);
+
+    let saved =
+        sqlx::query!("SELECT email, email_normalized FROM subscriptions ORDER BY email_normalized")
+            .fetch_all(&app.db_pool)
+            .await
+            .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved[0].email, "octavia@gmail.com");
+    assert_eq!(saved[1].email, "Ursula@example.com");
+    assert_eq!(saved[1].email_normalized, "ursula@example.com");
+}
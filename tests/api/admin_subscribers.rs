@@ -0,0 +1,222 @@
+use chrono::{TimeZone, Utc};
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, TestApp};
+
+async fn insert_subscriber(app: &TestApp, email: &str, status: &str, day: u32) {
+    sqlx::query!(
+        r#"
+    INSERT INTO subscriptions (id, email, email_normalized, name, subscribed_at, status)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    "#,
+        Uuid::new_v4(),
+        email,
+        email.to_lowercase(),
+        "A subscriber",
+        Utc.with_ymd_and_hms(2023, 1, day, 12, 0, 0).unwrap(),
+        status
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert subscriber");
+}
+
+async fn seed(app: &TestApp) {
+    insert_subscriber(app, "ursula@example.com", "confirmed", 1).await;
+    insert_subscriber(app, "terry@example.com", "pending_confirmation", 2).await;
+    insert_subscriber(app, "octavia@example.org", "confirmed", 3).await;
+}
+
+#[tokio::test]
+async fn admin_subscriber_endpoints_require_the_admin_token() {
+    let app = spawn_app().await;
+
+    let detail = format!("/admin/subscribers/{}", Uuid::new_v4());
+    for path in ["/admin/subscribers", "/admin/subscribers/export", &detail] {
+        let resp = reqwest::get(format!("{}{}", &app.address, path))
+            .await
+            .unwrap();
+        assert_eq!(resp.status().as_u16(), 401, "{} is not protected", path);
+    }
+}
+
+#[tokio::test]
+async fn list_returns_subscribers_matching_the_filters() {
+    let app = spawn_app().await;
+    seed(&app).await;
+
+    let test_cases = vec![
+        (
+            "",
+            vec![
+                "ursula@example.com",
+                "terry@example.com",
+                "octavia@example.org",
+            ],
+        ),
+        (
+            "?status=confirmed",
+            vec!["ursula@example.com", "octavia@example.org"],
+        ),
+        ("?email=EXAMPLE.ORG", vec!["octavia@example.org"]),
+        (
+            "?subscribed_after=2023-01-02T00:00:00Z&subscribed_before=2023-01-03T00:00:00Z",
+            vec!["terry@example.com"],
+        ),
+        ("?limit=1&offset=1", vec!["terry@example.com"]),
+        ("?email=%25", vec![]),
+    ];
+
+    for (query, expected) in test_cases {
+        let resp = app.get_admin(&format!("/admin/subscribers{}", query)).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let emails: Vec<_> = body["subscribers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["email"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(emails, expected, "Unexpected subscribers for '{}'", query);
+    }
+}
+
+#[tokio::test]
+async fn list_rejects_unknown_statuses() {
+    let app = spawn_app().await;
+
+    let resp = app.get_admin("/admin/subscribers?status=maybe").await;
+
+    assert_eq!(resp.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn export_streams_subscribers_as_csv() {
+    let app = spawn_app().await;
+    seed(&app).await;
+
+    let resp = app
+        .get_admin("/admin/subscribers/export?status=confirmed")
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["Content-Type"], "text/csv; charset=utf-8");
+    let body = resp.text().await.unwrap();
+    let mut reader = csv::Reader::from_reader(body.as_bytes());
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec![
+            "id",
+            "email",
+            "name",
+            "status",
+            "subscribed_at",
+            "consent_source",
+            "consent_text_version",
+            "signup_ip",
+            "signup_user_agent",
+            "signed_up_at",
+            "confirmation_ip",
+            "confirmation_user_agent",
+            "confirmed_at",
+        ]
+    );
+    let emails: Vec<_> = reader.records().map(|r| r.unwrap()[1].to_owned()).collect();
+    assert_eq!(emails, vec!["ursula@example.com", "octavia@example.org"]);
+}
+
+#[tokio::test]
+async fn export_streams_subscribers_as_json_lines() {
+    let app = spawn_app().await;
+    seed(&app).await;
+
+    let resp = app
+        .get_admin("/admin/subscribers/export?format=jsonl&email=terry")
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["Content-Type"], "application/x-ndjson");
+    let body = resp.text().await.unwrap();
+    let rows: Vec<serde_json::Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["email"], "terry@example.com");
+    assert_eq!(rows[0]["status"], "pending_confirmation");
+}
+
+#[tokio::test]
+async fn consent_is_recorded_at_signup_and_confirmation() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("User-Agent", "Signup Browser/1.0")
+        .body("name=le%20guin&email=ursula%40example.com&source=footer-form")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.wait_for_emails(1).await;
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_req);
+    reqwest::Client::new()
+        .get(confirmation_links.html)
+        .header("User-Agent", "Mail Client/2.0")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let resp = app
+        .get_admin(&format!("/admin/subscribers/{}", subscriber.id))
+        .await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let detail: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(detail["email"], "ursula@example.com");
+    assert_eq!(detail["status"], "confirmed");
+    assert_eq!(detail["consent_source"], "footer-form");
+    assert_eq!(detail["consent_text_version"], "2023-07-01");
+    assert_eq!(detail["signup_ip"], "127.0.0.1");
+    assert_eq!(detail["signup_user_agent"], "Signup Browser/1.0");
+    assert!(detail["signed_up_at"].is_string());
+    assert_eq!(detail["confirmation_ip"], "127.0.0.1");
+    assert_eq!(detail["confirmation_user_agent"], "Mail Client/2.0");
+    assert!(detail["confirmed_at"].is_string());
+
+    let resp = app
+        .get_admin("/admin/subscribers/export?format=jsonl")
+        .await;
+    let row: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(row["consent_source"], "footer-form");
+    assert_eq!(row["confirmation_user_agent"], "Mail Client/2.0");
+}
+
+#[tokio::test]
+async fn detail_of_an_unknown_subscriber_is_a_404() {
+    let app = spawn_app().await;
+
+    let resp = app
+        .get_admin(&format!("/admin/subscribers/{}", Uuid::new_v4()))
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 404);
+}
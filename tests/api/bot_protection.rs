@@ -0,0 +1,235 @@
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+async fn get_form_token(app: &TestApp) -> String {
+    let body: serde_json::Value = reqwest::get(format!("{}/subscriptions/form_token", app.address))
+        .await
+        .expect("Failed to execute request")
+        .json()
+        .await
+        .unwrap();
+    body["form_token"].as_str().unwrap().to_owned()
+}
+
+async fn assert_nothing_happened(app: &TestApp) {
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_empty());
+}
+
+fn expect_no_email() -> Mock {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+}
+
+#[tokio::test]
+async fn a_filled_honeypot_is_accepted_but_dropped() {
+    let app = spawn_app().await;
+    expect_no_email().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula%40example.com&website=https%3A%2F%2Fspam.example.com"
+                .into(),
+        )
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula@example.com",
+            "website": "https://spam.example.com",
+        }))
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["status"], "pending_confirmation");
+
+    assert_nothing_happened(&app).await;
+}
+
+#[tokio::test]
+async fn a_form_submitted_too_fast_is_accepted_but_dropped() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_secs = 60).await;
+    expect_no_email().mount(&app.email_server).await;
+    let form_token = get_form_token(&app).await;
+
+    let resp = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula%40example.com&form_token={}",
+            form_token
+        ))
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    assert_nothing_happened(&app).await;
+}
+
+#[tokio::test]
+async fn a_forged_form_token_is_accepted_but_dropped() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_secs = 0).await;
+    expect_no_email().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com&form_token=1.abcd".into())
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    assert_nothing_happened(&app).await;
+}
+
+#[tokio::test]
+async fn a_missing_form_token_is_dropped_when_required() {
+    let app = spawn_app_with(|c| c.bot_protection.require_form_token = true).await;
+    expect_no_email().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    assert_nothing_happened(&app).await;
+}
+
+#[tokio::test]
+async fn a_valid_form_token_and_an_empty_honeypot_let_the_signup_through() {
+    let app = spawn_app_with(|c| {
+        c.bot_protection.min_submit_secs = 0;
+        c.bot_protection.require_form_token = true;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let form_token = get_form_token(&app).await;
+
+    let resp = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula%40example.com&website=&form_token={}",
+            form_token
+        ))
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn bots_get_the_usual_answer_instead_of_field_errors() {
+    let app = spawn_app().await;
+    expect_no_email().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions(
+            "name=&email=not-an-email&website=https%3A%2F%2Fspam.example.com".into(),
+        )
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    assert_nothing_happened(&app).await;
+}
+
+#[tokio::test]
+async fn a_form_token_is_good_for_a_single_signup() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_secs = 0).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let form_token = get_form_token(&app).await;
+
+    for email in ["ursula%40example.com", "octavia%40example.com"] {
+        let resp = app
+            .post_subscriptions(format!(
+                "name=le%20guin&email={}&form_token={}",
+                email, form_token
+            ))
+            .await;
+        assert_eq!(200, resp.status().as_u16());
+    }
+
+    let saved = sqlx::query_scalar!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved, ["ursula@example.com"]);
+}
+
+#[tokio::test]
+async fn a_form_token_is_not_spent_by_a_submission_with_field_errors() {
+    let app = spawn_app_with(|c| c.bot_protection.min_submit_secs = 0).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let form_token = get_form_token(&app).await;
+
+    let resp = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula%40&form_token={}",
+            form_token
+        ))
+        .await;
+    assert_eq!(400, resp.status().as_u16());
+    let resp = app
+        .post_subscriptions(format!(
+            "name=le%20guin&email=ursula%40example.com&form_token={}",
+            form_token
+        ))
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+
+    let saved = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved, Some(1));
+}
+
+/// Known gap: until `require_form_token` is on, leaving the token out
+/// skips the time trap, for API clients that don't fetch one.
+#[tokio::test]
+async fn without_a_required_form_token_leaving_it_out_skips_the_time_trap() {
+    let app = spawn_app_with(|c| {
+        c.bot_protection.min_submit_secs = 60;
+        c.bot_protection.require_form_token = false;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    let saved = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved, Some(1));
+}
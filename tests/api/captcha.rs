@@ -0,0 +1,125 @@
+use mailbolt::configuration::CaptchaSettings;
+use secrecy::Secret;
+use wiremock::{
+    matchers::{body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::helpers::spawn_app_with;
+
+fn mock_email_server_call(expected_calls: u64) -> Mock {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(expected_calls)
+}
+
+#[tokio::test]
+async fn subscribe_returns_400_when_the_captcha_is_not_solved() {
+    let app = spawn_app_with(|c| c.captcha = CaptchaSettings::AlwaysFail).await;
+    mock_email_server_call(0).mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula%40example.com&captcha_response=guessed".into(),
+        )
+        .await;
+
+    assert_eq!(400, resp.status().as_u16());
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(problem["invalid-params"][0]["name"], "captcha_response");
+
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_empty());
+}
+
+#[tokio::test]
+async fn subscribe_returns_400_when_the_captcha_response_is_missing() {
+    let app = spawn_app_with(|c| c.captcha = CaptchaSettings::AlwaysPass).await;
+    mock_email_server_call(0).mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(400, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_accepts_a_solved_captcha_under_the_widget_field_name() {
+    let app = spawn_app_with(|c| c.captcha = CaptchaSettings::AlwaysPass).await;
+    mock_email_server_call(1).mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula%40example.com&h-captcha-response=solved".into(),
+        )
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_asks_the_configured_verify_endpoint() {
+    let captcha_server = MockServer::start().await;
+    let verify_url = format!("{}/siteverify", captcha_server.uri());
+    let app = spawn_app_with(|c| {
+        c.captcha = CaptchaSettings::Http {
+            verify_url,
+            secret_key: Secret::new("secret-key".into()),
+            timeout_milliseconds: 1000,
+        }
+    })
+    .await;
+    mock_email_server_call(1).mount(&app.email_server).await;
+    Mock::given(path("/siteverify"))
+        .and(body_string_contains("response=solved"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "success": true,
+        })))
+        .expect(1)
+        .mount(&captcha_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula@example.com",
+            "captcha_response": "solved",
+        }))
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_returns_500_when_the_verify_endpoint_is_down() {
+    let captcha_server = MockServer::start().await;
+    let verify_url = format!("{}/siteverify", captcha_server.uri());
+    let app = spawn_app_with(|c| {
+        c.captcha = CaptchaSettings::Http {
+            verify_url,
+            secret_key: Secret::new("secret-key".into()),
+            timeout_milliseconds: 1000,
+        }
+    })
+    .await;
+    mock_email_server_call(0).mount(&app.email_server).await;
+    Mock::given(path("/siteverify"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&captcha_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions(
+            "name=le%20guin&email=ursula%40example.com&cf-turnstile-response=solved".into(),
+        )
+        .await;
+
+    assert_eq!(500, resp.status().as_u16());
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+}
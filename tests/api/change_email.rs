@@ -0,0 +1,190 @@
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+async fn post_change_email(app: &TestApp, email: &str, new_email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions/change-email", &app.address))
+        .json(&serde_json::json!({ "email": email, "new_email": new_email }))
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+/// Subscribe `email` and follow the confirmation link.
+async fn confirmed_subscriber(app: &TestApp, email: &str) {
+    app.post_subscriptions_json(serde_json::json!({ "name": "le guin", "email": email }))
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(email_requests.last().unwrap());
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+fn recipient(email_req: &wiremock::Request) -> String {
+    let body: serde_json::Value = serde_json::from_slice(&email_req.body).unwrap();
+    body["To"].as_str().unwrap().to_owned()
+}
+
+async fn saved_email(app: &TestApp) -> String {
+    sqlx::query_scalar!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn following_the_links_sent_to_both_addresses_moves_the_subscription() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    confirmed_subscriber(&app, "ursula@example.com").await;
+
+    let resp = post_change_email(&app, "Ursula@Example.com", "ursula@newjob.example.com").await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    // The current address approves first
+    app.wait_for_emails(2).await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(recipient(&email_requests[1]), "ursula@example.com");
+    let approval_link = app.get_confirmation_links(&email_requests[1]).html;
+    let resp = reqwest::get(approval_link.clone()).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    assert!(resp.text().await.unwrap().contains("Check your new inbox"));
+
+    // Nothing changes before the new address is confirmed
+    assert_eq!(saved_email(&app).await, "ursula@example.com");
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(recipient(&email_requests[2]), "ursula@newjob.example.com");
+    let links = app.get_confirmation_links(&email_requests[2]);
+    let resp = reqwest::get(links.html.clone()).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+
+    let saved = sqlx::query!("SELECT email, email_normalized, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "ursula@newjob.example.com");
+    assert_eq!(saved.email_normalized, "ursula@newjob.example.com");
+    assert_eq!(saved.status, "confirmed");
+
+    // The old address hears about it
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 4);
+    assert_eq!(recipient(&email_requests[3]), "ursula@example.com");
+
+    let last_event =
+        sqlx::query!("SELECT kind, actor FROM subscription_events ORDER BY id DESC LIMIT 1")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(last_event.kind, "email_changed");
+    assert_eq!(last_event.actor, "subscriber");
+    // Webhook receivers can tell which subscriber moved
+    let payload = sqlx::query_scalar!(
+        "SELECT payload FROM webhook_events WHERE event_kind = 'email_changed'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+    assert_eq!(payload["previous_email"], "ursula@example.com");
+    assert_eq!(payload["subscriber"]["email"], "ursula@newjob.example.com");
+
+    // Links work once
+    let resp = reqwest::get(links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+    let resp = reqwest::get(approval_link).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_stranger_knowing_the_address_cannot_move_the_subscription() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    confirmed_subscriber(&app, "ursula@example.com").await;
+
+    post_change_email(&app, "ursula@example.com", "mallory@example.net").await;
+
+    // Only the subscriber hears of it
+    app.wait_for_background_tasks().await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    assert_eq!(recipient(&email_requests[1]), "ursula@example.com");
+
+    // Even with the token meant for the new address, there is nothing to confirm
+    let token = sqlx::query_scalar!("SELECT token FROM email_change_requests")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let resp = reqwest::get(format!(
+        "{}/subscriptions/change-email/confirm?token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+    assert_eq!(saved_email(&app).await, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn unknown_addresses_and_subscribed_new_addresses_get_no_email() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    confirmed_subscriber(&app, "ursula@example.com").await;
+    confirmed_subscriber(&app, "octavia@example.com").await;
+
+    for (email, new_email) in [
+        ("terry@example.com", "terry@newjob.example.com"),
+        ("ursula@example.com", "octavia@example.com"),
+    ] {
+        let resp = post_change_email(&app, email, new_email).await;
+        assert_eq!(resp.status().as_u16(), 200);
+    }
+
+    // Emails are sent in the background, once it's done nothing else goes out
+    app.wait_for_background_tasks().await;
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn invalid_or_unchanged_addresses_are_rejected() {
+    let app = spawn_app().await;
+
+    for (email, new_email, field) in [
+        ("ursula@example.com", "not-an-email", "new_email"),
+        ("not-an-email", "ursula@newjob.example.com", "email"),
+        ("ursula@example.com", "Ursula@Example.com", "new_email"),
+    ] {
+        let resp = post_change_email(&app, email, new_email).await;
+
+        assert_eq!(resp.status().as_u16(), 400);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["invalid-params"][0]["name"], field);
+    }
+}
+
+#[tokio::test]
+async fn expired_email_change_links_leave_the_address_alone() {
+    let app = spawn_app_with(|c| c.confirmation.token_ttl_hours = 0).await;
+    app.mock_email_server().await;
+    // Confirmation links expire right away too, so confirm by hand
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    sqlx::query!("UPDATE subscriptions SET status = 'confirmed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    post_change_email(&app, "ursula@example.com", "ursula@newjob.example.com").await;
+    app.wait_for_emails(2).await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let approval_link = app.get_confirmation_links(&email_requests[1]).html;
+    let resp = reqwest::get(approval_link).await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 410);
+    assert_eq!(saved_email(&app).await, "ursula@example.com");
+}
@@ -1,9 +1,11 @@
+use mailbolt::{configuration::Settings, routes::ConfirmationTokenMode, signed_tokens::SigningKey};
+use secrecy::Secret;
 use wiremock::{
     matchers::{method, path},
     Mock, ResponseTemplate,
 };
 
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected() {
@@ -69,3 +71,341 @@ async fn clicking_on_confirmation_link_confirms_subscription() {
     assert_eq!(saved.name, "james bond");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn following_the_confirmation_link_twice_is_fine() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_req);
+
+    for _ in 0..2 {
+        let resp = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+    }
+}
+
+#[tokio::test]
+async fn unsubscribed_people_cannot_be_confirmed_by_an_old_link() {
+    let app = spawn_app().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_req);
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let resp = reqwest::get(confirmation_links.html).await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 409);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unsubscribed_people_can_subscribe_again_by_confirming_their_address() {
+    let app = spawn_app().await;
+    let old_link = confirmation_link(&app).await;
+    reqwest::get(old_link.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+    // Links from before they left are gone
+    let resp = reqwest::get(old_link).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    reqwest::get(new_link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_again_with_a_confirmed_address_sends_nothing() {
+    let app = spawn_app().await;
+    let link = confirmation_link(&app).await;
+    reqwest::get(link)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let queued = sqlx::query_scalar!("SELECT COUNT(*) FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued, Some(1));
+}
+
+#[tokio::test]
+async fn the_database_refuses_unknown_statuses() {
+    let app = spawn_app().await;
+
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO subscriptions (id, email, email_normalized, name, subscribed_at, status)
+    VALUES (gen_random_uuid(), 'ursula@example.com', 'ursula@example.com', 'le guin', now(), 'maybe')
+    "#
+    )
+    .execute(&app.db_pool)
+    .await;
+
+    assert!(result.is_err());
+}
+
+/// Subscribe ursula@example.com and return the link of her confirmation email.
+async fn confirmation_link(app: &TestApp) -> reqwest::Url {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    app.get_confirmation_links(email_req).html
+}
+
+#[tokio::test]
+async fn browsers_get_a_page_for_every_outcome() {
+    let app = spawn_app().await;
+    let link = confirmation_link(&app).await;
+    let mut invalid_link = link.clone();
+    invalid_link.set_query(Some("subscription_token=not-a-token"));
+
+    let test_cases = [
+        (link.clone(), 200, "You're subscribed!"),
+        (link, 200, "You're already subscribed"),
+        (invalid_link, 401, "This link is not valid"),
+    ];
+    for (link, status, title) in test_cases {
+        let resp = reqwest::Client::new()
+            .get(link)
+            .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status().as_u16(), status);
+        assert_eq!(resp.headers()["Content-Type"], "text/html; charset=utf-8");
+        assert!(resp.text().await.unwrap().contains(title));
+    }
+}
+
+#[tokio::test]
+async fn expired_links_do_not_confirm() {
+    let app = spawn_app_with(|c| c.confirmation.token_ttl_hours = 24).await;
+    let link = confirmation_link(&app).await;
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '25 hours'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let resp = reqwest::get(link).await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 410);
+    assert!(resp.text().await.unwrap().contains("This link has expired"));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+
+    // Subscribing again, as the page says, gets them a link that works
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    let resp = reqwest::get(new_link).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn api_clients_get_bare_status_codes() {
+    let app = spawn_app().await;
+    let link = confirmation_link(&app).await;
+    let mut invalid_link = link.clone();
+    invalid_link.set_query(Some("subscription_token=not-a-token"));
+
+    for (link, status) in [(link, 200), (invalid_link, 401)] {
+        let resp = reqwest::Client::new()
+            .get(link)
+            .header("Accept", "application/json")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status().as_u16(), status);
+        assert!(resp.text().await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn browsers_are_redirected_to_the_configured_page() {
+    let app = spawn_app_with(|c| {
+        c.confirmation.redirect_url = Some("https://example.com/welcome?lang=en".into())
+    })
+    .await;
+    let link = confirmation_link(&app).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let resp = client.get(link.clone()).send().await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 303);
+    assert_eq!(
+        resp.headers()["Location"],
+        "https://example.com/welcome?lang=en&status=confirmed"
+    );
+
+    let resp = client
+        .get(link)
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+fn use_signed_tokens(c: &mut Settings) {
+    c.confirmation.token_mode = ConfirmationTokenMode::Signed;
+    c.confirmation.signing_keys = vec![SigningKey {
+        id: "2023-08".into(),
+        secret: Secret::new("a-signing-secret-of-at-least-32-bytes".into()),
+    }];
+}
+
+fn token_of(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(name, _)| name == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned()
+}
+
+#[tokio::test]
+async fn signed_confirmation_links_confirm_without_any_stored_token() {
+    let app = spawn_app_with(use_signed_tokens).await;
+    let link = confirmation_link(&app).await;
+
+    assert!(token_of(&link).starts_with("2023-08."));
+    let stored = sqlx::query_scalar!("SELECT COUNT(*) FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(stored, Some(0));
+
+    let resp = reqwest::get(link.clone()).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+
+    let resp = reqwest::get(link).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn tampered_signed_confirmation_links_are_rejected() {
+    let app = spawn_app_with(use_signed_tokens).await;
+    let mut link = confirmation_link(&app).await;
+    let token = token_of(&link);
+    let (payload, _) = token.rsplit_once('.').unwrap();
+    let forged = format!("{}.{}", payload, "00".repeat(32));
+    link.set_query(Some(&format!("subscription_token={}", forged)));
+
+    let resp = reqwest::get(link).await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn expired_signed_confirmation_links_are_refused() {
+    let app = spawn_app_with(|c| {
+        use_signed_tokens(c);
+        c.confirmation.token_ttl_hours = 0;
+    })
+    .await;
+    let link = confirmation_link(&app).await;
+
+    let resp = reqwest::get(link).await.unwrap();
+
+    assert_eq!(resp.status().as_u16(), 410);
+}
+
+#[tokio::test]
+async fn signed_links_sent_before_a_resend_stop_working() {
+    let app = spawn_app_with(use_signed_tokens).await;
+    let old_link = confirmation_link(&app).await;
+
+    app.post_resend_confirmation("ursula@example.com")
+        .await
+        .error_for_status()
+        .unwrap();
+    app.wait_for_emails(2).await;
+
+    let resp = reqwest::get(old_link).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    let resp = reqwest::get(new_link).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+}
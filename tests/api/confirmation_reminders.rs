@@ -0,0 +1,223 @@
+use mailbolt::confirmation_reminders::send_confirmation_reminders;
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+async fn send_reminders(app: &TestApp) -> u64 {
+    send_confirmation_reminders(
+        &app.db_pool,
+        &app.config.email_client.clone().client(),
+        &app.config.application.base_url,
+        &app.config.reminders.policy(),
+        &app.config.confirmation.policy().unwrap(),
+        &app.config.email_outbox.policy(),
+    )
+    .await
+    .expect("Failed to send confirmation reminders")
+}
+
+/// Make everything that happened so far look like it happened `hours` earlier.
+async fn travel_forward(app: &TestApp, hours: i32) {
+    let interval = format!("{} hours", hours);
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET subscribed_at = subscribed_at - $1::text::interval,
+        pending_since = pending_since - $1::text::interval,
+        last_confirmation_sent_at = last_confirmation_sent_at - $1::text::interval
+    "#,
+        interval
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn pending_subscribers_get_a_reminder_with_another_link_once_the_delay_passed() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let old_links = app.get_confirmation_links(&email_requests[0]);
+
+    // Too early
+    assert_eq!(send_reminders(&app).await, 0);
+
+    travel_forward(&app, app.config.reminders.delay_hours as i32).await;
+    assert_eq!(send_reminders(&app).await, 1);
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let new_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(new_links.html, old_links.html);
+    let resp = reqwest::get(new_links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+
+    let saved = sqlx::query!("SELECT status, confirmation_emails_sent FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    assert_eq!(saved.confirmation_emails_sent, 2);
+}
+
+#[tokio::test]
+async fn links_sent_before_a_reminder_keep_working() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]);
+
+    travel_forward(&app, app.config.reminders.delay_hours as i32).await;
+    assert_eq!(send_reminders(&app).await, 1);
+
+    let resp = reqwest::get(first_links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn a_resend_counts_as_a_reminder_and_restarts_the_delay() {
+    let app = spawn_app_with(|c| c.reminders.max_reminders = 2).await;
+    app.mock_email_server().await;
+    let delay = app.config.reminders.delay_hours as i32;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    travel_forward(&app, delay).await;
+    app.post_resend_confirmation("ursula@example.com").await;
+    app.wait_for_emails(2).await;
+
+    // The resend was just now
+    assert_eq!(send_reminders(&app).await, 0);
+    travel_forward(&app, delay).await;
+    assert_eq!(send_reminders(&app).await, 1);
+    // The first email, the resend and the reminder use up both reminders
+    travel_forward(&app, delay).await;
+    assert_eq!(send_reminders(&app).await, 0);
+}
+
+#[tokio::test]
+async fn signing_up_again_after_leaving_starts_the_reminders_over() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    let delay = app.config.reminders.delay_hours as i32;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    for _ in 0..app.config.reminders.max_reminders {
+        travel_forward(&app, delay).await;
+        assert_eq!(send_reminders(&app).await, 1);
+    }
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    travel_forward(&app, delay).await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    // The confirmation email of the new signup was just now
+    assert_eq!(send_reminders(&app).await, 0);
+    travel_forward(&app, delay).await;
+    assert_eq!(send_reminders(&app).await, 1);
+}
+
+#[tokio::test]
+async fn no_one_gets_more_than_the_maximum_number_of_reminders() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    let delay = app.config.reminders.delay_hours as i32;
+    for _ in 0..app.config.reminders.max_reminders {
+        travel_forward(&app, delay).await;
+        assert_eq!(send_reminders(&app).await, 1);
+        // The next one waits for the delay again
+        assert_eq!(send_reminders(&app).await, 0);
+    }
+    travel_forward(&app, delay).await;
+    assert_eq!(send_reminders(&app).await, 0);
+
+    let saved = sqlx::query!("SELECT confirmation_emails_sent FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        saved.confirmation_emails_sent,
+        app.config.reminders.max_reminders + 1
+    );
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_are_not_reminded() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(&email_requests[0]);
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    travel_forward(&app, app.config.reminders.delay_hours as i32).await;
+    assert_eq!(send_reminders(&app).await, 0);
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn reminders_show_up_in_the_subscriber_history() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    travel_forward(&app, app.config.reminders.delay_hours as i32).await;
+    send_reminders(&app).await;
+
+    let sent = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM subscription_events WHERE kind = 'confirmation_sent'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(sent, Some(2));
+}
+
+#[tokio::test]
+async fn a_subscriber_with_an_invalid_stored_address_does_not_stop_the_batch() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    // Stored before the address rules got stricter
+    sqlx::query!("UPDATE subscriptions SET email = 'ursula at example.com'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.post_subscriptions("name=butler&email=octavia%40example.com".into())
+        .await;
+
+    travel_forward(&app, app.config.reminders.delay_hours as i32).await;
+    assert_eq!(send_reminders(&app).await, 1);
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let reminder: serde_json::Value = serde_json::from_slice(&email_requests[2].body).unwrap();
+    assert_eq!(reminder["To"], "octavia@example.com");
+}
@@ -0,0 +1,74 @@
+use mailbolt::email_normalization::normalize_stored_emails;
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+/// Normalize with `gmail.com` as a plus alias domain, whatever the app uses.
+async fn normalize(app: &TestApp) -> serde_json::Value {
+    let mut settings = app.config.email_policy.clone();
+    settings.plus_alias_domains = vec!["gmail.com".into()];
+    let report = normalize_stored_emails(&app.db_pool, &settings.policy().unwrap())
+        .await
+        .expect("Failed to normalize stored emails");
+    serde_json::to_value(report).unwrap()
+}
+
+#[tokio::test]
+async fn stored_addresses_get_the_normalized_form_signups_use() {
+    let app =
+        spawn_app_with(|c| c.email_policy.plus_alias_domains = vec!["gmail.com".into()]).await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=Ursula%2Bnews%40gmail.com".into())
+        .await;
+    app.post_subscriptions("name=butler&email=octavia%40example.com".into())
+        .await;
+    sqlx::query!("UPDATE subscriptions SET email_normalized = lower(email)")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let report = normalize(&app).await;
+
+    assert_eq!(report["updated"], 1);
+    let saved =
+        sqlx::query_scalar!("SELECT email_normalized FROM subscriptions ORDER BY email_normalized")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(saved, ["octavia@example.com", "ursula@gmail.com"]);
+
+    // An alias of the same mailbox is now the same subscriber
+    app.post_subscriptions("name=le%20guin&email=ursula%2Bother%40gmail.com".into())
+        .await;
+    let count = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(count, Some(2));
+}
+
+#[tokio::test]
+async fn addresses_that_would_become_duplicates_are_reported_and_left_alone() {
+    // `gmail.com` isn't one of the plus alias domains yet
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula%2Bnews%40gmail.com".into())
+        .await;
+
+    let report = normalize(&app).await;
+
+    assert_eq!(report["updated"], 0);
+    assert_eq!(
+        report["conflicts"],
+        serde_json::json!(["ursula+news@gmail.com"])
+    );
+    let saved =
+        sqlx::query_scalar!("SELECT email_normalized FROM subscriptions ORDER BY email_normalized")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(saved, ["ursula+news@gmail.com", "ursula@gmail.com"]);
+}
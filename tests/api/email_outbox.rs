@@ -0,0 +1,238 @@
+use std::time::Duration;
+
+use mailbolt::{
+    confirmation_reminders::send_confirmation_reminders, email_outbox::dispatch_outbox,
+};
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+async fn dispatch(app: &TestApp) -> u64 {
+    dispatch_outbox(
+        &app.db_pool,
+        &app.config.email_client.clone().client(),
+        &app.config.email_outbox.policy(),
+    )
+    .await
+    .expect("Failed to dispatch the email outbox")
+}
+
+/// Make failed emails due for their next attempt.
+async fn skip_retry_delays(app: &TestApp) {
+    sqlx::query!("UPDATE email_outbox SET next_attempt_at = now() WHERE sent_at IS NULL")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn signups_get_their_confirmation_email_straight_from_the_outbox() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    let outbox = sqlx::query!("SELECT attempts, sent_at FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.attempts, 1);
+    assert!(outbox.sent_at.is_some());
+    // Nothing is left for the dispatcher
+    assert_eq!(dispatch(&app).await, 0);
+}
+
+#[tokio::test]
+async fn the_dispatcher_retries_emails_that_could_not_be_sent() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    // The retry waits for its delay
+    assert_eq!(dispatch(&app).await, 0);
+    skip_retry_delays(&app).await;
+    assert_eq!(dispatch(&app).await, 1);
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let links = app.get_confirmation_links(&email_requests[1]);
+    let resp = reqwest::get(links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+
+    let sent = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM subscription_events WHERE kind = 'confirmation_sent'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(sent, Some(1));
+}
+
+#[tokio::test]
+async fn the_dispatcher_gives_up_after_the_maximum_number_of_attempts() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let max_attempts = app.config.email_outbox.max_attempts;
+    for _ in 1..max_attempts {
+        skip_retry_delays(&app).await;
+        assert_eq!(dispatch(&app).await, 0);
+    }
+    skip_retry_delays(&app).await;
+    dispatch(&app).await;
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), max_attempts as usize);
+    let outbox = sqlx::query!("SELECT attempts, sent_at FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.attempts, max_attempts);
+    assert!(outbox.sent_at.is_none());
+}
+
+#[tokio::test]
+async fn emails_being_sent_are_claimed_without_holding_a_lock() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let signup = app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into());
+    let while_sending = async {
+        app.wait_for_emails(1).await;
+        // The claim is committed: other connections see it and don't wait for the send
+        let outbox = sqlx::query!("SELECT attempts, sent_at FROM email_outbox FOR UPDATE NOWAIT")
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("The outbox email is locked while it is sent");
+        assert_eq!(outbox.attempts, 1);
+        assert!(outbox.sent_at.is_none());
+        // Nor does the dispatcher pick it up again
+        assert_eq!(dispatch(&app).await, 0);
+    };
+    tokio::join!(signup, while_sending);
+
+    let sent_at = sqlx::query_scalar!("SELECT sent_at FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(sent_at.is_some());
+}
+
+#[tokio::test]
+async fn every_email_goes_through_the_outbox_and_is_recorded_by_kind() {
+    // The resend counts as one of the reminders
+    let app = spawn_app_with(|c| c.reminders.max_reminders = 2).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_import("email,name\nursula@example.com,Ursula".into(), "")
+        .await
+        .error_for_status()
+        .unwrap();
+    app.wait_for_emails(1).await;
+    app.post_resend_confirmation("ursula@example.com").await;
+    app.wait_for_emails(2).await;
+    sqlx::query!(
+        "UPDATE subscriptions SET last_confirmation_sent_at = last_confirmation_sent_at - interval '1 year'"
+    )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    send_confirmation_reminders(
+        &app.db_pool,
+        &app.config.email_client.clone().client(),
+        &app.config.application.base_url,
+        &app.config.reminders.policy(),
+        &app.config.confirmation.policy().unwrap(),
+        &app.config.email_outbox.policy(),
+    )
+    .await
+    .unwrap();
+
+    let kinds = sqlx::query_scalar!(
+        "SELECT kind FROM email_outbox WHERE sent_at IS NOT NULL ORDER BY created_at"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+        kinds,
+        ["confirmation", "confirmation", "confirmation_reminder"]
+    );
+    let sent = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM subscription_events WHERE kind = 'confirmation_sent'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(sent, Some(3));
+}
+
+#[tokio::test]
+async fn welcome_emails_go_through_the_outbox_without_counting_as_confirmations() {
+    let app = spawn_app_with(|c| c.confirmation.double_opt_in = false).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    skip_retry_delays(&app).await;
+    assert_eq!(dispatch(&app).await, 1);
+
+    let kind = sqlx::query_scalar!("SELECT kind FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(kind, "welcome");
+    let sent = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM subscription_events WHERE kind = 'confirmation_sent'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(sent, Some(0));
+}
@@ -1,18 +1,30 @@
+use std::time::Duration;
+
 use once_cell::sync::Lazy;
 use reqwest::Url;
+use secrecy::ExposeSecret;
 use uuid::Uuid;
 
 use mailbolt::{
-    configuration::{get_configuration, DatabaseSettings},
+    background_tasks::BackgroundTasks,
+    configuration::{get_configuration, DatabaseSettings, Settings},
     startup::{get_db_conn_pool, Application},
     telemetry::{get_subscriber, init_subscriber},
 };
 use sqlx::{Connection, Executor, PgConnection, PgPool};
-use wiremock::MockServer;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
 
 // Spawn our web server in the background so we can execute
 // the web server and our tests concurrently.
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Same as `spawn_app`, letting a test tweak the configuration first.
+pub async fn spawn_app_with(customize: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time `initialize` is invoked, the code in `TRACING` is executed.
     // All other invocations will instead skip execution. It memoises this call.
     Lazy::force(&TRACING);
@@ -26,6 +38,7 @@ pub async fn spawn_app() -> TestApp {
         c.application.port = 0;
         // Overwrite email server endpoint so we can intercept and mock responses
         c.email_client.base_url = email_server.uri();
+        customize(&mut c);
         c
     };
 
@@ -37,14 +50,18 @@ pub async fn spawn_app() -> TestApp {
         .expect("Could not build application");
 
     let app_port = app.port();
+    let background_tasks = app.background_tasks();
     let address = format!("http://127.0.0.1:{}", app_port);
-    let _ = tokio::spawn(app.run_until_stopped());
+    tokio::spawn(app.run_until_stopped());
 
     TestApp {
         address,
         port: app_port,
         db_pool: get_db_conn_pool(&config.database),
         email_server,
+        admin_api_token: config.admin.api_token.expose_secret().clone(),
+        background_tasks,
+        config,
     }
 }
 
@@ -103,19 +120,100 @@ pub struct TestApp {
     pub db_pool: PgPool,
     /// Intercept and mock email provider APIs
     pub email_server: MockServer,
+    /// Token accepted by the `/admin` endpoints
+    pub admin_api_token: String,
+    /// What request handlers left running once they responded
+    pub background_tasks: BackgroundTasks,
+    /// Configuration the app was built with, for tests running jobs themselves.
+    pub config: Settings,
 }
 
 impl TestApp {
+    /// Signups send their email in the background; this waits for it to be sent.
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
-        reqwest::Client::new()
+        let response = reqwest::Client::new()
             .post(format!("{}/subscriptions", &self.address))
             .header("Content-type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
+            .expect("Failed to execute request");
+        self.wait_for_background_tasks().await;
+        response
+    }
+
+    pub async fn post_subscriptions_json(&self, body: serde_json::Value) -> reqwest::Response {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request");
+        self.wait_for_background_tasks().await;
+        response
+    }
+
+    pub async fn post_resend_confirmation(&self, email: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/subscriptions/resend-confirmation",
+                &self.address
+            ))
+            .json(&serde_json::json!({ "email": email }))
+            .send()
+            .await
             .expect("Failed to execute request")
     }
 
+    pub async fn post_import(&self, csv: String, query: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/admin/subscribers/import{}",
+                &self.address, query
+            ))
+            .bearer_auth(&self.admin_api_token)
+            .header("Content-type", "text/csv")
+            .body(csv)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", &self.address, path))
+            .bearer_auth(&self.admin_api_token)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Accept every email the app sends.
+    pub async fn mock_email_server(&self) {
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&self.email_server)
+            .await;
+    }
+
+    /// Wait for whatever request handlers left running in the background,
+    /// e.g. to check that it sent no email.
+    pub async fn wait_for_background_tasks(&self) {
+        self.background_tasks.wait_until_idle().await;
+    }
+
+    /// Wait for emails sent in the background, once responses went out.
+    pub async fn wait_for_emails(&self, count: usize) {
+        for _ in 0..50 {
+            if self.email_server.received_requests().await.unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("Expected {} emails to be sent", count);
+    }
+
     pub fn get_confirmation_links(&self, email_req: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_req.body).unwrap();
 
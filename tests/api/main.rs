@@ -1,4 +1,17 @@
+mod admin_import_subscribers;
+mod admin_subscribers;
+mod bot_protection;
+mod captcha;
+mod change_email;
 mod confirm_subscriptions;
+mod confirmation_reminders;
+mod email_normalization;
+mod email_outbox;
 mod health_check;
 mod helpers;
+mod rate_limiting;
+mod resend_confirmation;
+mod retention;
+mod subscriber_history;
 mod subscriptions;
+mod webhooks;
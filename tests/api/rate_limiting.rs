@@ -0,0 +1,92 @@
+use crate::helpers::{spawn_app_with, TestApp};
+
+#[tokio::test]
+async fn signups_over_the_per_ip_limit_get_a_429_with_retry_after() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.signup.per_ip.capacity = 2;
+        c.rate_limit.signup.per_ip.refill_interval_secs = 60;
+    })
+    .await;
+    app.mock_email_server().await;
+
+    for i in 0..2 {
+        let resp = app
+            .post_subscriptions(format!("name=le%20guin&email=ursula{}%40example.com", i))
+            .await;
+        assert_eq!(200, resp.status().as_u16());
+    }
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula2%40example.com".into())
+        .await;
+
+    assert_eq!(429, resp.status().as_u16());
+    assert_eq!(resp.headers()["Retry-After"], "60");
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+}
+
+#[tokio::test]
+async fn signups_over_the_per_email_limit_get_a_429() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.signup.per_email.capacity = 1;
+        c.rate_limit.signup.per_email.refill_interval_secs = 600;
+    })
+    .await;
+    app.mock_email_server().await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+
+    // Changing the case or the format does not get around the limit
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "Ursula@Example.com",
+        }))
+        .await;
+    assert_eq!(429, resp.status().as_u16());
+    assert_eq!(resp.headers()["Retry-After"], "600");
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=terry%40example.com".into())
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn forwarded_for_is_only_trusted_from_trusted_proxies() {
+    let post_from = |app: &TestApp, client_ip: &str, i: usize| {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("Content-type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", client_ip)
+            .body(format!("name=le%20guin&email=ursula{}%40example.com", i))
+            .send()
+    };
+
+    // Clients can't pick their own IP by sending the header themselves
+    let app = spawn_app_with(|c| c.rate_limit.signup.per_ip.capacity = 1).await;
+    app.mock_email_server().await;
+
+    let resp = post_from(&app, "198.51.100.1", 0).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    let resp = post_from(&app, "198.51.100.2", 1).await.unwrap();
+    assert_eq!(429, resp.status().as_u16());
+
+    // Behind a trusted proxy, every client gets its own limit
+    let app = spawn_app_with(|c| {
+        c.rate_limit.signup.per_ip.capacity = 1;
+        c.rate_limit.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    })
+    .await;
+    app.mock_email_server().await;
+
+    let resp = post_from(&app, "198.51.100.1", 0).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    let resp = post_from(&app, "198.51.100.2", 1).await.unwrap();
+    assert_eq!(200, resp.status().as_u16());
+    let resp = post_from(&app, "198.51.100.1", 2).await.unwrap();
+    assert_eq!(429, resp.status().as_u16());
+}
@@ -0,0 +1,112 @@
+use crate::helpers::{spawn_app, spawn_app_with};
+
+#[tokio::test]
+async fn pending_subscribers_get_a_new_link_and_the_old_one_stops_working() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let old_links = app.get_confirmation_links(&email_requests[0]);
+
+    // Any form of the address finds the subscriber
+    let resp = app.post_resend_confirmation("Ursula@Example.com").await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    app.wait_for_emails(2).await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let new_links = app.get_confirmation_links(&email_requests[1]);
+    assert_ne!(old_links.html, new_links.html);
+
+    let resp = reqwest::get(old_links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+
+    let resp = reqwest::get(new_links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch subscription");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn stored_names_the_name_policy_now_rejects_still_get_a_link() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    // Stored before a stricter name policy came in
+    sqlx::query!("UPDATE subscriptions SET name = 'le <guin>'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let resp = app.post_resend_confirmation("ursula@example.com").await;
+    assert_eq!(resp.status().as_u16(), 200);
+    app.wait_for_emails(2).await;
+}
+
+#[tokio::test]
+async fn unknown_and_confirmed_addresses_get_the_same_answer_and_no_email() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(&email_requests[0]);
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    for email in ["ursula@example.com", "octavia@example.com"] {
+        let resp = app.post_resend_confirmation(email).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.text().await.unwrap(), "");
+    }
+
+    // Emails are sent in the background, once it's done nothing else goes out
+    app.wait_for_background_tasks().await;
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn invalid_addresses_are_rejected() {
+    let app = spawn_app().await;
+
+    let resp = app.post_resend_confirmation("not-an-email").await;
+
+    assert_eq!(resp.status().as_u16(), 400);
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+}
+
+#[tokio::test]
+async fn resends_over_the_per_email_limit_get_a_429() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.resend_confirmation.per_email.capacity = 1;
+        c.rate_limit
+            .resend_confirmation
+            .per_email
+            .refill_interval_secs = 1800;
+    })
+    .await;
+    app.mock_email_server().await;
+
+    let resp = app.post_resend_confirmation("ursula@example.com").await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    let resp = app.post_resend_confirmation("Ursula@Example.com").await;
+    assert_eq!(resp.status().as_u16(), 429);
+    assert_eq!(resp.headers()["Retry-After"], "1800");
+
+    // Signups are limited separately
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(resp.status().as_u16(), 200);
+}
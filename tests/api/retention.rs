@@ -0,0 +1,157 @@
+use mailbolt::retention::purge_stale_pending_subscriptions;
+
+use crate::helpers::{spawn_app, TestApp};
+
+async fn purge(app: &TestApp) -> u64 {
+    purge_stale_pending_subscriptions(
+        &app.db_pool,
+        app.config.retention.pending_max_age(),
+        app.config.retention.batch_size,
+    )
+    .await
+    .expect("Failed to purge stale pending subscriptions")
+}
+
+async fn backdate_signup(app: &TestApp, email: &str, days: i64) {
+    sqlx::query!(
+        "UPDATE subscriptions SET pending_since = pending_since - make_interval(days => $2) WHERE email = $1",
+        email,
+        days as i32
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn subscribers_pending_for_too_long_are_deleted_with_their_tokens() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(&email_requests[0]);
+    backdate_signup(
+        &app,
+        "ursula@example.com",
+        app.config.retention.pending_max_age_days + 1,
+    )
+    .await;
+
+    assert_eq!(purge(&app).await, 1);
+
+    let subscribers = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers, Some(0));
+    let tokens = sqlx::query_scalar!("SELECT COUNT(*) FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens, Some(0));
+    let resp = reqwest::get(links.html).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+
+    // The history outlives them
+    let last_event =
+        sqlx::query!("SELECT kind, actor FROM subscription_events ORDER BY id DESC LIMIT 1")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(last_event.kind, "erased");
+    assert_eq!(last_event.actor, "system");
+
+    // Nothing left to purge
+    assert_eq!(purge(&app).await, 0);
+}
+
+#[tokio::test]
+async fn recent_and_confirmed_subscribers_are_kept() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    app.post_subscriptions("name=butler&email=octavia%40example.com".into())
+        .await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(&email_requests[1]);
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let max_age_days = app.config.retention.pending_max_age_days;
+    backdate_signup(&app, "ursula@example.com", max_age_days - 1).await;
+    backdate_signup(&app, "octavia@example.com", max_age_days + 1).await;
+
+    assert_eq!(purge(&app).await, 0);
+
+    let subscribers = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers, Some(2));
+}
+
+#[tokio::test]
+async fn large_backlogs_are_purged_in_batches_within_a_run() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+
+    for email in ["ursula", "octavia", "terry"] {
+        app.post_subscriptions(format!("name=writer&email={}%40example.com", email))
+            .await;
+        backdate_signup(
+            &app,
+            &format!("{}@example.com", email),
+            app.config.retention.pending_max_age_days + 1,
+        )
+        .await;
+    }
+
+    let purged =
+        purge_stale_pending_subscriptions(&app.db_pool, app.config.retention.pending_max_age(), 2)
+            .await
+            .unwrap();
+
+    assert_eq!(purged, 3);
+    let subscribers = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers, Some(0));
+}
+
+#[tokio::test]
+async fn people_signing_up_again_long_after_they_left_are_kept() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET status = 'unsubscribed',
+        subscribed_at = subscribed_at - interval '1 year',
+        pending_since = pending_since - interval '1 year'
+    "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(purge(&app).await, 0);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
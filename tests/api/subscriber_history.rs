@@ -0,0 +1,146 @@
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app, TestApp};
+
+/// The `(kind, actor)` pairs in the history of the subscriber with `email`.
+async fn history_of(app: &TestApp, email: &str) -> Vec<(String, String)> {
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Could not fetch the subscriber");
+
+    let resp = app
+        .get_admin(&format!("/admin/subscribers/{}/history", subscriber.id))
+        .await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["subscriber_id"], subscriber.id.to_string());
+    body["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| {
+            assert!(event["occurred_at"].is_string());
+            (
+                event["kind"].as_str().unwrap().to_owned(),
+                event["actor"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect()
+}
+
+fn events(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+    expected
+        .iter()
+        .map(|(kind, actor)| (kind.to_string(), actor.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn history_records_signup_confirmation_email_and_confirmation() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_req);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(
+        history_of(&app, "ursula@example.com").await,
+        events(&[
+            ("subscribed", "subscriber"),
+            ("confirmation_sent", "system"),
+            ("confirmed", "subscriber"),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn history_records_the_status_of_imported_subscribers() {
+    let app = spawn_app().await;
+    let csv = "Email Address,First Name,Last Name,Status\n\
+        ursula@example.com,Ursula,Le Guin,subscribed\n\
+        terry@example.com,Terry,Pratchett,unsubscribed\n";
+
+    let resp = app
+        .post_import(csv.into(), "?format=mailchimp&status=confirmed")
+        .await;
+    assert_eq!(resp.status().as_u16(), 200);
+
+    assert_eq!(
+        history_of(&app, "ursula@example.com").await,
+        events(&[("subscribed", "import"), ("confirmed", "import")])
+    );
+    assert_eq!(
+        history_of(&app, "terry@example.com").await,
+        events(&[("subscribed", "import"), ("unsubscribed", "import")])
+    );
+}
+
+#[tokio::test]
+async fn history_of_an_unknown_subscriber_is_a_404() {
+    let app = spawn_app().await;
+
+    let resp = app
+        .get_admin(&format!("/admin/subscribers/{}/history", Uuid::new_v4()))
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 404);
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+}
+
+#[tokio::test]
+async fn history_requires_the_admin_token() {
+    let app = spawn_app().await;
+
+    let resp = reqwest::get(format!(
+        "{}/admin/subscribers/{}/history",
+        &app.address,
+        Uuid::new_v4()
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn recorded_events_cannot_be_changed_or_deleted() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let update = sqlx::query!("UPDATE subscription_events SET kind = 'confirmed'")
+        .execute(&app.db_pool)
+        .await;
+    let delete = sqlx::query!("DELETE FROM subscription_events")
+        .execute(&app.db_pool)
+        .await;
+
+    assert!(update.is_err());
+    assert!(delete.is_err());
+}
@@ -1,8 +1,8 @@
-use std::assert_eq;
+use std::{assert_eq, time::Duration};
 
 use wiremock::{matchers::method, Mock, ResponseTemplate};
 
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with};
 
 #[tokio::test]
 async fn subscribe_returns_200_for_valid_form_data() {
@@ -117,6 +117,138 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
     let resp = app.post_subscriptions(body.into()).await;
 
     assert_eq!(resp.status().as_u16(), 500);
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+}
+
+#[tokio::test]
+async fn subscribe_returns_problem_details_for_every_invalid_field() {
+    let app = spawn_app().await;
+
+    let resp = app
+        .post_subscriptions("name=&email=not-a-valid-email".into())
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 400);
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(problem["status"], 400);
+    let invalid_params = problem["invalid-params"].as_array().unwrap();
+    assert_eq!(invalid_params.len(), 2);
+    assert_eq!(invalid_params[0]["name"], "name");
+    assert_eq!(invalid_params[1]["name"], "email");
+    assert_eq!(
+        invalid_params[1]["reason"],
+        "'not-a-valid-email' is not a valid email"
+    );
+}
+
+#[tokio::test]
+async fn subscribe_returns_problem_details_when_data_is_missing() {
+    let app = spawn_app().await;
+
+    let resp = app.post_subscriptions("name=Bruno%20p".into()).await;
+
+    assert_eq!(resp.status().as_u16(), 400);
+    assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert!(problem["detail"].as_str().unwrap().contains("email"));
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_and_keeps_the_email_for_later_when_it_cannot_be_sent() {
+    let app = spawn_app().await;
+    Mock::given(wiremock::matchers::path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let outbox = sqlx::query!("SELECT attempts, sent_at, last_error FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.attempts, 1);
+    assert!(outbox.sent_at.is_none());
+    assert!(outbox.last_error.is_some());
+}
+
+#[tokio::test]
+async fn subscribe_answers_without_waiting_for_the_email_to_be_sent() {
+    let app = spawn_app().await;
+    Mock::given(wiremock::matchers::path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+        .mount(&app.email_server)
+        .await;
+
+    // Not through `post_subscriptions`, which waits for the email
+    let signup = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send();
+    let resp = tokio::time::timeout(Duration::from_secs(5), signup)
+        .await
+        .expect("The signup waited for the email API")
+        .expect("Failed to execute request");
+
+    assert_eq!(resp.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn subscribe_accepts_json_and_responds_with_json() {
+    let app = spawn_app().await;
+    mock_email_server_call().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula_le_guin@gmail.com"
+        }))
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    assert_eq!(resp.headers()["Content-Type"], "application/json");
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["status"], "pending_confirmation");
+
+    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribe_returns_problem_details_for_invalid_json() {
+    let app = spawn_app().await;
+
+    let test_cases = vec![
+        (serde_json::json!({ "name": "le guin" }), "a missing email"),
+        (
+            serde_json::json!({ "name": "le guin", "email": "not-an-email" }),
+            "an invalid email",
+        ),
+        (serde_json::json!(["not", "an", "object"]), "a JSON array"),
+    ];
+
+    for (body, description) in test_cases {
+        let resp = app.post_subscriptions_json(body).await;
+
+        assert_eq!(
+            resp.status().as_u16(),
+            400,
+            "The API did not fail with a 400 for {}",
+            description
+        );
+        assert_eq!(resp.headers()["Content-Type"], "application/problem+json");
+    }
 }
 
 fn mock_email_server_call() -> Mock {
@@ -125,3 +257,345 @@ fn mock_email_server_call() -> Mock {
         .respond_with(ResponseTemplate::new(200))
         .expect(1)
 }
+
+#[tokio::test]
+async fn subscribe_rejects_role_addresses_when_the_policy_says_so() {
+    let app = spawn_app_with(|c| c.email_policy.reject_role_accounts = true).await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=noreply%40example.com".into())
+        .await;
+
+    assert_eq!(400, resp.status().as_u16());
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(problem["invalid-params"][0]["name"], "email");
+    assert!(problem["invalid-params"][0]["reason"]
+        .as_str()
+        .unwrap()
+        .contains("role address"));
+}
+
+#[tokio::test]
+async fn subscribe_rejects_domains_from_the_blocklist_file() {
+    let app = spawn_app_with(|c| {
+        c.email_policy.blocked_domains_file = Some("config/disposable_domains.txt".into())
+    })
+    .await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40mailinator.com".into())
+        .await;
+
+    assert_eq!(400, resp.status().as_u16());
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert!(problem["invalid-params"][0]["reason"]
+        .as_str()
+        .unwrap()
+        .contains("disposable"));
+}
+
+#[tokio::test]
+async fn subscribe_does_not_store_the_same_address_twice_in_another_case() {
+    let app = spawn_app().await;
+    Mock::given(wiremock::matchers::path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=Ursula%40Example.COM".into())
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+    // Still pending, so they get a fresh link
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+
+    let saved = sqlx::query!("SELECT email, email_normalized FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "Ursula@example.com");
+    assert_eq!(saved[0].email_normalized, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn subscribe_finds_addresses_stored_before_they_were_normalized() {
+    let app =
+        spawn_app_with(|c| c.email_policy.plus_alias_domains = vec!["gmail.com".into()]).await;
+    app.mock_email_server().await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula%2Bnews%40gmail.com".into())
+        .await;
+    // Only lowercased, as the migration adding the column did
+    sqlx::query!("UPDATE subscriptions SET email_normalized = lower(email)")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=ursula%2Bnews%40gmail.com".into())
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.len(), 1);
+}
+
+#[tokio::test]
+async fn subscribe_stores_internationalized_domains_in_unicode_and_emails_their_ascii_form() {
+    let app = spawn_app().await;
+    Mock::given(wiremock::matchers::path("/email"))
+        .and(wiremock::matchers::body_partial_json(serde_json::json!({
+            "To": "ursula@xn--bcher-kva.example"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula@bücher.example",
+        }))
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.email, "ursula@bücher.example");
+}
+
+#[tokio::test]
+async fn subscribe_accepts_non_ascii_local_parts_only_if_the_email_api_supports_them() {
+    let body = serde_json::json!({ "name": "josé", "email": "josé@example.com" });
+
+    let app = spawn_app().await;
+    let resp = app.post_subscriptions_json(body.clone()).await;
+    assert_eq!(400, resp.status().as_u16());
+
+    let app = spawn_app_with(|c| c.email_client.smtputf8 = true).await;
+    Mock::given(wiremock::matchers::path("/email"))
+        .and(wiremock::matchers::body_partial_json(serde_json::json!({
+            "To": "josé@example.com"
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let resp = app.post_subscriptions_json(body).await;
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_suggests_a_fix_for_typos_in_popular_domains() {
+    let app = spawn_app().await;
+
+    let resp = app
+        .post_subscriptions("name=jane&email=jane%40gmial.com".into())
+        .await;
+
+    assert_eq!(400, resp.status().as_u16());
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(problem["invalid-params"][0]["name"], "email");
+    assert_eq!(
+        problem["invalid-params"][0]["reason"],
+        "Did you mean jane@gmail.com?"
+    );
+    assert_eq!(problem["invalid-params"][0]["suggestion"], "jane@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribe_keeps_a_suggested_address_when_asked_to() {
+    let app = spawn_app().await;
+    mock_email_server_call().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "jane",
+            "email": "jane@gmial.com",
+            "ignore_email_suggestion": true,
+        }))
+        .await;
+
+    assert_eq!(200, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_stores_names_in_nfc_and_rejects_bidi_overrides() {
+    let app = spawn_app().await;
+    mock_email_server_call().mount(&app.email_server).await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "Rene\u{301}e",
+            "email": "renee@example.com",
+        }))
+        .await;
+    assert_eq!(200, resp.status().as_u16());
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "Ursula \u{202e}niugeL",
+            "email": "ursula@example.com",
+        }))
+        .await;
+    assert_eq!(400, resp.status().as_u16());
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(problem["invalid-params"][0]["name"], "name");
+
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.name, "Ren\u{e9}e");
+}
+
+#[tokio::test]
+async fn subscribe_enforces_the_configured_name_policy() {
+    let app = spawn_app_with(|c| {
+        c.name_policy.forbidden_chars = "@".into();
+        c.name_policy.max_graphemes = 8;
+    })
+    .await;
+
+    let resp = app
+        .post_subscriptions("name=ursula%20le%20guin&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(400, resp.status().as_u16());
+
+    let resp = app
+        .post_subscriptions("name=le%40guin&email=ursula%40example.com".into())
+        .await;
+    assert_eq!(400, resp.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_rejects_overly_long_sources() {
+    let app = spawn_app().await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula@example.com",
+            "source": "a".repeat(101),
+        }))
+        .await;
+
+    assert_eq!(400, resp.status().as_u16());
+    let problem: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(problem["invalid-params"][0]["name"], "source");
+}
+
+#[tokio::test]
+async fn subscribe_confirms_right_away_and_sends_a_welcome_email_without_double_opt_in() {
+    let app = spawn_app_with(|c| c.confirmation.double_opt_in = false).await;
+    mock_email_server_call()
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let resp = app
+        .post_subscriptions_json(serde_json::json!({
+            "name": "le guin",
+            "email": "ursula@example.com",
+        }))
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["status"], "confirmed");
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.status, "confirmed");
+    let tokens = sqlx::query_scalar!("SELECT COUNT(*) FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens, Some(0));
+    let events = sqlx::query_scalar!("SELECT kind FROM subscription_events ORDER BY id")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(events, ["subscribed", "confirmed"]);
+    let consent = sqlx::query!("SELECT signed_up_at, confirmed_at FROM subscription_consents")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(consent.signed_up_at.is_some());
+    assert!(consent.confirmed_at.is_some());
+
+    // A welcome, not a link to follow
+    let email_req = &app.email_server.received_requests().await.unwrap()[0];
+    let email: serde_json::Value = serde_json::from_slice(&email_req.body).unwrap();
+    assert!(!email["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("subscription_token"));
+}
+
+#[tokio::test]
+async fn former_subscribers_have_to_confirm_again_even_without_double_opt_in() {
+    let app = spawn_app_with(|c| c.confirmation.double_opt_in = false).await;
+    app.mock_email_server().await;
+    let signup = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula@example.com",
+    });
+
+    app.post_subscriptions_json(signup.clone()).await;
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let resp = app.post_subscriptions_json(signup).await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["status"], "pending_confirmation");
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Could not fetch subscriptions from db");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribe_drops_suppressed_addresses_with_the_usual_answer() {
+    let app = spawn_app().await;
+    mock_email_server_call()
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!(
+        "INSERT INTO email_suppressions (email, reason, created_at) \
+        VALUES ('ursula@example.com', 'bounced', now())"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let resp = app
+        .post_subscriptions("name=le%20guin&email=Ursula%40example.com".into())
+        .await;
+
+    assert_eq!(resp.status().as_u16(), 200);
+    let saved = sqlx::query_scalar!("SELECT COUNT(*) FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved, Some(0));
+}
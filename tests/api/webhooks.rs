@@ -0,0 +1,419 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use mailbolt::{
+    domain::SubscriptionEventKind,
+    webhooks::{dispatch_webhooks, WebhookEndpoint, WEBHOOK_EVENTS},
+};
+use secrecy::Secret;
+use sha2::Sha256;
+use uuid::Uuid;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::helpers::{spawn_app_with, TestApp};
+
+const SECRET: &str = "webhook-secret";
+
+/// An app sending `events` to a webhook endpoint on `hook_server`.
+async fn spawn_app_with_webhook(
+    hook_server: &MockServer,
+    events: Vec<SubscriptionEventKind>,
+) -> TestApp {
+    let url = format!("{}/hooks", hook_server.uri());
+    let app = spawn_app_with(|c| {
+        c.webhooks.endpoints = vec![WebhookEndpoint {
+            name: "crm".into(),
+            url,
+            secret: Secret::new(SECRET.into()),
+            events,
+        }]
+    })
+    .await;
+    app.mock_email_server().await;
+    app
+}
+
+async fn dispatch(app: &TestApp) -> u64 {
+    dispatch_webhooks(
+        &app.db_pool,
+        &app.config.webhooks.client(),
+        &app.config.webhooks.policy().unwrap(),
+    )
+    .await
+    .expect("Failed to dispatch webhooks")
+}
+
+async fn subscribe_and_confirm(app: &TestApp) {
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let links = app.get_confirmation_links(&email_requests[0]);
+    reqwest::get(links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+async fn post_redeliver(app: &TestApp, delivery_id: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!(
+            "{}/admin/webhooks/deliveries/{}/redeliver",
+            &app.address, delivery_id
+        ))
+        .bearer_auth(&app.admin_api_token)
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+async fn deliveries(app: &TestApp, query: &str) -> Vec<serde_json::Value> {
+    let page: serde_json::Value = app
+        .get_admin(&format!("/admin/webhooks/deliveries{}", query))
+        .await
+        .json()
+        .await
+        .unwrap();
+    page["deliveries"].as_array().unwrap().clone()
+}
+
+/// The header as sent. Wiremock splits values on commas, which signatures contain.
+fn header(request: &wiremock::Request, name: &str) -> String {
+    let name: wiremock::http::HeaderName = name.parse().unwrap();
+    let values: Vec<_> = request.headers[&name]
+        .iter()
+        .map(|value| value.as_str())
+        .collect();
+    values.join(",")
+}
+
+#[tokio::test]
+async fn subscriber_events_are_posted_to_the_endpoint_with_a_valid_signature() {
+    let hook_server = MockServer::start().await;
+    Mock::given(path("/hooks"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(2)
+        .mount(&hook_server)
+        .await;
+    let app = spawn_app_with_webhook(&hook_server, WEBHOOK_EVENTS.to_vec()).await;
+
+    subscribe_and_confirm(&app).await;
+    assert_eq!(dispatch(&app).await, 2);
+
+    let hooks = hook_server.received_requests().await.unwrap();
+    let kinds: Vec<_> = hooks
+        .iter()
+        .map(|hook| header(hook, "Mailbolt-Event"))
+        .collect();
+    assert_eq!(kinds, ["subscribed", "confirmed"]);
+
+    let confirmed = &hooks[1];
+    assert_eq!(header(confirmed, "Content-Type"), "application/json");
+    let signature_header = header(confirmed, "Mailbolt-Signature");
+    let (timestamp, signature) = signature_header.split_once(",v1=").unwrap();
+    let timestamp = timestamp.strip_prefix("t=").unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(&confirmed.body);
+    mac.verify_slice(&hex::decode(signature).unwrap())
+        .expect("The signature does not match the payload");
+
+    let payload: serde_json::Value = serde_json::from_slice(&confirmed.body).unwrap();
+    assert_eq!(payload["type"], "confirmed");
+    assert_eq!(payload["actor"], "subscriber");
+    assert_eq!(payload["subscriber"]["email"], "ursula@example.com");
+    assert_eq!(payload["subscriber"]["status"], "confirmed");
+
+    // Nothing is sent twice
+    assert_eq!(dispatch(&app).await, 0);
+}
+
+#[tokio::test]
+async fn endpoints_only_get_the_events_they_asked_for() {
+    let hook_server = MockServer::start().await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&hook_server)
+        .await;
+    let app = spawn_app_with_webhook(&hook_server, vec![SubscriptionEventKind::Confirmed]).await;
+
+    subscribe_and_confirm(&app).await;
+    assert_eq!(dispatch(&app).await, 1);
+
+    let hooks = hook_server.received_requests().await.unwrap();
+    assert_eq!(header(&hooks[0], "Mailbolt-Event"), "confirmed");
+    // The queue is emptied either way
+    let queued = sqlx::query_scalar!("SELECT COUNT(*) FROM webhook_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued, Some(0));
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried_later_and_logged() {
+    let hook_server = MockServer::start().await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&hook_server)
+        .await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&hook_server)
+        .await;
+    let app = spawn_app_with_webhook(&hook_server, vec![SubscriptionEventKind::Subscribed]).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(dispatch(&app).await, 0);
+    let pending = deliveries(&app, "?state=pending").await;
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0]["attempts"], 1);
+    assert_eq!(pending[0]["last_status_code"], 500);
+
+    // Not due again yet
+    assert_eq!(dispatch(&app).await, 0);
+    assert_eq!(hook_server.received_requests().await.unwrap().len(), 1);
+
+    sqlx::query!("UPDATE webhook_deliveries SET next_attempt_at = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(dispatch(&app).await, 1);
+
+    let delivered = deliveries(&app, "?state=delivered").await;
+    assert_eq!(delivered.len(), 1);
+    let detail: serde_json::Value = app
+        .get_admin(&format!(
+            "/admin/webhooks/deliveries/{}",
+            delivered[0]["id"].as_str().unwrap()
+        ))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail["payload"]["type"], "subscribed");
+    let attempt_log = detail["attempt_log"].as_array().unwrap();
+    assert_eq!(attempt_log.len(), 2);
+    assert_eq!(attempt_log[0]["status_code"], 500);
+    assert_eq!(attempt_log[1]["status_code"], 200);
+    assert_eq!(attempt_log[1]["error"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn deliveries_being_posted_are_claimed_without_holding_a_lock() {
+    let hook_server = MockServer::start().await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+        .expect(1)
+        .mount(&hook_server)
+        .await;
+    let app = spawn_app_with_webhook(&hook_server, vec![SubscriptionEventKind::Subscribed]).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    let delivering = dispatch(&app);
+    let while_posting = async {
+        while hook_server.received_requests().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // The claim is committed: other connections see it and don't wait for the post
+        let delivery =
+            sqlx::query!("SELECT attempts, delivered_at FROM webhook_deliveries FOR UPDATE NOWAIT")
+                .fetch_one(&app.db_pool)
+                .await
+                .expect("The delivery is locked while it is posted");
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.delivered_at.is_none());
+        // Nor does another dispatcher post it again
+        assert_eq!(dispatch(&app).await, 0);
+    };
+    let (delivered, _) = tokio::join!(delivering, while_posting);
+
+    assert_eq!(delivered, 1);
+    let delivered_at = sqlx::query_scalar!("SELECT delivered_at FROM webhook_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(delivered_at.is_some());
+}
+
+#[tokio::test]
+async fn deliveries_out_of_attempts_are_failed_until_redelivered() {
+    let hook_server = MockServer::start().await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&hook_server)
+        .await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&hook_server)
+        .await;
+    let url = format!("{}/hooks", hook_server.uri());
+    let app = spawn_app_with(|c| {
+        c.webhooks.max_attempts = 1;
+        c.webhooks.endpoints = vec![WebhookEndpoint {
+            name: "crm".into(),
+            url,
+            secret: Secret::new(SECRET.into()),
+            events: vec![SubscriptionEventKind::Subscribed],
+        }];
+    })
+    .await;
+    app.mock_email_server().await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await;
+
+    assert_eq!(dispatch(&app).await, 0);
+    let failed = deliveries(&app, "?state=failed&endpoint=crm").await;
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0]["next_attempt_at"], serde_json::Value::Null);
+    let delivery_id = failed[0]["id"].as_str().unwrap().to_owned();
+
+    let resp = post_redeliver(&app, &delivery_id).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let detail: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(detail["state"], "delivered");
+    assert_eq!(detail["attempt_log"].as_array().unwrap().len(), 2);
+
+    // Receivers can tell it is the same delivery
+    let hooks = hook_server.received_requests().await.unwrap();
+    assert_eq!(hooks.len(), 2);
+    assert_eq!(header(&hooks[0], "Mailbolt-Delivery"), delivery_id);
+    assert_eq!(header(&hooks[1], "Mailbolt-Delivery"), delivery_id);
+}
+
+#[tokio::test]
+async fn redelivering_unknown_deliveries_is_a_404() {
+    let hook_server = MockServer::start().await;
+    let app = spawn_app_with_webhook(&hook_server, WEBHOOK_EVENTS.to_vec()).await;
+
+    let resp = post_redeliver(&app, &Uuid::new_v4().to_string()).await;
+    assert_eq!(resp.status().as_u16(), 404);
+
+    let resp = app
+        .get_admin(&format!("/admin/webhooks/deliveries/{}", Uuid::new_v4()))
+        .await;
+    assert_eq!(resp.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_delivery_log_needs_the_admin_token() {
+    let hook_server = MockServer::start().await;
+    let app = spawn_app_with_webhook(&hook_server, WEBHOOK_EVENTS.to_vec()).await;
+
+    let resp = reqwest::get(format!("{}/admin/webhooks/deliveries", &app.address))
+        .await
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn imported_subscribers_are_sent_with_the_status_they_came_with() {
+    let hook_server = MockServer::start().await;
+    Mock::given(path("/hooks"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&hook_server)
+        .await;
+    let app = spawn_app_with_webhook(&hook_server, WEBHOOK_EVENTS.to_vec()).await;
+    let unsubscribed = "Email Address,First Name,Last Name,MEMBER_RATING,UNSUB_TIME\n\
+        terry@example.com,Terry,Pratchett,2,2023-02-01 10:00:00\n";
+    let cleaned = "Email Address,First Name,Last Name,MEMBER_RATING,CLEAN_TIME\n\
+        octavia@example.com,,,1,2023-03-01 10:00:00\n";
+    for csv in [unsubscribed, cleaned] {
+        app.post_import(csv.into(), "?format=mailchimp&status=confirmed")
+            .await
+            .error_for_status()
+            .unwrap();
+    }
+
+    assert_eq!(dispatch(&app).await, 4);
+
+    let hooks: Vec<_> = hook_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|hook| {
+            let payload: serde_json::Value = serde_json::from_slice(&hook.body).unwrap();
+            (
+                payload["type"].as_str().unwrap().to_owned(),
+                payload["actor"].as_str().unwrap().to_owned(),
+                payload["subscriber"]["email"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect();
+    let expected = [
+        ("subscribed", "terry@example.com"),
+        ("unsubscribed", "terry@example.com"),
+        ("subscribed", "octavia@example.com"),
+        ("bounced", "octavia@example.com"),
+    ];
+    assert_eq!(hooks.len(), expected.len());
+    for ((kind, actor, email), (expected_kind, expected_email)) in hooks.iter().zip(expected) {
+        assert_eq!(
+            (kind.as_str(), email.as_str()),
+            (expected_kind, expected_email)
+        );
+        assert_eq!(actor, "import");
+    }
+}
+
+#[tokio::test]
+async fn every_webhook_event_and_only_those_are_queued_whoever_records_them() {
+    let hook_server = MockServer::start().await;
+    let app = spawn_app_with_webhook(&hook_server, WEBHOOK_EVENTS.to_vec()).await;
+    app.post_subscriptions("name=le%20guin&email=ursula%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("DELETE FROM webhook_events")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    use SubscriptionEventKind::*;
+    let all_kinds = [
+        Subscribed,
+        ConfirmationSent,
+        Confirmed,
+        Unsubscribed,
+        Bounced,
+        Erased,
+        EmailChanged,
+    ];
+    for kind in all_kinds {
+        sqlx::query!(
+            r#"
+    INSERT INTO subscription_events (subscriber_id, kind, actor, occurred_at)
+    SELECT id, $1, 'system', now() FROM subscriptions
+    "#,
+            kind.as_str()
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    }
+
+    let queued = sqlx::query_scalar!("SELECT event_kind FROM webhook_events ORDER BY event_id")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    let expected: Vec<_> = all_kinds
+        .into_iter()
+        .filter(|kind| WEBHOOK_EVENTS.contains(kind))
+        .map(|kind| kind.as_str().to_owned())
+        .collect();
+    assert_eq!(queued, expected);
+    assert_eq!(expected.len(), WEBHOOK_EVENTS.len());
+}